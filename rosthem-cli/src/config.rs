@@ -0,0 +1,76 @@
+use crate::CliError;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// `~/.config/rosthem/config.toml`:
+///
+/// ```toml
+/// gateway = "192.168.1.10"  # or a full coaps:// URI
+///
+/// # Either a PSK from an earlier pairing...
+/// identity = "rosthem"
+/// key = "..."
+///
+/// # ...or the credential store `rosthem pair` writes to (this is the default)
+/// credential_store = "~/.config/rosthem/credentials.json"
+/// ```
+#[derive(Deserialize, Default, Debug)]
+pub struct Config {
+    pub gateway: Option<String>,
+    pub identity: Option<String>,
+    pub key: Option<String>,
+    pub credential_store: Option<PathBuf>,
+}
+
+impl Config {
+    /// A missing file is an empty config, so everything can also be passed as arguments
+    pub fn load(path: Option<&Path>) -> Result<Config, CliError> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match config_dir() {
+                Some(dir) => dir.join("config.toml"),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| CliError::Config(format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(CliError::Config(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    /// The gateway as a CoAP URI, on the default CoAPS port if only a host is given
+    pub fn gateway_uri(&self) -> Result<String, CliError> {
+        match self.gateway.as_deref() {
+            Some(gateway) if gateway.contains("://") => Ok(gateway.to_owned()),
+            Some(gateway) => Ok(format!("coaps://{}", gateway)),
+            None => Err(CliError::Config(
+                "no gateway configured, pass --gateway or set it in the config file".to_owned(),
+            )),
+        }
+    }
+
+    pub fn credential_store(&self) -> Result<PathBuf, CliError> {
+        match &self.credential_store {
+            Some(path) => Ok(expand_home(path)),
+            None => config_dir()
+                .map(|dir| dir.join("credentials.json"))
+                .ok_or_else(|| CliError::Config("no credential store configured".to_owned())),
+        }
+    }
+}
+
+fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rosthem"))
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_owned(),
+    }
+}
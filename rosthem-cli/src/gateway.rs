@@ -0,0 +1,56 @@
+use crate::CliError;
+use rosthem::{CoapSession, GatewayState, StateEvent, SupervisedSession};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How long CoAP IO is processed between looking for requests of the bridges
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often devices and groups are listed again. The gateway doesn't notify about new or
+/// removed ones.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The session and state cache shared by the long running bridges (HTTP, MQTT, metrics): every
+/// device and group is observed, and the bridges look at the changes between polling their own
+/// IO.
+pub struct ObservedGateway {
+    pub session: SupervisedSession,
+    pub state: GatewayState,
+    events: Rc<RefCell<Vec<StateEvent>>>,
+    next_refresh: Instant,
+}
+
+impl ObservedGateway {
+    pub fn new(session: CoapSession) -> Result<ObservedGateway, CliError> {
+        let mut session = SupervisedSession::new(session);
+        let mut state = GatewayState::load(&mut session)?;
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let queue = events.clone();
+        state.subscribe(Box::new(move |event: &StateEvent| {
+            queue.borrow_mut().push(event.clone())
+        }));
+
+        Ok(ObservedGateway {
+            session,
+            state,
+            events,
+            next_refresh: Instant::now() + REFRESH_INTERVAL,
+        })
+    }
+
+    /// Processes CoAP IO for a moment and returns what changed meanwhile, including devices and
+    /// groups that were added or removed
+    pub fn poll(&mut self) -> Result<Vec<StateEvent>, CliError> {
+        if Instant::now() >= self.next_refresh {
+            // Tried again next time, the observations keep working meanwhile
+            if let Err(e) = self.state.refresh(&mut self.session) {
+                eprintln!("refreshing devices and groups failed: {}", e);
+            }
+            self.next_refresh = Instant::now() + REFRESH_INTERVAL;
+        }
+        self.state.run(&mut self.session, POLL_INTERVAL)?;
+
+        Ok(self.events.borrow_mut().drain(..).collect())
+    }
+}
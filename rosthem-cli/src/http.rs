@@ -0,0 +1,250 @@
+use crate::gateway::ObservedGateway;
+use crate::CliError;
+use rosthem::rosthem_dto::{GroupInfo, LightInfo, MoodInfo};
+use rosthem::{CoapError, CoapSession, CoapSessionExt, GatewayState, StateEvent, StateKind};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Events buffered per `/events` subscriber before it counts as stuck and is disconnected
+const SUBSCRIBER_BACKLOG: usize = 64;
+
+/// Serves the gateway as JSON over HTTP, in the shape of the rosthem-dto types:
+///
+/// - `GET /devices`, `GET /devices/{id}`, `PUT /devices/{id}` with a light update
+/// - `GET /groups`, `GET /groups/{id}`, `PUT /groups/{id}` with a group update
+/// - `GET /moods`, the moods of every group keyed by group id
+///
+/// Devices and groups are served from the observed state, updates and moods are requested
+/// from the gateway.
+/// - `GET /events`, server-sent `device` and `group` events with the new state whenever the
+///   gateway reports a change, and `device-removed` and `group-removed` events with the id
+///
+/// Everything runs on one thread, since sessions can't leave the thread that made them, except
+/// for writing to `/events` subscribers, which every subscriber gets its own thread for.
+pub fn serve(session: CoapSession, listen: SocketAddr) -> Result<(), CliError> {
+    let server = Server::http(listen).map_err(|e| CliError::Http(format!("{}: {}", listen, e)))?;
+    let mut gateway = ObservedGateway::new(session)?;
+    let mut bridge = Bridge::default();
+    eprintln!("Listening on http://{}", listen);
+
+    loop {
+        while let Some(request) = server
+            .try_recv()
+            .map_err(|e| CliError::Http(e.to_string()))?
+        {
+            bridge.handle(&mut gateway, request);
+        }
+
+        for event in gateway.poll()? {
+            bridge.notify(&gateway.state, &event);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bridge {
+    subscribers: Vec<SyncSender<String>>,
+}
+
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl From<CoapError> for HttpError {
+    fn from(e: CoapError) -> Self {
+        let status = match e {
+            CoapError::NotFound => 404,
+            CoapError::BadRequest | CoapError::DeserializeError => 400,
+            CoapError::Unauthorized | CoapError::Forbidden => 403,
+            CoapError::Timeout => 504,
+            _ => 502,
+        };
+
+        HttpError {
+            status,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl HttpError {
+    fn new(status: u16, message: &str) -> HttpError {
+        HttpError {
+            status,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl Bridge {
+    fn handle(&mut self, gateway: &mut ObservedGateway, mut request: Request) {
+        if request.method() == &Method::Get && request.url() == "/events" {
+            self.subscribe(request);
+            return;
+        }
+
+        let response = match route(gateway, &mut request) {
+            Ok(Some(body)) => {
+                Response::from_string(body).with_header(header("Content-Type", "application/json"))
+            }
+            Ok(None) => Response::from_string("").with_status_code(204),
+            Err(e) => Response::from_string(e.message).with_status_code(e.status),
+        };
+        let _ = request.respond(response);
+    }
+
+    /// Hands the connection to a thread of its own, so a slow client can't hold up the bridge
+    fn subscribe(&mut self, request: Request) {
+        let (tx, rx) = mpsc::sync_channel::<String>(SUBSCRIBER_BACKLOG);
+        let mut writer = request.into_writer();
+
+        thread::spawn(move || {
+            let head = "HTTP/1.1 200 OK\r\n\
+                        Content-Type: text/event-stream\r\n\
+                        Cache-Control: no-cache\r\n\
+                        Connection: keep-alive\r\n\r\n";
+            if writer
+                .write_all(head.as_bytes())
+                .and_then(|_| writer.flush())
+                .is_err()
+            {
+                return;
+            }
+
+            for message in rx {
+                if writer
+                    .write_all(message.as_bytes())
+                    .and_then(|_| writer.flush())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+
+        self.subscribers.push(tx);
+    }
+
+    /// Sends a change to every `/events` subscriber, dropping those that went away or fell too
+    /// far behind
+    fn notify(&mut self, state: &GatewayState, event: &StateEvent) {
+        let message = match event {
+            StateEvent::Added(kind, id) | StateEvent::Changed { kind, id, .. } => {
+                let data = match kind {
+                    StateKind::Device => state.device(*id).map(serde_json::to_string),
+                    StateKind::Group => state.group(*id).map(serde_json::to_string),
+                };
+                match data {
+                    Some(Ok(data)) => format!("event: {}\ndata: {}\n\n", event_name(*kind), data),
+                    _ => return,
+                }
+            }
+            StateEvent::Removed(kind, id) => {
+                format!("event: {}-removed\ndata: {}\n\n", event_name(*kind), id)
+            }
+        };
+
+        self.subscribers
+            .retain(|subscriber| match subscriber.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+fn event_name(kind: StateKind) -> &'static str {
+    match kind {
+        StateKind::Device => "device",
+        StateKind::Group => "group",
+    }
+}
+
+fn route(
+    gateway: &mut ObservedGateway,
+    request: &mut Request,
+) -> Result<Option<String>, HttpError> {
+    let url = request.url().to_owned();
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let (state, session) = (&gateway.state, &mut gateway.session);
+
+    match (request.method(), segments.as_slice()) {
+        (Method::Get, ["devices"]) => to_json(
+            &state
+                .devices()
+                .map(|(_, device)| device)
+                .collect::<Vec<_>>(),
+        ),
+        (Method::Get, ["devices", id]) => {
+            to_json(&state.device(parse_id(id)?).ok_or_else(not_found)?)
+        }
+        (Method::Put, ["devices", id]) => {
+            let command: LightInfo = from_json(request)?;
+            session.update_light(&parse_id(id)?.to_string(), command)?;
+            Ok(None)
+        }
+        (Method::Get, ["groups"]) => {
+            to_json(&state.groups().map(|(_, group)| group).collect::<Vec<_>>())
+        }
+        (Method::Get, ["groups", id]) => {
+            to_json(&state.group(parse_id(id)?).ok_or_else(not_found)?)
+        }
+        (Method::Put, ["groups", id]) => {
+            let command: GroupInfo = from_json(request)?;
+            session.update_group(&parse_id(id)?.to_string(), command)?;
+            Ok(None)
+        }
+        (Method::Get, ["moods"]) => {
+            let mut moods: BTreeMap<usize, Vec<MoodInfo>> = BTreeMap::new();
+            for (group, _) in state.groups() {
+                let group_id = group.to_string();
+                let entry = moods.entry(group).or_default();
+                for id in session.list_moods(&group_id)? {
+                    entry.push(session.request_mood(&group_id, &id.to_string())?);
+                }
+            }
+            to_json(&moods)
+        }
+        (_, ["devices"])
+        | (_, ["devices", _])
+        | (_, ["groups"])
+        | (_, ["groups", _])
+        | (_, ["moods"])
+        | (_, ["events"]) => Err(HttpError::new(405, "Method not allowed")),
+        _ => Err(not_found()),
+    }
+}
+
+fn parse_id(id: &str) -> Result<usize, HttpError> {
+    id.parse().map_err(|_| not_found())
+}
+
+fn not_found() -> HttpError {
+    HttpError::new(404, "Not found")
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Option<String>, HttpError> {
+    serde_json::to_string(value)
+        .map(Some)
+        .map_err(|e| HttpError::new(500, &e.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(request: &mut Request) -> Result<T, HttpError> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|e| HttpError::new(400, &e.to_string()))?;
+
+    serde_json::from_str(&body).map_err(|e| HttpError::new(400, &e.to_string()))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
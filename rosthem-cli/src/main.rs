@@ -0,0 +1,548 @@
+mod config;
+mod gateway;
+mod http;
+mod metrics;
+mod mqtt;
+mod output;
+mod reconcile;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use config::Config;
+use output::{print_fields, print_json, print_table, DeviceSummary, GroupSummary};
+use reconcile::{DesiredState, Drift};
+use rosthem::rosthem_dto::{DeviceInfo, GroupInfo, LightColorPreset, LightInfo};
+use rosthem::{
+    Coap, CoapContext, CoapCredentials, CoapError, CoapMethod, CoapOptList, CoapPduBuilder,
+    CoapResponse, CoapSession, CoapSessionExt, CoapUri, CredentialStore, FileCredentialStore,
+    SupervisedSession,
+};
+use serde_json::Value;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+/// Everyday operations on an IKEA Tradfri gateway
+#[derive(Parser)]
+#[clap(name = "rosthem", version)]
+struct Args {
+    /// Config file, defaults to ~/.config/rosthem/config.toml
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Gateway host or coaps:// URI, overrides the config file
+    #[clap(long, global = true)]
+    gateway: Option<String>,
+
+    #[clap(long, global = true, value_enum, default_value = "table")]
+    output: Output,
+
+    /// Print every PDU sent and received to stderr
+    #[clap(long, global = true)]
+    trace: bool,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum Output {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Registers with the gateway and stores the PSK in the credential store
+    Pair {
+        /// The security code on the bottom of the gateway
+        #[clap(long)]
+        security_code: String,
+        /// Name to register as; it has to be new to the gateway
+        #[clap(long, default_value = "rosthem")]
+        identity: String,
+    },
+    /// Lists all devices
+    Devices,
+    /// Lists all groups
+    Groups,
+    /// Shows a device, or a group with --group
+    Get {
+        id: String,
+        #[clap(long)]
+        group: bool,
+    },
+    /// Changes a light, or all lights of a group with --group
+    Set {
+        id: String,
+        #[clap(long)]
+        group: bool,
+        #[clap(long, conflicts_with = "off")]
+        on: bool,
+        #[clap(long)]
+        off: bool,
+        #[clap(long)]
+        brightness: Option<u8>,
+        /// A color preset like warm-white or cool-white
+        #[clap(long, conflicts_with = "xy")]
+        preset: Option<String>,
+        /// CIE xy color as two values from 0 to 65535, e.g. 30015,26870
+        #[clap(long, value_delimiter = ',', number_of_values = 2)]
+        xy: Option<Vec<u16>>,
+    },
+    /// Prints every change of a device (or group) until interrupted
+    Observe {
+        id: String,
+        #[clap(long)]
+        group: bool,
+    },
+    /// Sends a request to any path, e.g. `raw get 15011/15012`
+    Raw {
+        #[clap(value_enum)]
+        method: RawMethod,
+        /// Path with optional query, e.g. 15001/65536 or 15011/9034?reboot
+        path: String,
+        /// JSON payload
+        payload: Option<String>,
+    },
+    /// Serves devices, groups and moods as JSON over HTTP, with change events
+    Http {
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+    },
+    /// Serves device states and session counters as Prometheus metrics
+    Metrics {
+        #[clap(long, default_value = "127.0.0.1:9100")]
+        listen: SocketAddr,
+    },
+    /// Brings lights to the state described in a YAML file and reports what drifted
+    Reconcile {
+        file: PathBuf,
+        /// Only report drift, don't change anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Keep reconciling, every this many seconds
+        #[clap(long)]
+        interval: Option<u64>,
+    },
+    /// Bridges the gateway to MQTT, with Home Assistant discovery
+    Mqtt {
+        /// host or host:port
+        #[clap(long, default_value = "127.0.0.1:1883")]
+        broker: String,
+        #[clap(long, default_value = "homeassistant")]
+        discovery_prefix: String,
+        #[clap(long, default_value = "rosthem")]
+        base_topic: String,
+        #[clap(long)]
+        username: Option<String>,
+        #[clap(long, requires = "username")]
+        password: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum RawMethod {
+    Get,
+    Put,
+    Post,
+    Delete,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Coap(CoapError),
+    Config(String),
+    Usage(String),
+    Http(String),
+}
+
+impl From<CoapError> for CliError {
+    fn from(e: CoapError) -> Self {
+        CliError::Coap(e)
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Coap(e) => write!(f, "{}", e),
+            CliError::Config(message) | CliError::Usage(message) | CliError::Http(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), CliError> {
+    let mut config = Config::load(args.config.as_deref())?;
+    if args.gateway.is_some() {
+        config.gateway = args.gateway.clone();
+    }
+
+    let coap = Coap::new(None)?;
+    let context = coap.new_context()?;
+    context.set_credential_store(Some(Box::new(FileCredentialStore::new(
+        config.credential_store()?,
+    ))));
+
+    if let Command::Pair {
+        security_code,
+        identity,
+    } = &args.command
+    {
+        return pair(&context, &config, security_code, identity);
+    }
+
+    let mut session = connect(&context, &config, None)?;
+    if args.trace {
+        trace(&mut session);
+    }
+
+    match args.command {
+        Command::Pair { .. } => unreachable!(),
+        Command::Devices => {
+            let mut devices = Vec::new();
+            for id in session.list_devices()? {
+                devices.push(DeviceSummary::from(
+                    &session.request_status(&id.to_string())?,
+                ));
+            }
+
+            match args.output {
+                Output::Json => print_json(&devices),
+                Output::Table => print_table(
+                    DeviceSummary::HEADERS,
+                    &devices.iter().map(DeviceSummary::row).collect::<Vec<_>>(),
+                ),
+            }
+        }
+        Command::Groups => {
+            let mut groups = Vec::new();
+            for id in session.list_groups()? {
+                groups.push(GroupSummary::from(&session.request_group(&id.to_string())?));
+            }
+
+            match args.output {
+                Output::Json => print_json(&groups),
+                Output::Table => print_table(
+                    GroupSummary::HEADERS,
+                    &groups.iter().map(GroupSummary::row).collect::<Vec<_>>(),
+                ),
+            }
+        }
+        Command::Get { id, group: false } => {
+            let device = DeviceSummary::from(&session.request_status(&id)?);
+            match args.output {
+                Output::Json => print_json(&device),
+                Output::Table => print_fields(&device.fields()),
+            }
+        }
+        Command::Get { id, group: true } => {
+            let group = GroupSummary::from(&session.request_group(&id)?);
+            match args.output {
+                Output::Json => print_json(&group),
+                Output::Table => print_fields(&group.fields()),
+            }
+        }
+        Command::Set {
+            id,
+            group,
+            on,
+            off,
+            brightness,
+            preset,
+            xy,
+        } => {
+            let on = if on || off { Some(on) } else { None };
+
+            if group {
+                if preset.is_some() || xy.is_some() {
+                    return Err(CliError::Usage(
+                        "groups only support --on, --off and --brightness".to_owned(),
+                    ));
+                }
+
+                let mut command = GroupInfo::default();
+                if let Some(on) = on {
+                    command = command.on(on);
+                }
+                if let Some(brightness) = brightness {
+                    command = command.brightness(brightness);
+                }
+                session.update_group(&id, command)?;
+            } else {
+                let mut command = LightInfo::default();
+                if let Some(on) = on {
+                    command = command.on(on);
+                }
+                if let Some(brightness) = brightness {
+                    command = command.brightness(brightness);
+                }
+                if let Some(preset) = preset {
+                    command = command.color_preset(parse_preset(&preset)?);
+                }
+                if let Some(xy) = xy {
+                    command = command.color_xy(xy[0], xy[1]);
+                }
+                session.update_light(&id, command)?;
+            }
+        }
+        Command::Observe { id, group } => observe(session, &id, group, args.output)?,
+        Command::Raw {
+            method,
+            path,
+            payload,
+        } => raw(&mut session, method, &path, payload.as_deref())?,
+        Command::Http { listen } => http::serve(session, listen)?,
+        Command::Reconcile {
+            file,
+            dry_run,
+            interval,
+        } => reconcile(&mut session, &file, dry_run, interval, args.output)?,
+        Command::Metrics { listen } => metrics::serve(session, listen)?,
+        Command::Mqtt {
+            broker,
+            discovery_prefix,
+            base_topic,
+            username,
+            password,
+        } => mqtt::serve(
+            session,
+            mqtt::MqttSettings {
+                broker,
+                discovery_prefix,
+                base_topic,
+                username,
+                password,
+            },
+        )?,
+    }
+
+    Ok(())
+}
+
+fn connect(
+    context: &Rc<CoapContext>,
+    config: &Config,
+    credentials: Option<CoapCredentials>,
+) -> Result<CoapSession, CliError> {
+    let uri = CoapUri::new(config.gateway_uri()?)?;
+    let credentials = match (credentials, &config.identity, &config.key) {
+        (Some(credentials), _, _) => Some(credentials),
+        (None, Some(identity), Some(key)) => {
+            Some(CoapCredentials::psk(identity.as_bytes(), key.as_bytes()))
+        }
+        _ => None,
+    };
+
+    let mut session = context.new_session_from_uri(uri, credentials)?;
+    session.wait_until_connected(Duration::from_secs(5))?;
+
+    Ok(session)
+}
+
+fn pair(
+    context: &Rc<CoapContext>,
+    config: &Config,
+    security_code: &str,
+    identity: &str,
+) -> Result<(), CliError> {
+    let mut session = connect(
+        context,
+        config,
+        Some(CoapCredentials::psk("Client_identity", security_code)),
+    )?;
+    let key = session.pair(identity)?;
+
+    let uri = CoapUri::new(config.gateway_uri()?)?;
+    let path = config.credential_store()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| CliError::Config(format!("{}: {}", dir.display(), e)))?;
+    }
+    FileCredentialStore::new(&path).store(uri.host()?, identity.as_bytes(), key.as_bytes())?;
+
+    eprintln!(
+        "Paired as {}, credentials stored in {}",
+        identity,
+        path.display()
+    );
+
+    Ok(())
+}
+
+fn trace(session: &mut CoapSession) {
+    session.set_pdu_inspector(Some(Box::new(|direction, pdu| {
+        eprintln!("{:?} {:#}", direction, pdu);
+    })));
+}
+
+fn observe(session: CoapSession, id: &str, group: bool, output: Output) -> Result<(), CliError> {
+    let optlist = CoapOptList::new();
+    optlist.add_path_segment(if group { "15004" } else { "15001" })?;
+    optlist.add_path_segment(id)?;
+
+    let mut session = SupervisedSession::new(session);
+    let token = session.observe(CoapPduBuilder::new(CoapMethod::Get).with_optlist(&optlist))?;
+
+    loop {
+        session.run(
+            Duration::from_secs(1),
+            Some(Box::new(move |response: CoapResponse| {
+                if response.token() == token {
+                    print_notification(&response, group, output);
+                }
+            })),
+        )?;
+    }
+}
+
+fn print_notification(response: &CoapResponse, group: bool, output: Output) {
+    let row = if group {
+        response
+            .decode::<GroupInfo>()
+            .map(|g| serialize_row(&GroupSummary::from(&g), GroupSummary::row, output))
+    } else {
+        response
+            .decode::<DeviceInfo>()
+            .map(|d| serialize_row(&DeviceSummary::from(&d), DeviceSummary::row, output))
+    };
+
+    match row {
+        Ok(row) => println!("{}", row),
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
+
+/// One line per notification: compact JSON, or the table row without header
+fn serialize_row<T: serde::Serialize>(
+    value: &T,
+    row: fn(&T) -> Vec<String>,
+    output: Output,
+) -> String {
+    match output {
+        Output::Json => serde_json::to_string(value).unwrap_or_default(),
+        Output::Table => row(value).join("  "),
+    }
+}
+
+fn raw(
+    session: &mut CoapSession,
+    method: RawMethod,
+    path: &str,
+    payload: Option<&str>,
+) -> Result<(), CliError> {
+    let method = match method {
+        RawMethod::Get => CoapMethod::Get,
+        RawMethod::Put => CoapMethod::Put,
+        RawMethod::Post => CoapMethod::Post,
+        RawMethod::Delete => CoapMethod::Delete,
+    };
+
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let optlist = CoapOptList::new();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        optlist.add_path_segment(segment)?;
+    }
+    for argument in query.into_iter().flat_map(|q| q.split('&')) {
+        optlist.add_uri_query(argument)?;
+    }
+
+    let mut pdu = CoapPduBuilder::new(method).with_optlist(&optlist);
+    if let Some(payload) = payload {
+        let payload: Value = serde_json::from_str(payload)
+            .map_err(|e| CliError::Usage(format!("payload is not JSON: {}", e)))?;
+        pdu = pdu.with_payload(payload);
+    }
+
+    let response = session.request(pdu)?;
+    eprintln!("{}", response.code());
+    if !response.payload().is_empty() {
+        match response.decode::<Value>() {
+            Ok(value) => print_json(&value),
+            Err(_) => println!("{}", response.payload_str()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reconciles once, or every `interval` seconds. With an interval errors are reported and the
+/// next round tries again.
+fn reconcile(
+    session: &mut CoapSession,
+    file: &Path,
+    dry_run: bool,
+    interval: Option<u64>,
+    output: Output,
+) -> Result<(), CliError> {
+    let desired = DesiredState::load(file)?;
+
+    loop {
+        let result = reconcile_once(session, &desired, dry_run, output);
+        match interval {
+            Some(seconds) => {
+                if let Err(e) = result {
+                    eprintln!("error: {}, retrying in {}s", e, seconds);
+                }
+                thread::sleep(Duration::from_secs(seconds));
+            }
+            None => return result,
+        }
+    }
+}
+
+fn reconcile_once(
+    session: &mut CoapSession,
+    desired: &DesiredState,
+    dry_run: bool,
+    output: Output,
+) -> Result<(), CliError> {
+    if session.has_failed() {
+        session.reconnect()?;
+        session.wait_until_connected(Duration::from_secs(5))?;
+    }
+
+    let plans = desired.plan(session, reconcile::now())?;
+    let drift: Vec<&Drift> = plans.iter().flat_map(|plan| &plan.drift).collect();
+    match output {
+        Output::Json => print_json(&drift),
+        Output::Table if drift.is_empty() => eprintln!("No drift"),
+        Output::Table => print_table(
+            Drift::HEADERS,
+            &drift.iter().map(|d| d.row()).collect::<Vec<_>>(),
+        ),
+    }
+
+    if !dry_run {
+        reconcile::apply(session, &plans, desired.rate)?;
+    }
+
+    Ok(())
+}
+
+fn parse_preset(name: &str) -> Result<LightColorPreset, CliError> {
+    LightColorPreset::from_name(name).ok_or_else(|| {
+        let names: Vec<_> = LightColorPreset::all().iter().map(|p| p.name()).collect();
+        CliError::Usage(format!(
+            "unknown preset {}, expected one of {}",
+            name,
+            names.join(", ")
+        ))
+    })
+}
@@ -0,0 +1,325 @@
+use crate::gateway::ObservedGateway;
+use crate::CliError;
+use rosthem::rosthem_dto::DeviceInfo;
+use rosthem::{CoapNackReason, CoapSession, SessionMetrics};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Serves `GET /metrics` in the Prometheus text format: the state of every device, kept up to
+/// date by observing them, and the counters of the session to the gateway.
+pub fn serve(session: CoapSession, listen: SocketAddr) -> Result<(), CliError> {
+    let server = Server::http(listen).map_err(|e| CliError::Http(format!("{}: {}", listen, e)))?;
+    let mut gateway = ObservedGateway::new(session)?;
+    eprintln!("Serving metrics on http://{}/metrics", listen);
+
+    loop {
+        while let Some(request) = server
+            .try_recv()
+            .map_err(|e| CliError::Http(e.to_string()))?
+        {
+            let exporter = Exporter {
+                devices: gateway.state.devices().collect(),
+                session: gateway.session.session().metrics(),
+            };
+            exporter.handle(request);
+        }
+
+        gateway.poll()?;
+    }
+}
+
+/// A snapshot of everything that is exported
+struct Exporter<'a> {
+    devices: BTreeMap<usize, &'a DeviceInfo>,
+    session: SessionMetrics,
+}
+
+impl Exporter<'_> {
+    fn handle(&self, request: Request) {
+        let response = match (request.method(), request.url()) {
+            (Method::Get, "/metrics") => Response::from_string(self.render()).with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                    .expect("valid header"),
+            ),
+            (_, "/metrics") => Response::from_string("Method not allowed").with_status_code(405),
+            _ => Response::from_string("Not found").with_status_code(404),
+        };
+        let _ = request.respond(response);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_devices(&mut out);
+        render_session(&mut out, &self.session);
+
+        out
+    }
+
+    fn render_devices(&self, out: &mut String) {
+        let labels: Vec<(usize, String)> = self
+            .devices
+            .iter()
+            .map(|(id, device)| {
+                let name = device.label.as_deref().unwrap_or_default();
+                (*id, format!("id=\"{}\",name=\"{}\"", id, escape(name)))
+            })
+            .collect();
+        let device = |id: &usize| self.devices[id];
+
+        let on = labels.iter().filter_map(|(id, labels)| {
+            let device = device(id);
+            let on = match (&device.light_info, &device.plug_info) {
+                (Some(light), _) => light.get_on(),
+                (None, Some(plug)) => plug.get_on(),
+                (None, None) => None,
+            };
+            on.map(|on| (labels, on as u8 as f64))
+        });
+        gauge(
+            out,
+            "rosthem_device_on",
+            "Whether a light or plug is on",
+            on,
+        );
+
+        let brightness = labels.iter().filter_map(|(id, labels)| {
+            let light = device(id).light_info.as_ref()?;
+            light.get_brightness().map(|b| (labels, b as f64))
+        });
+        gauge(
+            out,
+            "rosthem_device_brightness",
+            "Brightness of a light from 0 to 254",
+            brightness,
+        );
+
+        let reachable = labels.iter().filter_map(|(id, labels)| {
+            device(id)
+                .is_reachable()
+                .map(|reachable| (labels, reachable as u8 as f64))
+        });
+        gauge(
+            out,
+            "rosthem_device_reachable",
+            "Whether the gateway can reach a device",
+            reachable,
+        );
+
+        let battery = labels.iter().filter_map(|(id, labels)| {
+            let level = device(id).product_info.as_ref()?.battery_level?;
+            Some((labels, level as f64))
+        });
+        gauge(
+            out,
+            "rosthem_device_battery_level",
+            "Battery level of a device in percent",
+            battery,
+        );
+
+        let last_seen = labels.iter().filter_map(|(id, labels)| {
+            device(id)
+                .last_seen
+                .map(|last_seen| (labels, last_seen as f64))
+        });
+        gauge(
+            out,
+            "rosthem_device_last_seen_seconds",
+            "Unix time the gateway last heard from a device",
+            last_seen,
+        );
+    }
+}
+
+fn render_session(out: &mut String, session: &SessionMetrics) {
+    counter(
+        out,
+        "rosthem_requests_sent_total",
+        "Requests sent to the gateway",
+        session.requests_sent,
+    );
+    counter(
+        out,
+        "rosthem_responses_received_total",
+        "Responses and notifications received from the gateway",
+        session.responses_received,
+    );
+    counter(
+        out,
+        "rosthem_retransmissions_total",
+        "Retransmissions of confirmable requests the gateway never acknowledged",
+        session.retransmissions,
+    );
+    counter(
+        out,
+        "rosthem_reconnects_total",
+        "Reconnects of the DTLS session",
+        session.reconnects,
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP rosthem_nacks_total Requests that were not acknowledged"
+    );
+    let _ = writeln!(out, "# TYPE rosthem_nacks_total counter");
+    let mut nacks: Vec<_> = session
+        .nacks
+        .iter()
+        .map(|(reason, count)| (nack_reason(reason), count))
+        .collect();
+    nacks.sort();
+    for (reason, count) in nacks {
+        let _ = writeln!(
+            out,
+            "rosthem_nacks_total{{reason=\"{}\"}} {}",
+            reason, count
+        );
+    }
+
+    let latency = &session.response_latency;
+    let name = "rosthem_response_latency_seconds";
+    let _ = writeln!(out, "# HELP {} Time from a request to its response", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, count) in latency.buckets() {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            bound.as_secs_f64(),
+            count
+        );
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, latency.count());
+    let _ = writeln!(out, "{}_sum {}", name, latency.sum().as_secs_f64());
+    let _ = writeln!(out, "{}_count {}", name, latency.count());
+}
+
+fn gauge<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: impl Iterator<Item = (&'a String, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn nack_reason(reason: &CoapNackReason) -> &'static str {
+    match reason {
+        CoapNackReason::TooManyRetries => "too_many_retries",
+        CoapNackReason::NotDeliverable => "not_deliverable",
+        CoapNackReason::Reset => "reset",
+        CoapNackReason::TlsFailed => "tls_failed",
+        CoapNackReason::IcmpIssue => "icmp_issue",
+        CoapNackReason::Unknown => "unknown",
+    }
+}
+
+/// Escapes a label value of the text exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rosthem::rosthem_dto::{LightInfo, ProductInfo};
+
+    fn render(devices: &[(usize, DeviceInfo)]) -> String {
+        let mut session = SessionMetrics {
+            requests_sent: 3,
+            responses_received: 2,
+            retransmissions: 8,
+            reconnects: 1,
+            ..Default::default()
+        };
+        session.nacks.insert(CoapNackReason::Reset, 1);
+        session.nacks.insert(CoapNackReason::TooManyRetries, 2);
+
+        Exporter {
+            devices: devices.iter().map(|(id, device)| (*id, device)).collect(),
+            session,
+        }
+        .render()
+    }
+
+    #[test]
+    fn renders_device_gauges() {
+        let lamp = DeviceInfo {
+            label: Some("Desk \"lamp\"".into()),
+            reachable: Some(1),
+            last_seen: Some(1600000000),
+            ..Default::default()
+        }
+        .with_light_info(LightInfo::default().on(true).brightness(100));
+        let remote = DeviceInfo {
+            label: Some("Remote".into()),
+            product_info: Some(ProductInfo {
+                battery_level: Some(87),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let out = render(&[(65536, lamp), (65537, remote)]);
+
+        let lamp = r#"id="65536",name="Desk \"lamp\"""#;
+        let remote = r#"id="65537",name="Remote""#;
+        assert!(out.contains("# TYPE rosthem_device_on gauge\n"));
+        assert!(out.contains(&format!("rosthem_device_on{{{}}} 1\n", lamp)));
+        assert!(out.contains(&format!("rosthem_device_brightness{{{}}} 100\n", lamp)));
+        assert!(out.contains(&format!("rosthem_device_reachable{{{}}} 1\n", lamp)));
+        assert!(out.contains(&format!(
+            "rosthem_device_last_seen_seconds{{{}}} 1600000000\n",
+            lamp
+        )));
+        assert!(out.contains(&format!("rosthem_device_battery_level{{{}}} 87\n", remote)));
+        assert!(!out.contains(&format!("rosthem_device_on{{{}}}", remote)));
+    }
+
+    #[test]
+    fn renders_session_counters() {
+        let out = render(&[]);
+
+        assert!(out.contains(
+            "# TYPE rosthem_requests_sent_total counter\nrosthem_requests_sent_total 3\n"
+        ));
+        assert!(out.contains("rosthem_responses_received_total 2\n"));
+        assert!(out.contains("rosthem_reconnects_total 1\n"));
+        assert!(out.contains(
+            "rosthem_nacks_total{reason=\"reset\"} 1\nrosthem_nacks_total{reason=\"too_many_retries\"} 2\n"
+        ));
+        assert!(out.contains("rosthem_retransmissions_total 8\n"));
+    }
+
+    #[test]
+    fn renders_latency_histogram() {
+        let out = render(&[]);
+
+        assert!(out.contains("# TYPE rosthem_response_latency_seconds histogram\n"));
+        assert!(out.contains("rosthem_response_latency_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(out.contains("rosthem_response_latency_seconds_bucket{le=\"10\"} 0\n"));
+        assert!(out.contains(
+            "rosthem_response_latency_seconds_bucket{le=\"+Inf\"} 0\n\
+             rosthem_response_latency_seconds_sum 0\n\
+             rosthem_response_latency_seconds_count 0\n"
+        ));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
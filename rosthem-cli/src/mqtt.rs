@@ -0,0 +1,657 @@
+use crate::gateway::ObservedGateway;
+use crate::CliError;
+use rosthem::rosthem_dto::{BlindInfo, DeviceInfo, DeviceType, LightInfo, PlugInfo};
+use rosthem::{
+    CoapSession, CoapSessionExt, GatewayState, StateEvent, StateKind, SupervisedSession,
+};
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+/// Messages the MQTT client buffers for the connection thread, enough for the discovery configs
+/// and states of a few hundred devices. Publishing never blocks; when the buffer is full the
+/// message is dropped.
+const MQTT_CAPACITY: usize = 1024;
+
+pub struct MqttSettings {
+    /// `host` or `host:port`
+    pub broker: String,
+    pub discovery_prefix: String,
+    pub base_topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl MqttSettings {
+    fn broker_addr(&self) -> Result<(String, u16), CliError> {
+        match self.broker.rsplit_once(':') {
+            Some((host, port)) => port
+                .parse()
+                .map(|port| (host.to_owned(), port))
+                .map_err(|_| CliError::Usage(format!("invalid broker port {}", port))),
+            None => Ok((self.broker.clone(), 1883)),
+        }
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.base_topic)
+    }
+
+    fn topic(&self, component: &str, id: usize, name: &str) -> String {
+        format!("{}/{}/{}/{}", self.base_topic, component, id, name)
+    }
+}
+
+/// What a device shows up as in Home Assistant. Battery powered devices additionally get a
+/// battery sensor.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Component {
+    Light,
+    Switch,
+    Cover,
+}
+
+impl Component {
+    fn of(device: &DeviceInfo) -> Option<Component> {
+        if device.light_info.is_some() {
+            return Some(Component::Light);
+        }
+        if device.plug_info.is_some() {
+            return Some(Component::Switch);
+        }
+        if device.blind_info.is_some() {
+            return Some(Component::Cover);
+        }
+
+        match device.get_device_type() {
+            Some(DeviceType::Bulb) => Some(Component::Light),
+            Some(DeviceType::Plug) => Some(Component::Switch),
+            Some(DeviceType::Blind) => Some(Component::Cover),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Component::Light => "light",
+            Component::Switch => "switch",
+            Component::Cover => "cover",
+        }
+    }
+}
+
+enum Incoming {
+    Connected,
+    Publish { topic: String, payload: Vec<u8> },
+}
+
+/// Publishes every device of the gateway as Home Assistant MQTT discovery configs, mirrors
+/// their state from Observe notifications and turns command topics into updates:
+///
+/// - lights (JSON schema): `{base}/light/{id}/state` and `.../set`
+/// - plugs as switches: `{base}/switch/{id}/state` and `.../set` with `ON`/`OFF`
+/// - blinds as covers: `{base}/cover/{id}/position`, `.../set` with `OPEN`/`CLOSE` and
+///   `.../set_position` with 0 (closed) to 100 (open)
+/// - battery levels: `{base}/sensor/{id}/battery`
+///
+/// Discovery configs and states are retained and published again on every (re)connect to the
+/// broker. `{base}/status` is `online` while the bridge runs.
+pub fn serve(session: CoapSession, settings: MqttSettings) -> Result<(), CliError> {
+    let (host, port) = settings.broker_addr()?;
+    let mut options = MqttOptions::new(format!("rosthem-{}", settings.base_topic), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        settings.availability_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, connection) = Client::new(options, MQTT_CAPACITY);
+    let incoming = spawn_connection(connection);
+    let mut gateway = ObservedGateway::new(session)?;
+    let bridge = Bridge { client, settings };
+
+    loop {
+        for message in incoming.try_iter() {
+            match message {
+                Incoming::Connected => bridge.announce(&gateway.state),
+                Incoming::Publish { topic, payload } => {
+                    bridge.command(&mut gateway.session, &topic, &payload)
+                }
+            }
+        }
+
+        for event in gateway.poll()? {
+            bridge.notify(&gateway.state, &event);
+        }
+    }
+}
+
+/// Drives the MQTT connection on its own thread, since it blocks while waiting for the broker.
+/// The connection reconnects by itself; every new ConnAck is passed on so the bridge can
+/// announce itself again.
+fn spawn_connection(mut connection: Connection) -> Receiver<Incoming> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for event in connection.iter() {
+            let message = match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Incoming::Connected,
+                Ok(Event::Incoming(Packet::Publish(publish))) => Incoming::Publish {
+                    topic: publish.topic,
+                    payload: publish.payload.to_vec(),
+                },
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("MQTT connection error: {}", e);
+                    thread::sleep(Duration::from_secs(5));
+                    continue;
+                }
+            };
+
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+struct Bridge {
+    client: Client,
+    settings: MqttSettings,
+}
+
+impl Bridge {
+    /// Subscribes to the command topics and publishes discovery configs and current states
+    fn announce(&self, state: &GatewayState) {
+        let base = &self.settings.base_topic;
+        for filter in [
+            format!("{}/+/+/set", base),
+            format!("{}/cover/+/set_position", base),
+        ] {
+            if let Err(e) = self.client.try_subscribe(filter, QoS::AtLeastOnce) {
+                eprintln!("MQTT subscribe failed: {}", e);
+            }
+        }
+
+        for (id, device) in state.devices() {
+            self.add(id, device);
+        }
+
+        self.publish(self.settings.availability_topic(), "online");
+    }
+
+    /// Publishes the discovery configs and the state of a device
+    fn add(&self, id: usize, device: &DeviceInfo) {
+        for (topic, config) in self.discovery(id, device) {
+            self.publish(topic, config.to_string());
+        }
+        self.publish_state(id, device);
+    }
+
+    fn discovery(&self, id: usize, device: &DeviceInfo) -> Vec<(String, Value)> {
+        let settings = &self.settings;
+        let object_id = format!("rosthem_{}", id);
+        let name = device
+            .label
+            .as_deref()
+            .map(str::to_owned)
+            .unwrap_or_else(|| id.to_string());
+        let product = device.product_info.as_ref();
+        let ha_device = json!({
+            "identifiers": [object_id],
+            "name": name,
+            "manufacturer": product.and_then(|p| p.manufacturer.as_deref()),
+            "model": product.and_then(|p| p.product_name.as_deref()),
+        });
+
+        let mut configs = Vec::new();
+        let component = Component::of(device);
+        let mut config = match component {
+            Some(Component::Light) => {
+                let light = device.light_info.clone().unwrap_or_default();
+                let color_modes = if light.get_color_xy().is_some() {
+                    json!(["xy"])
+                } else if light.get_color_temperature().is_some() {
+                    json!(["color_temp"])
+                } else {
+                    json!(["brightness"])
+                };
+
+                json!({
+                    "schema": "json",
+                    "state_topic": settings.topic("light", id, "state"),
+                    "command_topic": settings.topic("light", id, "set"),
+                    "brightness": true,
+                    "brightness_scale": 254,
+                    "supported_color_modes": color_modes,
+                    "min_mireds": 250,
+                    "max_mireds": 454,
+                })
+            }
+            Some(Component::Switch) => json!({
+                "state_topic": settings.topic("switch", id, "state"),
+                "command_topic": settings.topic("switch", id, "set"),
+            }),
+            Some(Component::Cover) => json!({
+                "position_topic": settings.topic("cover", id, "position"),
+                "command_topic": settings.topic("cover", id, "set"),
+                "set_position_topic": settings.topic("cover", id, "set_position"),
+                "payload_stop": null,
+            }),
+            None => Value::Null,
+        };
+
+        if let Some(component) = component {
+            config["name"] = json!(name);
+            config["unique_id"] = json!(object_id);
+            config["availability_topic"] = json!(settings.availability_topic());
+            config["device"] = ha_device.clone();
+            configs.push((
+                format!(
+                    "{}/{}/{}/config",
+                    settings.discovery_prefix,
+                    component.name(),
+                    object_id
+                ),
+                config,
+            ));
+        }
+
+        if product.and_then(|p| p.battery_level).is_some() {
+            configs.push((
+                format!(
+                    "{}/sensor/{}_battery/config",
+                    settings.discovery_prefix, object_id
+                ),
+                json!({
+                    "name": format!("{} battery", name),
+                    "unique_id": format!("{}_battery", object_id),
+                    "device_class": "battery",
+                    "state_class": "measurement",
+                    "unit_of_measurement": "%",
+                    "state_topic": settings.topic("sensor", id, "battery"),
+                    "availability_topic": settings.availability_topic(),
+                    "device": ha_device,
+                }),
+            ));
+        }
+
+        configs
+    }
+
+    fn publish_state(&self, id: usize, device: &DeviceInfo) {
+        let settings = &self.settings;
+
+        match Component::of(device) {
+            Some(Component::Light) => {
+                if let Some(light) = &device.light_info {
+                    let mut state = json!({ "state": on_off(light.get_on()) });
+                    if let Some(brightness) = light.get_brightness() {
+                        state["brightness"] = json!(brightness);
+                    }
+                    if let Some(mireds) = light.get_color_temperature() {
+                        state["color_mode"] = json!("color_temp");
+                        state["color_temp"] = json!(mireds);
+                    } else if let Some((x, y)) = light.get_color_xy() {
+                        state["color_mode"] = json!("xy");
+                        state["color"] = json!({
+                            "x": x as f32 / u16::MAX as f32,
+                            "y": y as f32 / u16::MAX as f32,
+                        });
+                    }
+                    self.publish(settings.topic("light", id, "state"), state.to_string());
+                }
+            }
+            Some(Component::Switch) => {
+                if let Some(plug) = &device.plug_info {
+                    self.publish(settings.topic("switch", id, "state"), on_off(plug.get_on()));
+                }
+            }
+            Some(Component::Cover) => {
+                if let Some(position) = device.blind_info.as_ref().and_then(|b| b.get_position()) {
+                    // Home Assistant counts from closed, the gateway from open
+                    let position = (100.0 - position).round() as u8;
+                    self.publish(
+                        settings.topic("cover", id, "position"),
+                        position.to_string(),
+                    );
+                }
+            }
+            None => {}
+        }
+
+        if let Some(level) = device.product_info.as_ref().and_then(|p| p.battery_level) {
+            self.publish(settings.topic("sensor", id, "battery"), level.to_string());
+        }
+    }
+
+    /// Hands a retained message to the connection thread without waiting for room in its
+    /// buffer, so a stalled broker can't hold up the bridge
+    fn publish(&self, topic: String, payload: impl Into<Vec<u8>>) {
+        if let Err(e) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload.into())
+        {
+            eprintln!("MQTT publish to {} dropped: {}", topic, e);
+        }
+    }
+
+    /// Applies a message from one of the command topics. Bad commands are reported and dropped.
+    fn command(&self, session: &mut SupervisedSession, topic: &str, payload: &[u8]) {
+        let path = match topic.strip_prefix(&format!("{}/", self.settings.base_topic)) {
+            Some(path) => path,
+            None => return,
+        };
+        let parts: Vec<&str> = path.split('/').collect();
+        let payload = String::from_utf8_lossy(payload);
+
+        if let Err(e) = apply_command(session, &parts, payload.trim()) {
+            eprintln!("{}: {}", topic, e);
+        }
+    }
+
+    /// Publishes devices that were added, as well as the new state of changed ones
+    fn notify(&self, state: &GatewayState, event: &StateEvent) {
+        match event {
+            StateEvent::Added(StateKind::Device, id) => {
+                if let Some(device) = state.device(*id) {
+                    self.add(*id, device);
+                }
+            }
+            StateEvent::Changed {
+                kind: StateKind::Device,
+                id,
+                ..
+            } => {
+                if let Some(device) = state.device(*id) {
+                    self.publish_state(*id, device);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// An update of a device, from a message on one of its command topics
+#[derive(Debug, PartialEq)]
+enum DeviceCommand<'a> {
+    Light(&'a str, LightInfo),
+    Plug(&'a str, PlugInfo),
+    Blind(&'a str, BlindInfo),
+}
+
+fn apply_command(
+    session: &mut SupervisedSession,
+    parts: &[&str],
+    payload: &str,
+) -> Result<(), CliError> {
+    match parse_command(parts, payload)? {
+        Some(DeviceCommand::Light(id, light)) => session.update_light(id, light)?,
+        Some(DeviceCommand::Plug(id, plug)) => session.update_plug(id, plug)?,
+        Some(DeviceCommand::Blind(id, blind)) => session.update_blind(id, blind)?,
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// The update for a message on the topic made of `parts` (below the base topic), if it is a
+/// command topic
+fn parse_command<'a>(
+    parts: &[&'a str],
+    payload: &str,
+) -> Result<Option<DeviceCommand<'a>>, CliError> {
+    let command = match *parts {
+        ["light", id, "set"] => DeviceCommand::Light(id, light_command(payload)?),
+        ["switch", id, "set"] => {
+            let on = match payload {
+                "ON" => true,
+                "OFF" => false,
+                _ => {
+                    return Err(CliError::Usage(format!(
+                        "unknown switch command {}",
+                        payload
+                    )))
+                }
+            };
+            DeviceCommand::Plug(id, PlugInfo::default().on(on))
+        }
+        ["cover", id, "set"] => {
+            let position = match payload {
+                "OPEN" => 0.0,
+                "CLOSE" => 100.0,
+                _ => {
+                    return Err(CliError::Usage(format!(
+                        "unknown cover command {}",
+                        payload
+                    )))
+                }
+            };
+            DeviceCommand::Blind(id, BlindInfo::default().position(position))
+        }
+        ["cover", id, "set_position"] => {
+            let position: f32 = payload
+                .parse()
+                .map_err(|_| CliError::Usage(format!("invalid position {}", payload)))?;
+            // Home Assistant counts from closed, the gateway from open
+            DeviceCommand::Blind(id, BlindInfo::default().position(100.0 - position))
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(command))
+}
+
+/// Turns a Home Assistant JSON schema light command into a light update
+fn light_command(payload: &str) -> Result<LightInfo, CliError> {
+    let command: Value = serde_json::from_str(payload)
+        .map_err(|e| CliError::Usage(format!("invalid light command: {}", e)))?;
+
+    let mut light = LightInfo::default();
+    match command["state"].as_str() {
+        Some("ON") => light = light.on(true),
+        Some("OFF") => light = light.on(false),
+        _ => {}
+    }
+    if let Some(brightness) = command["brightness"].as_u64() {
+        light = light.brightness(brightness.min(254) as u8);
+    }
+    if let Some(mireds) = command["color_temp"].as_u64() {
+        light = light.color_temperature(mireds.min(u16::MAX as u64) as u16);
+    }
+    if let (Some(x), Some(y)) = (
+        command["color"]["x"].as_f64(),
+        command["color"]["y"].as_f64(),
+    ) {
+        light = light.color_xy((x * u16::MAX as f64) as u16, (y * u16::MAX as f64) as u16);
+    }
+
+    Ok(light)
+}
+
+fn on_off(on: Option<bool>) -> &'static str {
+    if on.unwrap_or(false) {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rosthem::rosthem_dto::ProductInfo;
+
+    fn bridge() -> Bridge {
+        let (client, _) = Client::new(MqttOptions::new("test", "localhost", 1883), 10);
+        Bridge {
+            client,
+            settings: MqttSettings {
+                broker: "localhost".to_owned(),
+                discovery_prefix: "homeassistant".to_owned(),
+                base_topic: "rosthem".to_owned(),
+                username: None,
+                password: None,
+            },
+        }
+    }
+
+    fn device(label: &str) -> DeviceInfo {
+        DeviceInfo {
+            label: Some(label.to_owned().into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn component_of_prefers_the_reported_state() {
+        let light = device("Lamp").with_light_info(LightInfo::default());
+        let plug = DeviceInfo {
+            plug_info: Some(PlugInfo::default()),
+            ..device("Plug")
+        };
+        let blind = DeviceInfo {
+            blind_info: Some(BlindInfo::default()),
+            ..device("Blind")
+        };
+
+        assert_eq!(Component::of(&light), Some(Component::Light));
+        assert_eq!(Component::of(&plug), Some(Component::Switch));
+        assert_eq!(Component::of(&blind), Some(Component::Cover));
+    }
+
+    #[test]
+    fn component_of_falls_back_to_the_device_type() {
+        let of_type = |device_type| {
+            Component::of(&DeviceInfo {
+                device_type: Some(device_type),
+                ..device("Device")
+            })
+        };
+
+        assert_eq!(of_type(2), Some(Component::Light));
+        assert_eq!(of_type(3), Some(Component::Switch));
+        assert_eq!(of_type(7), Some(Component::Cover));
+        assert_eq!(of_type(0), None);
+    }
+
+    #[test]
+    fn light_command_reads_state_brightness_and_color() {
+        assert_eq!(
+            light_command(r#"{"state": "ON", "brightness": 300, "color_temp": 370}"#).unwrap(),
+            LightInfo::default()
+                .on(true)
+                .brightness(254)
+                .color_temperature(370)
+        );
+        assert_eq!(
+            light_command(r#"{"state": "OFF", "color": {"x": 0.5, "y": 0.25}}"#).unwrap(),
+            LightInfo::default()
+                .on(false)
+                .color_xy(u16::MAX / 2, u16::MAX / 4)
+        );
+        assert_eq!(light_command("{}").unwrap(), LightInfo::default());
+        assert!(light_command("ON").is_err());
+    }
+
+    #[test]
+    fn parse_command_maps_topics_to_updates() {
+        assert_eq!(
+            parse_command(&["light", "65536", "set"], r#"{"state": "ON"}"#).unwrap(),
+            Some(DeviceCommand::Light("65536", LightInfo::default().on(true)))
+        );
+        assert_eq!(
+            parse_command(&["switch", "65537", "set"], "OFF").unwrap(),
+            Some(DeviceCommand::Plug("65537", PlugInfo::default().on(false)))
+        );
+        assert_eq!(
+            parse_command(&["cover", "65538", "set"], "OPEN").unwrap(),
+            Some(DeviceCommand::Blind(
+                "65538",
+                BlindInfo::default().position(0.0)
+            ))
+        );
+        assert_eq!(
+            parse_command(&["cover", "65538", "set_position"], "30").unwrap(),
+            Some(DeviceCommand::Blind(
+                "65538",
+                BlindInfo::default().position(70.0)
+            ))
+        );
+        assert_eq!(
+            parse_command(&["sensor", "65539", "battery"], "50").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_command_rejects_unknown_payloads() {
+        assert!(parse_command(&["switch", "65537", "set"], "TOGGLE").is_err());
+        assert!(parse_command(&["cover", "65538", "set"], "STOP").is_err());
+        assert!(parse_command(&["cover", "65538", "set_position"], "half").is_err());
+    }
+
+    #[test]
+    fn discovery_of_a_light() {
+        let lamp = device("Lamp").with_light_info(LightInfo::default().color_xy(100, 200));
+        let configs = bridge().discovery(65536, &lamp);
+
+        assert_eq!(configs.len(), 1);
+        let (topic, config) = &configs[0];
+        assert_eq!(topic, "homeassistant/light/rosthem_65536/config");
+        assert_eq!(config["name"], "Lamp");
+        assert_eq!(config["unique_id"], "rosthem_65536");
+        assert_eq!(config["schema"], "json");
+        assert_eq!(config["state_topic"], "rosthem/light/65536/state");
+        assert_eq!(config["command_topic"], "rosthem/light/65536/set");
+        assert_eq!(config["supported_color_modes"], json!(["xy"]));
+        assert_eq!(config["availability_topic"], "rosthem/status");
+    }
+
+    #[test]
+    fn discovery_adds_a_battery_sensor() {
+        let remote = DeviceInfo {
+            device_type: Some(0),
+            product_info: Some(ProductInfo {
+                battery_level: Some(87),
+                ..Default::default()
+            }),
+            ..device("Remote")
+        };
+        let configs = bridge().discovery(65540, &remote);
+
+        assert_eq!(configs.len(), 1);
+        let (topic, config) = &configs[0];
+        assert_eq!(topic, "homeassistant/sensor/rosthem_65540_battery/config");
+        assert_eq!(config["name"], "Remote battery");
+        assert_eq!(config["device_class"], "battery");
+        assert_eq!(config["state_topic"], "rosthem/sensor/65540/battery");
+    }
+
+    #[test]
+    fn discovery_of_a_cover_without_a_label() {
+        let blind = DeviceInfo {
+            blind_info: Some(BlindInfo::default()),
+            ..Default::default()
+        };
+        let configs = bridge().discovery(65538, &blind);
+
+        assert_eq!(configs.len(), 1);
+        let (topic, config) = &configs[0];
+        assert_eq!(topic, "homeassistant/cover/rosthem_65538/config");
+        assert_eq!(config["name"], "65538");
+        assert_eq!(
+            config["set_position_topic"],
+            "rosthem/cover/65538/set_position"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use rosthem::rosthem_dto::{DeviceInfo, GroupInfo};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct DeviceSummary {
+    pub id: Option<usize>,
+    pub name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub on: Option<bool>,
+    pub brightness: Option<u8>,
+    pub preset: Option<&'static str>,
+    pub color_xy: Option<(u16, u16)>,
+}
+
+impl From<&DeviceInfo> for DeviceSummary {
+    fn from(device: &DeviceInfo) -> Self {
+        let light = device.light_info.as_ref();
+        let product = device.product_info.as_ref();
+
+        DeviceSummary {
+            id: device.id,
+            name: device.label.as_ref().map(|l| l.to_string()),
+            manufacturer: product.and_then(|p| p.manufacturer.as_ref().map(|m| m.to_string())),
+            product: product.and_then(|p| p.product_name.as_ref().map(|n| n.to_string())),
+            on: light.and_then(|l| l.get_on()),
+            brightness: light.and_then(|l| l.get_brightness()),
+            preset: light.and_then(|l| l.get_color_preset()).map(|p| p.name()),
+            color_xy: light.and_then(|l| l.get_color_xy()),
+        }
+    }
+}
+
+impl DeviceSummary {
+    pub const HEADERS: &'static [&'static str] = &["ID", "NAME", "ON", "BRIGHTNESS", "COLOR"];
+
+    pub fn row(&self) -> Vec<String> {
+        let color = match (self.preset, self.color_xy) {
+            (Some(preset), _) => preset.to_owned(),
+            (None, Some((x, y))) => format!("{},{}", x, y),
+            (None, None) => String::new(),
+        };
+
+        vec![
+            cell(self.id),
+            cell(self.name.as_ref()),
+            on_off(self.on),
+            cell(self.brightness),
+            color,
+        ]
+    }
+
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("id", cell(self.id)),
+            ("name", cell(self.name.as_ref())),
+            ("manufacturer", cell(self.manufacturer.as_ref())),
+            ("product", cell(self.product.as_ref())),
+        ];
+        if self.on.is_some() {
+            let row = self.row();
+            fields.push(("on", row[2].clone()));
+            fields.push(("brightness", row[3].clone()));
+            fields.push(("color", row[4].clone()));
+        }
+
+        fields
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct GroupSummary {
+    pub id: Option<usize>,
+    pub name: Option<String>,
+    pub on: Option<bool>,
+    pub brightness: Option<u8>,
+    pub members: Vec<usize>,
+}
+
+impl From<&GroupInfo> for GroupSummary {
+    fn from(group: &GroupInfo) -> Self {
+        GroupSummary {
+            id: group.id,
+            name: group.name.as_ref().map(|n| n.to_string()),
+            on: group.get_on(),
+            brightness: group.brightness,
+            members: group.member_ids().to_vec(),
+        }
+    }
+}
+
+impl GroupSummary {
+    pub const HEADERS: &'static [&'static str] = &["ID", "NAME", "ON", "BRIGHTNESS", "MEMBERS"];
+
+    pub fn row(&self) -> Vec<String> {
+        vec![
+            cell(self.id),
+            cell(self.name.as_ref()),
+            on_off(self.on),
+            cell(self.brightness),
+            self.members
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        ]
+    }
+
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let row = self.row();
+        vec![
+            ("id", row[0].clone()),
+            ("name", row[1].clone()),
+            ("on", row[2].clone()),
+            ("brightness", row[3].clone()),
+            ("members", row[4].clone()),
+        ]
+    }
+}
+
+pub fn print_json<T: Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
+
+/// Left aligned columns, two spaces apart
+pub fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(&headers).chain(rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+pub fn print_fields(fields: &[(&str, String)]) {
+    let width = fields.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    for (key, value) in fields {
+        println!("{:width$}  {}", key, value, width = width);
+    }
+}
+
+fn cell<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn on_off(on: Option<bool>) -> String {
+    match on {
+        Some(true) => "on".to_owned(),
+        Some(false) => "off".to_owned(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{parse_preset, CliError};
+use chrono::{Local, NaiveTime};
+use rosthem::rosthem_dto::{field_name, LightInfo};
+use rosthem::{CoapSession, CoapSessionExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The lighting a gateway should have, e.g.
+///
+/// ```yaml
+/// rate: 2 # updates per second
+/// lights:
+///   - device: Kitchen # label or id
+///     schedule:
+///       - from: "07:00"
+///         on: true
+///         brightness: 200
+///         preset: warm-white
+///       - from: "23:00"
+///         on: false
+/// ```
+///
+/// The schedule entry with the latest `from` that has passed (in local time) applies; before the
+/// first one of the day, the last one of the previous day still does.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DesiredState {
+    #[serde(default = "default_rate")]
+    pub rate: f32,
+    pub lights: Vec<DesiredLight>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DesiredLight {
+    pub device: String,
+    pub schedule: Vec<ScheduleEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    /// `HH:MM`
+    pub from: String,
+    pub on: Option<bool>,
+    pub brightness: Option<u8>,
+    /// A color preset like warm-white
+    pub preset: Option<String>,
+    pub xy: Option<(u16, u16)>,
+    /// Mireds
+    pub color_temperature: Option<u16>,
+}
+
+/// A field of a light that differs from the desired state
+#[derive(Serialize, Debug)]
+pub struct Drift {
+    pub device: String,
+    pub id: usize,
+    pub field: String,
+    pub current: Option<Value>,
+    pub desired: Value,
+}
+
+impl Drift {
+    pub const HEADERS: &'static [&'static str] = &["DEVICE", "ID", "FIELD", "CURRENT", "DESIRED"];
+
+    pub fn row(&self) -> Vec<String> {
+        vec![
+            self.device.clone(),
+            self.id.to_string(),
+            self.field.clone(),
+            self.current
+                .as_ref()
+                .map(Value::to_string)
+                .unwrap_or_default(),
+            self.desired.to_string(),
+        ]
+    }
+}
+
+/// A minimal update for one light, with the drift it fixes
+pub struct Plan {
+    pub id: usize,
+    pub update: LightInfo,
+    pub drift: Vec<Drift>,
+}
+
+fn default_rate() -> f32 {
+    2.0
+}
+
+impl DesiredState {
+    pub fn load(path: &Path) -> Result<DesiredState, CliError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| CliError::Config(format!("{}: {}", path.display(), e)))?;
+        let state: DesiredState = serde_yaml::from_str(&text)
+            .map_err(|e| CliError::Config(format!("{}: {}", path.display(), e)))?;
+
+        if state.rate.is_nan() || state.rate <= 0.0 {
+            return Err(CliError::Config(format!(
+                "{}: rate has to be positive",
+                path.display()
+            )));
+        }
+        for light in &state.lights {
+            for entry in &light.schedule {
+                entry.time()?;
+                entry.light_info()?;
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Compares the lights against the gateway and returns the updates that are needed at
+    /// `now`. Lights the gateway doesn't know are reported as a warning and skipped.
+    pub fn plan(&self, session: &mut CoapSession, now: NaiveTime) -> Result<Vec<Plan>, CliError> {
+        let mut devices = Vec::new();
+        for id in session.list_devices()? {
+            devices.push((id, session.request_status(&id.to_string())?));
+        }
+
+        let mut plans = Vec::new();
+        for light in &self.lights {
+            let entry = match light.active_entry(now)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let found = devices.iter().find(|(id, device)| {
+                device.label.as_deref() == Some(light.device.as_str())
+                    || id.to_string() == light.device
+            });
+            let (id, device) = match found {
+                Some(found) => found,
+                None => {
+                    // Maybe unplugged or renamed; the other lights are still reconciled
+                    eprintln!("warning: unknown device {}, skipped", light.device);
+                    continue;
+                }
+            };
+            let current = device.light_info.clone().unwrap_or_default();
+            let update = entry.light_info()?.diff(&current);
+
+            if !update.is_empty() {
+                plans.push(Plan {
+                    id: *id,
+                    drift: drift(&light.device, *id, &update, &current),
+                    update,
+                });
+            }
+        }
+
+        Ok(plans)
+    }
+}
+
+impl DesiredLight {
+    fn active_entry(&self, now: NaiveTime) -> Result<Option<&ScheduleEntry>, CliError> {
+        let mut entries = Vec::new();
+        for entry in &self.schedule {
+            entries.push((entry.time()?, entry));
+        }
+        entries.sort_by_key(|(time, _)| *time);
+
+        Ok(entries
+            .iter()
+            .rev()
+            .find(|(time, _)| *time <= now)
+            .or_else(|| entries.last())
+            .map(|(_, entry)| *entry))
+    }
+}
+
+impl ScheduleEntry {
+    fn time(&self) -> Result<NaiveTime, CliError> {
+        NaiveTime::parse_from_str(&self.from, "%H:%M")
+            .map_err(|_| CliError::Config(format!("invalid time {}, expected HH:MM", self.from)))
+    }
+
+    fn light_info(&self) -> Result<LightInfo, CliError> {
+        // A light takes one color mode per update
+        let colors = [
+            self.preset.is_some(),
+            self.xy.is_some(),
+            self.color_temperature.is_some(),
+        ];
+        if colors.iter().filter(|set| **set).count() > 1 {
+            return Err(CliError::Config(format!(
+                "entry from {} sets more than one of preset, xy and color_temperature",
+                self.from
+            )));
+        }
+
+        let mut light = LightInfo::default();
+        if let Some(on) = self.on {
+            light = light.on(on);
+        }
+        if let Some(brightness) = self.brightness {
+            light = light.brightness(brightness);
+        }
+        if let Some(preset) = &self.preset {
+            light = light.color_preset(parse_preset(preset)?);
+        }
+        if let Some((x, y)) = self.xy {
+            light = light.color_xy(x, y);
+        }
+        if let Some(mireds) = self.color_temperature {
+            light = light.color_temperature(mireds);
+        }
+
+        Ok(light)
+    }
+}
+
+/// Lists the fields of `update` with their current values, by their names in rosthem-dto
+fn drift(device: &str, id: usize, update: &LightInfo, current: &LightInfo) -> Vec<Drift> {
+    let options = |light: &LightInfo| {
+        serde_json::to_value(light)
+            .ok()
+            .and_then(|value| value["3311"][0].as_object().cloned())
+            .unwrap_or_default()
+    };
+    let current = options(current);
+
+    options(update)
+        .into_iter()
+        .map(|(key, desired)| Drift {
+            device: device.to_owned(),
+            id,
+            field: field_name(&key).unwrap_or(&key).to_owned(),
+            current: current.get(&key).cloned(),
+            desired,
+        })
+        .collect()
+}
+
+/// Sends the updates, at most `rate` per second
+pub fn apply(session: &mut CoapSession, plans: &[Plan], rate: f32) -> Result<(), CliError> {
+    let interval = Duration::from_secs_f32(1.0 / rate);
+    let mut next = Instant::now();
+
+    for plan in plans {
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+        next = Instant::now() + interval;
+
+        session.update_light(&plan.id.to_string(), plan.update.clone())?;
+    }
+
+    Ok(())
+}
+
+/// The local time of day
+pub fn now() -> NaiveTime {
+    Local::now().time()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(from: &str, on: bool) -> ScheduleEntry {
+        ScheduleEntry {
+            from: from.to_owned(),
+            on: Some(on),
+            brightness: None,
+            preset: None,
+            xy: None,
+            color_temperature: None,
+        }
+    }
+
+    fn light(schedule: Vec<ScheduleEntry>) -> DesiredLight {
+        DesiredLight {
+            device: "Kitchen".to_owned(),
+            schedule,
+        }
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    fn active_from(light: &DesiredLight, now: &str) -> Option<String> {
+        light
+            .active_entry(time(now))
+            .unwrap()
+            .map(|entry| entry.from.clone())
+    }
+
+    #[test]
+    fn active_entry_is_the_latest_that_passed() {
+        let light = light(vec![
+            entry("23:00", false),
+            entry("07:00", true),
+            entry("12:30", true),
+        ]);
+
+        assert_eq!(active_from(&light, "07:00").as_deref(), Some("07:00"));
+        assert_eq!(active_from(&light, "12:29").as_deref(), Some("07:00"));
+        assert_eq!(active_from(&light, "12:30").as_deref(), Some("12:30"));
+        assert_eq!(active_from(&light, "23:59").as_deref(), Some("23:00"));
+    }
+
+    #[test]
+    fn active_entry_wraps_around_before_the_first_of_the_day() {
+        let light = light(vec![entry("07:00", true), entry("23:00", false)]);
+
+        assert_eq!(active_from(&light, "00:00").as_deref(), Some("23:00"));
+        assert_eq!(active_from(&light, "06:59").as_deref(), Some("23:00"));
+    }
+
+    #[test]
+    fn active_entry_of_an_empty_schedule() {
+        assert_eq!(active_from(&light(Vec::new()), "12:00"), None);
+    }
+
+    #[test]
+    fn active_entry_rejects_invalid_times() {
+        assert!(light(vec![entry("7am", true)])
+            .active_entry(time("12:00"))
+            .is_err());
+    }
+
+    #[test]
+    fn light_info_allows_one_color_mode() {
+        let cases = [
+            (None, None, None, true),
+            (Some("warm-white"), None, None, true),
+            (None, Some((100, 200)), None, true),
+            (None, None, Some(250), true),
+            (Some("warm-white"), Some((100, 200)), None, false),
+            (Some("warm-white"), None, Some(250), false),
+            (None, Some((100, 200)), Some(250), false),
+            (Some("warm-white"), Some((100, 200)), Some(250), false),
+        ];
+
+        for (preset, xy, color_temperature, valid) in cases {
+            let entry = ScheduleEntry {
+                preset: preset.map(str::to_owned),
+                xy,
+                color_temperature,
+                ..entry("07:00", true)
+            };
+            assert_eq!(
+                entry.light_info().is_ok(),
+                valid,
+                "{:?} {:?} {:?}",
+                preset,
+                xy,
+                color_temperature
+            );
+        }
+    }
+
+    #[test]
+    fn drift_names_the_fields_of_the_update() {
+        let current = LightInfo::default().on(false).brightness(100);
+        let update = LightInfo::default().on(true).brightness(100).diff(&current);
+
+        let drift = drift("Kitchen", 65536, &update, &current);
+        assert_eq!(drift.len(), 1);
+        assert_eq!(drift[0].device, "Kitchen");
+        assert_eq!(drift[0].id, 65536);
+        assert_eq!(drift[0].field, "on_off");
+        assert_eq!(drift[0].current, Some(Value::from(0)));
+        assert_eq!(drift[0].desired, Value::from(1));
+    }
+
+    #[test]
+    fn drift_of_fields_without_a_current_value() {
+        let current = LightInfo::default().on(true);
+        let update = LightInfo::default().color_xy(100, 200).diff(&current);
+
+        let drift: Vec<(String, Option<Value>, Value)> = drift("Kitchen", 65536, &update, &current)
+            .into_iter()
+            .map(|drift| (drift.field, drift.current, drift.desired))
+            .collect();
+        assert_eq!(
+            drift,
+            vec![
+                ("color_x".to_owned(), None, Value::from(100)),
+                ("color_y".to_owned(), None, Value::from(200)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use crate::merge::{diff_field, merge_field};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BlindInfo {
+    #[serde(rename = "15015")]
+    blind_options: [BlindOptions; 1],
+}
+
+impl BlindInfo {
+    /// 0 is fully open, 100 fully closed
+    pub fn position(mut self, position: f32) -> Self {
+        self.blind_options[0].position = Some(position.clamp(0.0, 100.0));
+        self
+    }
+
+    pub fn get_position(&self) -> Option<f32> {
+        self.blind_options[0].position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blind_options[0] == BlindOptions::default()
+    }
+
+    pub fn merge(&mut self, other: &BlindInfo) {
+        merge_field(
+            &mut self.blind_options[0].position,
+            &other.blind_options[0].position,
+        );
+    }
+
+    pub fn diff(&self, current: &BlindInfo) -> BlindInfo {
+        BlindInfo {
+            blind_options: [BlindOptions {
+                position: diff_field(
+                    &self.blind_options[0].position,
+                    &current.blind_options[0].position,
+                ),
+            }],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+struct BlindOptions {
+    #[serde(rename = "5536", skip_serializing_if = "Option::is_none")]
+    position: Option<f32>,
+}
@@ -1,14 +1,22 @@
+use crate::blind::BlindInfo;
 use crate::light::LightInfo;
+use crate::merge::{diff_field, merge_field};
+use crate::plug::PlugInfo;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
 #[derive(Debug, Clone, Copy)]
 pub enum DeviceType {
+    Remote,
     Bulb,
+    Plug,
+    MotionSensor,
+    Repeater,
+    Blind,
     Unknown,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct DeviceInfo {
     #[serde(rename = "9001", skip_serializing_if = "Option::is_none")]
     pub label: Option<Cow<'static, str>>,
@@ -18,16 +26,29 @@ pub struct DeviceInfo {
     pub product_info: Option<ProductInfo>,
     #[serde(rename = "5750", skip_serializing_if = "Option::is_none")]
     pub device_type: Option<usize>,
+    /// 1 if the gateway can currently reach the device, 0 if not
+    #[serde(rename = "9019", skip_serializing_if = "Option::is_none")]
+    pub reachable: Option<u8>,
+    /// Unix timestamp of the last time the gateway heard from the device
+    #[serde(rename = "9020", skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>,
     #[serde(flatten, skip_serializing_if = "Option::is_none")]
     pub light_info: Option<LightInfo>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub plug_info: Option<PlugInfo>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub blind_info: Option<BlindInfo>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct ProductInfo {
     #[serde(rename = "0", skip_serializing_if = "Option::is_none")]
     pub manufacturer: Option<Cow<'static, str>>,
     #[serde(rename = "1", skip_serializing_if = "Option::is_none")]
     pub product_name: Option<Cow<'static, str>>,
+    /// Percent, only for battery powered devices
+    #[serde(rename = "9", skip_serializing_if = "Option::is_none")]
+    pub battery_level: Option<u8>,
 }
 
 impl DeviceInfo {
@@ -36,10 +57,212 @@ impl DeviceInfo {
         self
     }
 
+    pub fn is_reachable(&self) -> Option<bool> {
+        self.reachable.map(|r| r != 0)
+    }
+
     pub fn get_device_type(&self) -> Option<DeviceType> {
         self.device_type.map(|t| match t {
+            0 => DeviceType::Remote,
             2 => DeviceType::Bulb,
+            3 => DeviceType::Plug,
+            4 => DeviceType::MotionSensor,
+            6 => DeviceType::Repeater,
+            7 => DeviceType::Blind,
             _ => DeviceType::Unknown,
         })
     }
+
+    /// Takes over every field that is set in `other`, merging the light, plug and blind state
+    /// field by field
+    pub fn merge(&mut self, other: &DeviceInfo) {
+        merge_field(&mut self.label, &other.label);
+        merge_field(&mut self.id, &other.id);
+        merge_field(&mut self.device_type, &other.device_type);
+        merge_field(&mut self.reachable, &other.reachable);
+        merge_field(&mut self.last_seen, &other.last_seen);
+
+        match (&mut self.product_info, &other.product_info) {
+            (Some(product_info), Some(other)) => product_info.merge(other),
+            (product_info, other) => merge_field(product_info, other),
+        }
+        match (&mut self.light_info, &other.light_info) {
+            (Some(light_info), Some(other)) => light_info.merge(other),
+            (light_info, other) => merge_field(light_info, other),
+        }
+        match (&mut self.plug_info, &other.plug_info) {
+            (Some(plug_info), Some(other)) => plug_info.merge(other),
+            (plug_info, other) => merge_field(plug_info, other),
+        }
+        match (&mut self.blind_info, &other.blind_info) {
+            (Some(blind_info), Some(other)) => blind_info.merge(other),
+            (blind_info, other) => merge_field(blind_info, other),
+        }
+    }
+
+    /// The fields set here that differ from `current`. Light, plug and blind state are diffed
+    /// field by field and left out when nothing in them changed.
+    pub fn diff(&self, current: &DeviceInfo) -> DeviceInfo {
+        let product_info = self.product_info.as_ref().and_then(|desired| {
+            let diff = desired.diff(current.product_info.as_ref().unwrap_or(&Default::default()));
+            (diff != ProductInfo::default()).then_some(diff)
+        });
+        let light_info = self.light_info.as_ref().and_then(|desired| {
+            let diff = desired.diff(current.light_info.as_ref().unwrap_or(&Default::default()));
+            (!diff.is_empty()).then_some(diff)
+        });
+        let plug_info = self.plug_info.as_ref().and_then(|desired| {
+            let diff = desired.diff(current.plug_info.as_ref().unwrap_or(&Default::default()));
+            (!diff.is_empty()).then_some(diff)
+        });
+        let blind_info = self.blind_info.as_ref().and_then(|desired| {
+            let diff = desired.diff(current.blind_info.as_ref().unwrap_or(&Default::default()));
+            (!diff.is_empty()).then_some(diff)
+        });
+
+        DeviceInfo {
+            label: diff_field(&self.label, &current.label),
+            id: diff_field(&self.id, &current.id),
+            product_info,
+            device_type: diff_field(&self.device_type, &current.device_type),
+            reachable: diff_field(&self.reachable, &current.reachable),
+            last_seen: diff_field(&self.last_seen, &current.last_seen),
+            light_info,
+            plug_info,
+            blind_info,
+        }
+    }
+}
+
+impl ProductInfo {
+    pub fn merge(&mut self, other: &ProductInfo) {
+        merge_field(&mut self.manufacturer, &other.manufacturer);
+        merge_field(&mut self.product_name, &other.product_name);
+        merge_field(&mut self.battery_level, &other.battery_level);
+    }
+
+    pub fn diff(&self, current: &ProductInfo) -> ProductInfo {
+        ProductInfo {
+            manufacturer: diff_field(&self.manufacturer, &current.manufacturer),
+            product_name: diff_field(&self.product_name, &current.product_name),
+            battery_level: diff_field(&self.battery_level, &current.battery_level),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lamp() -> DeviceInfo {
+        DeviceInfo {
+            label: Some("Lamp".into()),
+            id: Some(65537),
+            ..Default::default()
+        }
+        .with_light_info(LightInfo::default().on(true).brightness(10))
+    }
+
+    #[test]
+    fn diff_of_equal_devices_is_empty() {
+        assert_eq!(lamp().diff(&lamp()), DeviceInfo::default());
+    }
+
+    #[test]
+    fn diff_leaves_out_unchanged_light_info() {
+        let desired = DeviceInfo {
+            label: Some("Desk lamp".into()),
+            ..lamp()
+        };
+
+        assert_eq!(
+            desired.diff(&lamp()),
+            DeviceInfo {
+                label: Some("Desk lamp".into()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn diff_keeps_changed_light_fields_only() {
+        let desired = lamp().with_light_info(LightInfo::default().on(true).brightness(20));
+
+        assert_eq!(
+            desired.diff(&lamp()),
+            DeviceInfo::default().with_light_info(LightInfo::default().brightness(20))
+        );
+    }
+
+    #[test]
+    fn diff_against_missing_nested_info_keeps_all_of_it() {
+        let current = DeviceInfo {
+            light_info: None,
+            ..lamp()
+        };
+
+        assert_eq!(
+            lamp().diff(&current),
+            DeviceInfo::default().with_light_info(LightInfo::default().on(true).brightness(10))
+        );
+    }
+
+    #[test]
+    fn diff_without_desired_nested_info_leaves_it_out() {
+        let desired = DeviceInfo {
+            light_info: None,
+            product_info: None,
+            ..lamp()
+        };
+        let current = DeviceInfo {
+            product_info: Some(ProductInfo {
+                battery_level: Some(50),
+                ..Default::default()
+            }),
+            ..lamp()
+        };
+
+        assert_eq!(desired.diff(&current), DeviceInfo::default());
+    }
+
+    #[test]
+    fn diff_of_product_info_keeps_changed_fields_only() {
+        let product_info = |battery_level| ProductInfo {
+            manufacturer: Some("IKEA of Sweden".into()),
+            battery_level: Some(battery_level),
+            ..Default::default()
+        };
+        let desired = DeviceInfo {
+            product_info: Some(product_info(40)),
+            ..Default::default()
+        };
+        let current = DeviceInfo {
+            product_info: Some(product_info(50)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            desired.diff(&current),
+            DeviceInfo {
+                product_info: Some(ProductInfo {
+                    battery_level: Some(40),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn merge_fills_in_missing_nested_info() {
+        let mut device = lamp();
+        let plug = DeviceInfo {
+            plug_info: Some(PlugInfo::default().on(true)),
+            ..Default::default()
+        };
+        device.merge(&plug);
+
+        assert_eq!(device.plug_info, plug.plug_info);
+        assert_eq!(device.light_info, lamp().light_info);
+    }
 }
@@ -0,0 +1,156 @@
+/// The numeric keys of the serde renames in this crate with the names of the fields they map
+/// to, e.g. for annotating raw gateway payloads in traces. The tests check it against the
+/// serialized DTOs, so a new rename needs an entry here.
+pub const FIELD_NAMES: &[(&str, &str)] = &[
+    ("9001", "label"),
+    ("9003", "id"),
+    ("3", "product_info"),
+    ("0", "manufacturer"),
+    ("1", "product_name"),
+    ("5750", "device_type"),
+    ("9019", "reachable"),
+    ("9020", "last_seen"),
+    ("3311", "light_options"),
+    ("5850", "on_off"),
+    ("5851", "brightness"),
+    ("5706", "color_preset"),
+    ("5709", "color_x"),
+    ("5710", "color_y"),
+    ("5711", "color_temperature"),
+    ("9", "battery_level"),
+    ("3312", "plug_options"),
+    ("15015", "blind_options"),
+    ("5536", "position"),
+    ("9039", "mood"),
+    ("9018", "members"),
+    ("15002", "accessories"),
+];
+
+/// The field name of a Tradfri payload key, if one of the DTOs has it
+pub fn field_name(key: &str) -> Option<&'static str> {
+    FIELD_NAMES
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, name)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BlindInfo, DeviceInfo, GroupInfo, GroupMembers, IdList, LightColorPreset, LightInfo,
+        MoodInfo, PlugInfo, ProductInfo,
+    };
+    use serde::Serialize;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    fn collect_keys(value: &Value, keys: &mut BTreeSet<String>) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object {
+                    keys.insert(key.clone());
+                    collect_keys(value, keys);
+                }
+            }
+            Value::Array(array) => array.iter().for_each(|value| collect_keys(value, keys)),
+            _ => {}
+        }
+    }
+
+    fn device(light_info: Option<LightInfo>) -> DeviceInfo {
+        DeviceInfo {
+            label: Some("Lamp".into()),
+            id: Some(65536),
+            product_info: Some(ProductInfo {
+                manufacturer: Some("IKEA of Sweden".into()),
+                product_name: Some("TRADFRI bulb".into()),
+                battery_level: Some(87),
+            }),
+            device_type: Some(2),
+            reachable: Some(1),
+            last_seen: Some(1600000000),
+            light_info,
+            plug_info: Some(PlugInfo::default().on(true)),
+            blind_info: Some(BlindInfo::default().position(50.0)),
+        }
+    }
+
+    /// Every DTO with every field set, so all keys show up in the serialized form
+    fn samples() -> Vec<Value> {
+        fn to_value<T: Serialize>(value: &T) -> Value {
+            serde_json::to_value(value).unwrap()
+        }
+
+        // The color fields of a light are exclusive, so they need a light each
+        let lights = [
+            LightInfo::default()
+                .on(true)
+                .brightness(100)
+                .color_preset(LightColorPreset::WarmWhite),
+            LightInfo::default().color_xy(100, 200),
+            LightInfo::default().color_temperature(300),
+        ];
+        let group = GroupInfo {
+            name: Some("Kitchen".into()),
+            id: Some(131073),
+            on_off: Some(1),
+            brightness: Some(100),
+            mood: Some(196608),
+            members: Some(GroupMembers {
+                accessories: Some(IdList { ids: vec![65536] }),
+            }),
+        };
+        let mood = MoodInfo {
+            name: Some("FOCUS".into()),
+            id: Some(196608),
+        };
+
+        let mut samples: Vec<Value> = lights
+            .iter()
+            .map(|light| to_value(&device(Some(light.clone()))))
+            .collect();
+        samples.push(to_value(&group));
+        samples.push(to_value(&mood));
+        samples
+    }
+
+    #[test]
+    fn every_serialized_key_has_a_name() {
+        let mut keys = BTreeSet::new();
+        for sample in samples() {
+            collect_keys(&sample, &mut keys);
+        }
+
+        for key in &keys {
+            assert!(
+                field_name(key).is_some(),
+                "{} is missing in FIELD_NAMES",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn every_name_belongs_to_a_serialized_key() {
+        let mut keys = BTreeSet::new();
+        for sample in samples() {
+            collect_keys(&sample, &mut keys);
+        }
+
+        for (key, name) in FIELD_NAMES {
+            assert!(
+                keys.contains(*key),
+                "{} ({}) isn't used by any DTO",
+                key,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn keys_are_unique() {
+        let keys: BTreeSet<&str> = FIELD_NAMES.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys.len(), FIELD_NAMES.len());
+    }
+}
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GroupInfo {
+    #[serde(rename = "9001", skip_serializing_if = "Option::is_none")]
+    pub name: Option<Cow<'static, str>>,
+    #[serde(rename = "9003", skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    #[serde(rename = "5850", skip_serializing_if = "Option::is_none")]
+    pub on_off: Option<u8>,
+    #[serde(rename = "5851", skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<u8>,
+    #[serde(rename = "9039", skip_serializing_if = "Option::is_none")]
+    pub mood: Option<usize>,
+    #[serde(rename = "9018", skip_serializing_if = "Option::is_none")]
+    pub members: Option<GroupMembers>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GroupMembers {
+    #[serde(rename = "15002", skip_serializing_if = "Option::is_none")]
+    pub accessories: Option<IdList>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IdList {
+    #[serde(rename = "9003", default)]
+    pub ids: Vec<usize>,
+}
+
+impl GroupInfo {
+    /// A group update turning all lights of the group on or off
+    pub fn on(mut self, on: bool) -> Self {
+        self.on_off = Some(if on { 1 } else { 0 });
+        self
+    }
+
+    pub fn get_on(&self) -> Option<bool> {
+        self.on_off.map(|o| o != 0)
+    }
+
+    /// A group update activating a mood of the group
+    pub fn mood(mut self, mood: usize) -> Self {
+        self.mood = Some(mood);
+        self
+    }
+
+    pub fn brightness(mut self, brightness: u8) -> Self {
+        self.brightness = Some(brightness.max(1));
+        self
+    }
+
+    /// Ids of the devices in the group
+    pub fn member_ids(&self) -> &[usize] {
+        self.members
+            .as_ref()
+            .and_then(|m| m.accessories.as_ref())
+            .map(|a| a.ids.as_slice())
+            .unwrap_or(&[])
+    }
+}
@@ -1,5 +1,16 @@
+mod blind;
 mod device_info;
+mod fields;
+mod group;
 mod light;
+mod merge;
+mod mood;
+mod plug;
 
+pub use blind::*;
 pub use device_info::*;
+pub use fields::*;
+pub use group::*;
 pub use light::*;
+pub use mood::*;
+pub use plug::*;
@@ -1,3 +1,4 @@
+use crate::merge::{diff_field, merge_field};
 use prisma::color_space::named::SRgb;
 use prisma::color_space::{ConvertFromXyz, ConvertToXyz};
 use prisma::encoding::EncodableColor;
@@ -6,7 +7,7 @@ use prisma::{Rgb, XyY};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct LightInfo {
     #[serde(rename = "3311")]
     light_options: [LightOptions; 1],
@@ -64,6 +65,19 @@ impl LightInfo {
             .zip(self.light_options[0].color_y)
     }
 
+    /// Color temperature in mireds (250 cold to 454 warm) for white spectrum bulbs
+    pub fn color_temperature(mut self, mireds: u16) -> Self {
+        self.light_options[0].color_preset = None;
+        self.light_options[0].color_x = None;
+        self.light_options[0].color_y = None;
+        self.light_options[0].color_temperature = Some(mireds);
+        self
+    }
+
+    pub fn get_color_temperature(&self) -> Option<u16> {
+        self.light_options[0].color_temperature
+    }
+
     pub fn color_rgb(mut self, rgb: &Rgb<f32>) -> Self {
         let xyz = XyY::from_color(&SRgb::new().convert_to_xyz(&rgb.srgb_encoded()));
         self.light_options[0].color_preset = None;
@@ -81,9 +95,62 @@ impl LightInfo {
                 .color_cast(),
         )
     }
+
+    /// Whether no field is set, e.g. because a diff found nothing to change
+    pub fn is_empty(&self) -> bool {
+        self.light_options[0] == LightOptions::default()
+    }
+
+    /// Takes over every field that is set in `other`. A color set in `other` replaces the
+    /// color here the same way the color builders do, so only one color mode is left.
+    pub fn merge(&mut self, other: &LightInfo) {
+        let (options, other) = (&mut self.light_options[0], &other.light_options[0]);
+        if other.color_preset.is_some()
+            || other.color_x.is_some()
+            || other.color_y.is_some()
+            || other.color_temperature.is_some()
+        {
+            options.color_preset = None;
+            options.color_x = None;
+            options.color_y = None;
+        }
+        merge_field(&mut options.on_off, &other.on_off);
+        merge_field(&mut options.brightness, &other.brightness);
+        merge_field(&mut options.color_preset, &other.color_preset);
+        merge_field(&mut options.color_x, &other.color_x);
+        merge_field(&mut options.color_y, &other.color_y);
+        merge_field(&mut options.color_temperature, &other.color_temperature);
+    }
+
+    /// The fields set here that differ from `current`, i.e. the smallest update that turns
+    /// `current` into this. The xy color is only sent as a pair.
+    pub fn diff(&self, current: &LightInfo) -> LightInfo {
+        let (desired, current) = (&self.light_options[0], &current.light_options[0]);
+        let (color_x, color_y) = match diff_field(
+            &desired.color_x.zip(desired.color_y),
+            &current.color_x.zip(current.color_y),
+        ) {
+            Some((x, y)) => (Some(x), Some(y)),
+            None => (None, None),
+        };
+
+        LightInfo {
+            light_options: [LightOptions {
+                on_off: diff_field(&desired.on_off, &current.on_off),
+                brightness: diff_field(&desired.brightness, &current.brightness),
+                color_preset: diff_field(&desired.color_preset, &current.color_preset),
+                color_x,
+                color_y,
+                color_temperature: diff_field(
+                    &desired.color_temperature,
+                    &current.color_temperature,
+                ),
+            }],
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
 struct LightOptions {
     #[serde(rename = "5850", skip_serializing_if = "Option::is_none")]
     on_off: Option<u8>,
@@ -95,6 +162,9 @@ struct LightOptions {
     color_x: Option<u16>,
     #[serde(rename = "5710", skip_serializing_if = "Option::is_none")]
     color_y: Option<u16>,
+    /// Mireds, only white spectrum bulbs
+    #[serde(rename = "5711", skip_serializing_if = "Option::is_none")]
+    color_temperature: Option<u16>,
     //   "5712": 10 // transition time (fade time)
 }
 
@@ -177,6 +247,36 @@ impl LightColorPreset {
         }
     }
 
+    /// Kebab-case name, e.g. `warm-white`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CoolWhite => "cool-white",
+            Self::WarmWhite => "warm-white",
+            Self::WarmGlow => "warm-glow",
+            Self::Blue => "blue",
+            Self::LightBlue => "light-blue",
+            Self::SaturatedPurple => "saturated-purple",
+            Self::Lime => "lime",
+            Self::LightPurple => "light-purple",
+            Self::Yellow => "yellow",
+            Self::SaturatedPink => "saturated-pink",
+            Self::DarkPeach => "dark-peach",
+            Self::SaturatedRed => "saturated-red",
+            Self::ColdSky => "cold-sky",
+            Self::Pink => "pink",
+            Self::Peach => "peach",
+            Self::WarmAmber => "warm-amber",
+            Self::LightPink => "light-pink",
+            Self::CoolDaylight => "cool-daylight",
+            Self::Candlelight => "candlelight",
+            Self::Sunrise => "sunrise",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::all().iter().copied().find(|p| p.name() == name)
+    }
+
     pub fn all() -> &'static [LightColorPreset] {
         &[
             Self::CoolWhite,
@@ -202,3 +302,94 @@ impl LightColorPreset {
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_takes_over_set_fields() {
+        let mut light = LightInfo::default().on(false).brightness(10);
+        light.merge(&LightInfo::default().on(true));
+
+        assert_eq!(light, LightInfo::default().on(true).brightness(10));
+    }
+
+    #[test]
+    fn merge_replaces_preset_with_xy() {
+        let mut light = LightInfo::default().color_preset(LightColorPreset::WarmWhite);
+        light.merge(&LightInfo::default().color_xy(100, 200));
+
+        assert_eq!(light, LightInfo::default().color_xy(100, 200));
+    }
+
+    #[test]
+    fn merge_replaces_xy_with_preset() {
+        let mut light = LightInfo::default().color_xy(100, 200);
+        light.merge(&LightInfo::default().color_preset(LightColorPreset::Blue));
+
+        assert_eq!(
+            light,
+            LightInfo::default().color_preset(LightColorPreset::Blue)
+        );
+    }
+
+    #[test]
+    fn merge_replaces_color_with_temperature() {
+        let mut light = LightInfo::default()
+            .brightness(10)
+            .color_preset(LightColorPreset::Blue);
+        light.merge(&LightInfo::default().color_temperature(300));
+
+        assert_eq!(
+            light,
+            LightInfo::default().brightness(10).color_temperature(300)
+        );
+    }
+
+    #[test]
+    fn merge_without_color_keeps_color() {
+        let mut light = LightInfo::default().color_xy(100, 200);
+        light.merge(&LightInfo::default().brightness(10));
+
+        assert_eq!(
+            light,
+            LightInfo::default().color_xy(100, 200).brightness(10)
+        );
+    }
+
+    #[test]
+    fn diff_of_equal_lights_is_empty() {
+        let light = LightInfo::default().on(true).color_xy(100, 200);
+
+        assert!(light.diff(&light).is_empty());
+        assert!(LightInfo::default().is_empty());
+        assert!(!LightInfo::default().on(false).is_empty());
+    }
+
+    #[test]
+    fn diff_leaves_out_unchanged_fields() {
+        let current = LightInfo::default().on(true).brightness(10);
+        let desired = LightInfo::default().on(true).brightness(20);
+
+        assert_eq!(desired.diff(&current), LightInfo::default().brightness(20));
+    }
+
+    #[test]
+    fn diff_sends_xy_as_pair() {
+        let current = LightInfo::default().color_xy(100, 200);
+        let desired = LightInfo::default().color_xy(100, 300);
+
+        assert_eq!(
+            desired.diff(&current),
+            LightInfo::default().color_xy(100, 300)
+        );
+    }
+
+    #[test]
+    fn diff_ignores_fields_not_set_in_desired() {
+        let current = LightInfo::default().on(true).color_xy(100, 200);
+
+        assert!(LightInfo::default().diff(&current).is_empty());
+    }
+}
@@ -0,0 +1,17 @@
+/// Overwrites `field` with `other` if that is set
+pub(crate) fn merge_field<T: Clone>(field: &mut Option<T>, other: &Option<T>) {
+    if other.is_some() {
+        *field = other.clone();
+    }
+}
+
+/// `desired` if it is set and differs from `current`
+pub(crate) fn diff_field<T: Clone + PartialEq>(
+    desired: &Option<T>,
+    current: &Option<T>,
+) -> Option<T> {
+    match desired {
+        Some(value) if current.as_ref() != Some(value) => Some(value.clone()),
+        _ => None,
+    }
+}
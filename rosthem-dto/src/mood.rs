@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// A scene of a group, activated by setting it as the group's mood
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MoodInfo {
+    #[serde(rename = "9001", skip_serializing_if = "Option::is_none")]
+    pub name: Option<Cow<'static, str>>,
+    #[serde(rename = "9003", skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+}
@@ -0,0 +1,47 @@
+use crate::merge::{diff_field, merge_field};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PlugInfo {
+    #[serde(rename = "3312")]
+    plug_options: [PlugOptions; 1],
+}
+
+impl PlugInfo {
+    pub fn on(mut self, on: bool) -> Self {
+        self.plug_options[0].on_off = Some(if on { 1 } else { 0 });
+        self
+    }
+
+    pub fn get_on(&self) -> Option<bool> {
+        self.plug_options[0].on_off.map(|o| o != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plug_options[0] == PlugOptions::default()
+    }
+
+    pub fn merge(&mut self, other: &PlugInfo) {
+        merge_field(
+            &mut self.plug_options[0].on_off,
+            &other.plug_options[0].on_off,
+        );
+    }
+
+    pub fn diff(&self, current: &PlugInfo) -> PlugInfo {
+        PlugInfo {
+            plug_options: [PlugOptions {
+                on_off: diff_field(
+                    &self.plug_options[0].on_off,
+                    &current.plug_options[0].on_off,
+                ),
+            }],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+struct PlugOptions {
+    #[serde(rename = "5850", skip_serializing_if = "Option::is_none")]
+    on_off: Option<u8>,
+}
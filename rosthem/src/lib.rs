@@ -1,8 +1,33 @@
 mod rosthem;
 
+#[cfg(feature = "cbor")]
+pub use rosthem::codec::CborCodec;
+#[cfg(feature = "json")]
+pub use rosthem::codec::JsonCodec;
+#[cfg(feature = "json")]
+pub use rosthem::credential_store::FileCredentialStore;
+#[cfg(feature = "mock-gateway")]
+pub use rosthem::mock_gateway::MockGateway;
 pub use rosthem::{
-    error::CoapError, session_ext::CoapSessionExt, Coap, CoapAddress, CoapContext, CoapLogLevel,
-    CoapMethod, CoapOptList, CoapPduBuilder, CoapSession, CoapToken, CoapUri,
+    codec::PayloadCodec,
+    credential_store::CredentialStore,
+    credentials::{CoapCredentials, CoapPkiCredentials},
+    error::CoapError,
+    inspect::CoapPduInfo,
+    metrics::{self, LatencyHistogram, SessionMetrics},
+    queue::QueueConfig,
+    recording::{Direction, RecordedOption, RecordedPdu, Replay},
+    response::{CoapResponse, CoapResponseCode},
+    server::{CoapReply, CoapRequest, CoapResource},
+    supervised::{ReconnectPolicy, SupervisedSession},
+    Coap, CoapAddress, CoapContentFormat, CoapContext, CoapEvent, CoapLogLevel, CoapMessageType,
+    CoapMethod, CoapNackReason, CoapOptList, CoapPduBuilder, CoapSession, CoapToken, CoapUri,
+    CoapUriScheme, PduInspector,
+};
+#[cfg(feature = "json")]
+pub use rosthem::{
+    gateway_state::{FieldChange, GatewayState, StateEvent, StateKind, StateSubscriber},
+    session_ext::CoapSessionExt,
 };
 
 pub use rosthem_dto;
@@ -0,0 +1,44 @@
+use super::{error::CoapError, CoapContentFormat};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Converts between payload bytes and serde types for a single Content-Format
+pub trait PayloadCodec {
+    const CONTENT_FORMAT: CoapContentFormat;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CoapError>;
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CoapError>;
+}
+
+#[cfg(feature = "json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl PayloadCodec for JsonCodec {
+    const CONTENT_FORMAT: CoapContentFormat = CoapContentFormat::Json;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CoapError> {
+        serde_json::to_vec(value).map_err(|_| CoapError::SerializeError)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CoapError> {
+        serde_json::from_slice(payload).map_err(|_| CoapError::DeserializeError)
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl PayloadCodec for CborCodec {
+    const CONTENT_FORMAT: CoapContentFormat = CoapContentFormat::Cbor;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CoapError> {
+        let mut payload = Vec::new();
+        ciborium::ser::into_writer(value, &mut payload).map_err(|_| CoapError::SerializeError)?;
+        Ok(payload)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CoapError> {
+        ciborium::de::from_reader(payload).map_err(|_| CoapError::DeserializeError)
+    }
+}
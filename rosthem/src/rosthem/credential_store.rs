@@ -0,0 +1,461 @@
+use super::{credentials::CoapCredentials, error::CoapError};
+#[cfg(feature = "json")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "json")]
+use std::collections::BTreeMap;
+#[cfg(feature = "json")]
+use std::fs::{self, File, OpenOptions};
+#[cfg(feature = "json")]
+use std::io::{ErrorKind, Read, Write};
+#[cfg(feature = "json")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "json")]
+use zeroize::Zeroize;
+
+/// Keeps the PSKs handed out by gateways during pairing, keyed by gateway address or serial.
+/// A store installed with [`super::CoapContext::set_credential_store`] is consulted by
+/// [`super::CoapContext::new_session`] and [`super::CoapContext::new_session_from_uri`] when no
+/// credentials are passed.
+pub trait CredentialStore {
+    fn load(&self, gateway: &str) -> Result<Option<CoapCredentials>, CoapError>;
+    fn store(&mut self, gateway: &str, identity: &[u8], key: &[u8]) -> Result<(), CoapError>;
+    fn remove(&mut self, gateway: &str) -> Result<(), CoapError>;
+}
+
+/// A JSON file only readable by its owner, optionally encrypted with a passphrase
+/// (`encrypted-store` feature)
+#[cfg(feature = "json")]
+pub struct FileCredentialStore {
+    path: PathBuf,
+    #[cfg(feature = "encrypted-store")]
+    passphrase: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoreFile {
+    Encrypted {
+        salt: String,
+        nonce: String,
+        ciphertext: String,
+    },
+    Plain {
+        gateways: BTreeMap<String, StoredPsk>,
+    },
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize, Deserialize)]
+struct StoredPsk {
+    identity: String,
+    key: String,
+}
+
+#[cfg(feature = "json")]
+impl Drop for StoredPsk {
+    fn drop(&mut self) {
+        self.identity.zeroize();
+        self.key.zeroize();
+    }
+}
+
+#[cfg(feature = "json")]
+impl FileCredentialStore {
+    /// The file is created on the first `store`
+    pub fn new(path: impl AsRef<Path>) -> FileCredentialStore {
+        FileCredentialStore {
+            path: path.as_ref().to_owned(),
+            #[cfg(feature = "encrypted-store")]
+            passphrase: None,
+        }
+    }
+
+    #[cfg(feature = "encrypted-store")]
+    pub fn with_passphrase(mut self, passphrase: &str) -> FileCredentialStore {
+        self.passphrase = Some(passphrase.as_bytes().to_owned());
+        self
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, StoredPsk>, CoapError> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(_) => return Err(CoapError::CredentialStoreIo),
+        };
+        // Checked on the open file, before anything is read from it
+        check_permissions(&file)?;
+
+        let mut contents = Vec::new();
+        if file.read_to_end(&mut contents).is_err() {
+            contents.zeroize();
+            return Err(CoapError::CredentialStoreIo);
+        }
+
+        let file = serde_json::from_slice(&contents).map_err(|_| CoapError::DeserializeError);
+        contents.zeroize();
+
+        match file? {
+            StoreFile::Plain { gateways } => Ok(gateways),
+            StoreFile::Encrypted {
+                salt,
+                nonce,
+                ciphertext,
+            } => {
+                let mut plaintext = self.decrypt(&salt, &nonce, &ciphertext)?;
+                let gateways =
+                    serde_json::from_slice(&plaintext).map_err(|_| CoapError::DeserializeError);
+                plaintext.zeroize();
+
+                gateways
+            }
+        }
+    }
+
+    fn write(&self, gateways: BTreeMap<String, StoredPsk>) -> Result<(), CoapError> {
+        let file = self.encrypt(gateways)?;
+        let mut contents =
+            serde_json::to_vec_pretty(&file).map_err(|_| CoapError::SerializeError)?;
+
+        // Written next to the store and renamed over it, so a crash never leaves half a file
+        let tmp_path = self.path.with_extension("tmp");
+        let result = create_private(&tmp_path)
+            .and_then(|mut tmp| tmp.write_all(&contents).and_then(|_| tmp.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|_| CoapError::CredentialStoreIo);
+        contents.zeroize();
+
+        result
+    }
+
+    #[cfg(feature = "encrypted-store")]
+    fn encrypt(&self, gateways: BTreeMap<String, StoredPsk>) -> Result<StoreFile, CoapError> {
+        use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+        use chacha20poly1305::ChaCha20Poly1305;
+
+        let passphrase = match &self.passphrase {
+            Some(passphrase) => passphrase,
+            None => return Ok(StoreFile::Plain { gateways }),
+        };
+
+        let mut plaintext = serde_json::to_vec(&gateways).map_err(|_| CoapError::SerializeError)?;
+
+        let mut salt = [0u8; 16];
+        getrandom::getrandom(&mut salt).map_err(|_| CoapError::CredentialStoreIo)?;
+        let mut key = derive_key(passphrase, &salt)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = ChaCha20Poly1305::new(&key.into())
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| CoapError::SerializeError);
+        plaintext.zeroize();
+        key.zeroize();
+
+        Ok(StoreFile::Encrypted {
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext?),
+        })
+    }
+
+    #[cfg(not(feature = "encrypted-store"))]
+    fn encrypt(&self, gateways: BTreeMap<String, StoredPsk>) -> Result<StoreFile, CoapError> {
+        Ok(StoreFile::Plain { gateways })
+    }
+
+    #[cfg(feature = "encrypted-store")]
+    fn decrypt(&self, salt: &str, nonce: &str, ciphertext: &str) -> Result<Vec<u8>, CoapError> {
+        use chacha20poly1305::aead::{Aead, KeyInit};
+        use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+        let passphrase = self.passphrase.as_ref().ok_or(CoapError::WrongPassphrase)?;
+        let salt = hex::decode(salt).map_err(|_| CoapError::DeserializeError)?;
+        let nonce = hex::decode(nonce).map_err(|_| CoapError::DeserializeError)?;
+        let ciphertext = hex::decode(ciphertext).map_err(|_| CoapError::DeserializeError)?;
+        if nonce.len() != 12 {
+            return Err(CoapError::DeserializeError);
+        }
+
+        let mut key = derive_key(passphrase, &salt)?;
+        let plaintext = ChaCha20Poly1305::new(&key.into())
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| CoapError::WrongPassphrase);
+        key.zeroize();
+
+        plaintext
+    }
+
+    #[cfg(not(feature = "encrypted-store"))]
+    fn decrypt(&self, _salt: &str, _nonce: &str, _ciphertext: &str) -> Result<Vec<u8>, CoapError> {
+        Err(CoapError::WrongPassphrase)
+    }
+}
+
+#[cfg(feature = "encrypted-store")]
+impl Drop for FileCredentialStore {
+    fn drop(&mut self) {
+        if let Some(passphrase) = &mut self.passphrase {
+            passphrase.zeroize();
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl CredentialStore for FileCredentialStore {
+    fn load(&self, gateway: &str) -> Result<Option<CoapCredentials>, CoapError> {
+        match self.read()?.get(gateway) {
+            Some(psk) => CoapCredentials::psk_hex(&psk.identity, &psk.key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&mut self, gateway: &str, identity: &[u8], key: &[u8]) -> Result<(), CoapError> {
+        let mut gateways = self.read()?;
+        gateways.insert(
+            gateway.to_owned(),
+            StoredPsk {
+                identity: hex::encode(identity),
+                key: hex::encode(key),
+            },
+        );
+
+        self.write(gateways)
+    }
+
+    fn remove(&mut self, gateway: &str) -> Result<(), CoapError> {
+        let mut gateways = self.read()?;
+        if gateways.remove(gateway).is_some() {
+            self.write(gateways)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "encrypted-store")]
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<[u8; 32], CoapError> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|_| CoapError::WrongPassphrase)?;
+
+    Ok(key)
+}
+
+/// Refuses stores that other users could read
+#[cfg(all(unix, feature = "json"))]
+fn check_permissions(file: &File) -> Result<(), CoapError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file
+        .metadata()
+        .map_err(|_| CoapError::CredentialStoreIo)?
+        .permissions()
+        .mode();
+
+    if mode & 0o077 != 0 {
+        Err(CoapError::InsecureCredentialStore)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(all(not(unix), feature = "json"))]
+fn check_permissions(_file: &File) -> Result<(), CoapError> {
+    Ok(())
+}
+
+/// Creates a file only its owner can access. A stale file left at `path` (e.g. by a crash) is
+/// removed first rather than reused, since it may have wider permissions or be a symlink.
+#[cfg(feature = "json")]
+fn create_private(path: &Path) -> std::io::Result<fs::File> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+    }
+
+    options.open(path)
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    /// A fresh directory per test, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "rosthem-credential-store-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn join(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    type Psk = (Vec<u8>, Vec<u8>);
+
+    fn psk(credentials: Option<CoapCredentials>) -> Option<Psk> {
+        match credentials? {
+            CoapCredentials::Psk { identity, key } => Some((identity, key)),
+            _ => None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_mode(path: &Path, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn stores_and_loads_psks() {
+        let dir = TempDir::new("round-trip");
+        let mut store = FileCredentialStore::new(dir.join("store.json"));
+
+        assert!(store.load("192.168.0.10").unwrap().is_none());
+        store.store("192.168.0.10", b"rosthem", b"secret").unwrap();
+        store.store("gw-b072bf", b"other", b"key").unwrap();
+
+        let reopened = FileCredentialStore::new(dir.join("store.json"));
+        assert_eq!(
+            psk(reopened.load("192.168.0.10").unwrap()),
+            Some((b"rosthem".to_vec(), b"secret".to_vec()))
+        );
+        assert_eq!(
+            psk(reopened.load("gw-b072bf").unwrap()),
+            Some((b"other".to_vec(), b"key".to_vec()))
+        );
+
+        store.remove("192.168.0.10").unwrap();
+        assert!(reopened.load("192.168.0.10").unwrap().is_none());
+        assert!(!dir.join("store.tmp").exists());
+    }
+
+    #[test]
+    fn reads_plain_files_and_detects_encrypted_ones() {
+        let cases: [(&str, Result<Option<Psk>, CoapError>); 4] = [
+            (
+                r#"{"gateways": {"gw": {"identity": "6964", "key": "6b6579"}}}"#,
+                Ok(Some((b"id".to_vec(), b"key".to_vec()))),
+            ),
+            (r#"{"gateways": {}}"#, Ok(None)),
+            // Without the passphrase (or the encrypted-store feature) it can't be read
+            (
+                r#"{"salt": "00", "nonce": "00", "ciphertext": "00"}"#,
+                Err(CoapError::WrongPassphrase),
+            ),
+            (r#"{"something": "else"}"#, Err(CoapError::DeserializeError)),
+        ];
+
+        for (contents, expected) in cases {
+            let dir = TempDir::new("detect");
+            let path = dir.join("store.json");
+            fs::write(&path, contents).unwrap();
+            #[cfg(unix)]
+            set_mode(&path, 0o600);
+
+            let loaded = FileCredentialStore::new(&path).load("gw").map(psk);
+            assert_eq!(
+                format!("{:?}", loaded),
+                format!("{:?}", expected),
+                "{}",
+                contents
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_files_other_users_can_access() {
+        let dir = TempDir::new("permissions");
+        let path = dir.join("store.json");
+        let mut store = FileCredentialStore::new(&path);
+        store.store("gw", b"id", b"key").unwrap();
+
+        for (mode, readable) in [(0o600, true), (0o400, true), (0o640, false), (0o604, false)] {
+            set_mode(&path, mode);
+            assert_eq!(store.load("gw").is_ok(), readable, "{:o}", mode);
+        }
+        assert!(matches!(
+            store.load("gw"),
+            Err(CoapError::InsecureCredentialStore)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaces_stale_temp_files_instead_of_reusing_them() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("stale-tmp");
+        let path = dir.join("store.json");
+        let mut store = FileCredentialStore::new(&path);
+
+        // A leftover temp file readable by everyone
+        fs::write(dir.join("store.tmp"), "leftover").unwrap();
+        set_mode(&dir.join("store.tmp"), 0o644);
+        store.store("gw", b"id", b"key").unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // A planted symlink isn't followed
+        let target = dir.join("target");
+        fs::write(&target, "untouched").unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("store.tmp")).unwrap();
+        store.store("gw", b"id", b"other key").unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+        assert_eq!(
+            psk(store.load("gw").unwrap()),
+            Some((b"id".to_vec(), b"other key".to_vec()))
+        );
+    }
+
+    #[cfg(feature = "encrypted-store")]
+    #[test]
+    fn encrypted_stores_need_the_passphrase() {
+        let dir = TempDir::new("encrypted");
+        let path = dir.join("store.json");
+        let mut store = FileCredentialStore::new(&path).with_passphrase("correct horse");
+        store.store("gw", b"id", b"secret key").unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("ciphertext"));
+        assert!(!contents.contains(&hex::encode("secret key")));
+
+        assert_eq!(
+            psk(store.load("gw").unwrap()),
+            Some((b"id".to_vec(), b"secret key".to_vec()))
+        );
+        for other in [
+            FileCredentialStore::new(&path).with_passphrase("wrong"),
+            FileCredentialStore::new(&path),
+        ] {
+            assert!(matches!(other.load("gw"), Err(CoapError::WrongPassphrase)));
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use super::{error::CoapError, ffi::*, CoapUri};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::ptr;
+use zeroize::Zeroize;
+
+/// Credentials for the secure schemes (`coaps://` and `coaps+tcp://`)
+pub enum CoapCredentials {
+    /// Identity and key are arbitrary bytes
+    Psk {
+        identity: Vec<u8>,
+        key: Vec<u8>,
+    },
+    Pki(CoapPkiCredentials),
+    /// Raw public key (RFC 7250), all keys DER encoded EC keys
+    Rpk {
+        public_key: Vec<u8>,
+        private_key: Vec<u8>,
+        /// The public key the server has to present. Without one, any server is accepted, so
+        /// the session is only as good as the network it is used on.
+        server_key: Option<Vec<u8>>,
+    },
+}
+
+impl CoapCredentials {
+    pub fn psk(identity: impl Into<Vec<u8>>, key: impl Into<Vec<u8>>) -> CoapCredentials {
+        CoapCredentials::Psk {
+            identity: identity.into(),
+            key: key.into(),
+        }
+    }
+
+    /// PSK credentials given as hex strings, as some gateways print them
+    pub fn psk_hex(identity: &str, key: &str) -> Result<CoapCredentials, CoapError> {
+        let identity = hex::decode(identity).map_err(|_| CoapError::InvalidCredentials)?;
+        let key = hex::decode(key).map_err(|_| CoapError::InvalidCredentials)?;
+
+        Ok(CoapCredentials::Psk { identity, key })
+    }
+
+    /// Raw public key credentials that only accept a server presenting `server_key`
+    pub fn rpk(
+        public_key: impl Into<Vec<u8>>,
+        private_key: impl Into<Vec<u8>>,
+        server_key: impl Into<Vec<u8>>,
+    ) -> CoapCredentials {
+        CoapCredentials::Rpk {
+            public_key: public_key.into(),
+            private_key: private_key.into(),
+            server_key: Some(server_key.into()),
+        }
+    }
+
+    /// Raw public key credentials that accept whatever key the server presents
+    pub fn rpk_without_server_verification(
+        public_key: impl Into<Vec<u8>>,
+        private_key: impl Into<Vec<u8>>,
+    ) -> CoapCredentials {
+        CoapCredentials::Rpk {
+            public_key: public_key.into(),
+            private_key: private_key.into(),
+            server_key: None,
+        }
+    }
+}
+
+/// X.509 client certificate and key from PEM files. The server certificate is verified against
+/// the CA of [`CoapPkiCredentials::with_ca_file`], which is required unless verification is
+/// turned off with [`CoapPkiCredentials::without_server_verification`].
+pub struct CoapPkiCredentials {
+    ca_file: Option<PathBuf>,
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    verify_server: bool,
+    verify_hostname: bool,
+}
+
+impl CoapPkiCredentials {
+    pub fn new(cert_file: impl AsRef<Path>, key_file: impl AsRef<Path>) -> CoapPkiCredentials {
+        CoapPkiCredentials {
+            ca_file: None,
+            cert_file: cert_file.as_ref().to_owned(),
+            key_file: key_file.as_ref().to_owned(),
+            verify_server: true,
+            verify_hostname: true,
+        }
+    }
+
+    /// The server certificate has to be signed by this CA
+    pub fn with_ca_file(mut self, ca_file: impl AsRef<Path>) -> CoapPkiCredentials {
+        self.ca_file = Some(ca_file.as_ref().to_owned());
+        self
+    }
+
+    /// Accepts any server certificate, including ones for another host. Only meant for servers
+    /// with throwaway certificates, e.g. in tests.
+    pub fn without_server_verification(mut self) -> CoapPkiCredentials {
+        self.verify_server = false;
+        self.verify_hostname = false;
+        self
+    }
+
+    /// Whether the server certificate has to name the host of the session URI (on by default)
+    pub fn with_hostname_verification(mut self, verify_hostname: bool) -> CoapPkiCredentials {
+        self.verify_hostname = verify_hostname;
+        self
+    }
+}
+
+/// Native setup of a secure session, which libcoap keeps pointers into
+pub(crate) enum DtlsSetup {
+    Psk(Pin<Box<CoapDtlsPsk>>),
+    Pki(Pin<Box<CoapDtlsPki>>),
+}
+
+impl DtlsSetup {
+    pub(crate) fn new(uri: CoapUri, credentials: CoapCredentials) -> Result<DtlsSetup, CoapError> {
+        match credentials {
+            CoapCredentials::Psk { identity, key } => {
+                Ok(DtlsSetup::Psk(CoapDtlsPsk::new(&uri, identity, key)?))
+            }
+            CoapCredentials::Pki(pki) => {
+                if pki.verify_server && pki.ca_file.is_none() {
+                    return Err(CoapError::MissingCaFile);
+                }
+
+                Ok(DtlsSetup::Pki(CoapDtlsPki::new(
+                    &uri,
+                    PkiKey::Pem {
+                        ca_file: pki.ca_file.as_deref().map(path_to_cstring).transpose()?,
+                        cert_file: path_to_cstring(&pki.cert_file)?,
+                        key_file: path_to_cstring(&pki.key_file)?,
+                        verify: pki.verify_server,
+                    },
+                    if pki.verify_hostname {
+                        ServerCheck::Hostname
+                    } else {
+                        ServerCheck::None
+                    },
+                )?))
+            }
+            CoapCredentials::Rpk {
+                public_key,
+                private_key,
+                server_key,
+            } => Ok(DtlsSetup::Pki(CoapDtlsPki::new(
+                &uri,
+                PkiKey::Rpk {
+                    public_key,
+                    private_key,
+                },
+                server_key.map_or(ServerCheck::None, ServerCheck::PublicKey),
+            )?)),
+        }
+    }
+}
+
+pub(crate) struct CoapDtlsPsk {
+    sni: CString,
+    identity: Vec<u8>,
+    key: Vec<u8>,
+    pub(crate) native: coap_dtls_cpsk_t,
+}
+
+impl CoapDtlsPsk {
+    pub(crate) fn new(
+        uri: &CoapUri,
+        identity: Vec<u8>,
+        key: Vec<u8>,
+    ) -> Result<Pin<Box<CoapDtlsPsk>>, CoapError> {
+        let sni = CString::new(uri.host()?).map_err(|_| CoapError::InvalidUri)?;
+
+        unsafe {
+            let mut native: coap_dtls_cpsk_t = std::mem::zeroed();
+            native.version = COAP_DTLS_CPSK_SETUP_VERSION as u8;
+            native.validate_ih_call_back = None;
+
+            let mut psk = Box::pin(CoapDtlsPsk {
+                sni,
+                identity,
+                key,
+                native,
+            });
+            let psk_mut = Pin::get_unchecked_mut(psk.as_mut());
+
+            psk_mut.native.psk_info.identity.s = psk_mut.identity.as_ptr();
+            psk_mut.native.psk_info.identity.length = psk_mut.identity.len() as c_ulong;
+            psk_mut.native.psk_info.key.s = psk_mut.key.as_ptr();
+            psk_mut.native.psk_info.key.length = psk_mut.key.len() as c_ulong;
+            psk_mut.native.ih_call_back_arg = &mut psk_mut.native.psk_info as *mut _ as *mut _;
+            psk_mut.native.client_sni = psk_mut.sni.as_ptr() as *mut c_char;
+
+            Ok(psk)
+        }
+    }
+}
+
+impl Drop for CoapDtlsPsk {
+    fn drop(&mut self) {
+        self.identity.zeroize();
+        self.key.zeroize();
+    }
+}
+
+/// The PSK a server endpoint accepts, whatever identity the client presents
+pub(crate) struct CoapDtlsServerPsk {
+    hint: Vec<u8>,
+    key: Vec<u8>,
+    pub(crate) native: coap_dtls_spsk_t,
+}
+
+impl CoapDtlsServerPsk {
+    pub(crate) fn new(hint: Vec<u8>, key: Vec<u8>) -> Pin<Box<CoapDtlsServerPsk>> {
+        unsafe {
+            let mut native: coap_dtls_spsk_t = std::mem::zeroed();
+            native.version = COAP_DTLS_SPSK_SETUP_VERSION as u8;
+
+            let mut psk = Box::pin(CoapDtlsServerPsk { hint, key, native });
+            let psk_mut = Pin::get_unchecked_mut(psk.as_mut());
+
+            psk_mut.native.psk_info.hint.s = psk_mut.hint.as_ptr();
+            psk_mut.native.psk_info.hint.length = psk_mut.hint.len() as c_ulong;
+            psk_mut.native.psk_info.key.s = psk_mut.key.as_ptr();
+            psk_mut.native.psk_info.key.length = psk_mut.key.len() as c_ulong;
+
+            psk
+        }
+    }
+}
+
+impl Drop for CoapDtlsServerPsk {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+enum PkiKey {
+    Pem {
+        ca_file: Option<CString>,
+        cert_file: CString,
+        key_file: CString,
+        verify: bool,
+    },
+    Rpk {
+        public_key: Vec<u8>,
+        private_key: Vec<u8>,
+    },
+}
+
+/// What the server certificate or key is checked against, after libcoap's own checks
+enum ServerCheck {
+    None,
+    /// The CN (or SubjectAltName) has to be the host of the session URI
+    Hostname,
+    /// The raw public key has to be exactly this one
+    PublicKey(Vec<u8>),
+}
+
+pub(crate) struct CoapDtlsPki {
+    host: CString,
+    key: PkiKey,
+    check: ServerCheck,
+    pub(crate) native: coap_dtls_pki_t,
+}
+
+impl CoapDtlsPki {
+    fn new(uri: &CoapUri, key: PkiKey, check: ServerCheck) -> Result<Pin<Box<Self>>, CoapError> {
+        let host = CString::new(uri.host()?).map_err(|_| CoapError::InvalidUri)?;
+
+        unsafe {
+            let mut native: coap_dtls_pki_t = std::mem::zeroed();
+            native.version = COAP_DTLS_PKI_SETUP_VERSION as u8;
+
+            let mut pki = Box::pin(CoapDtlsPki {
+                host,
+                key,
+                check,
+                native,
+            });
+            let pki_mut = Pin::get_unchecked_mut(pki.as_mut());
+
+            match &pki_mut.key {
+                PkiKey::Pem {
+                    ca_file,
+                    cert_file,
+                    key_file,
+                    verify,
+                } => {
+                    let verify = *verify as u8;
+                    pki_mut.native.verify_peer_cert = verify;
+                    pki_mut.native.check_common_ca = verify;
+                    pki_mut.native.cert_chain_validation = verify;
+                    pki_mut.native.cert_chain_verify_depth = 3;
+
+                    pki_mut.native.pki_key.key_type = coap_pki_key_t_COAP_PKI_KEY_PEM;
+                    pki_mut.native.pki_key.key.pem = coap_pki_key_pem_t {
+                        ca_file: ca_file.as_ref().map_or(ptr::null(), |f| f.as_ptr()),
+                        public_cert: cert_file.as_ptr(),
+                        private_key: key_file.as_ptr(),
+                    };
+                }
+                PkiKey::Rpk {
+                    public_key,
+                    private_key,
+                } => {
+                    pki_mut.native.is_rpk_not_cert = 1;
+
+                    pki_mut.native.pki_key.key_type = coap_pki_key_t_COAP_PKI_KEY_ASN1;
+                    pki_mut.native.pki_key.key.asn1 = coap_pki_key_asn1_t {
+                        ca_cert: ptr::null(),
+                        public_cert: public_key.as_ptr(),
+                        private_key: private_key.as_ptr(),
+                        ca_cert_len: 0,
+                        public_cert_len: public_key.len() as c_ulong,
+                        private_key_len: private_key.len() as c_ulong,
+                        private_key_type: coap_asn1_privatekey_type_t_COAP_ASN1_PKEY_EC,
+                    };
+                }
+            }
+
+            if !matches!(pki_mut.check, ServerCheck::None) {
+                pki_mut.native.validate_cn_call_back = Some(validate_server);
+                pki_mut.native.cn_call_back_arg = pki_mut as *mut CoapDtlsPki as *mut c_void;
+            }
+            pki_mut.native.client_sni = pki_mut.host.as_ptr() as *mut c_char;
+
+            Ok(pki)
+        }
+    }
+}
+
+impl Drop for CoapDtlsPki {
+    fn drop(&mut self) {
+        if let PkiKey::Rpk { private_key, .. } = &mut self.key {
+            private_key.zeroize();
+        }
+    }
+}
+
+fn path_to_cstring(path: &Path) -> Result<CString, CoapError> {
+    path.to_str()
+        .and_then(|path| CString::new(path).ok())
+        .ok_or(CoapError::InvalidCredentials)
+}
+
+/// Accepts the server certificate only if it passes the [`ServerCheck`] of its session
+unsafe extern "C" fn validate_server(
+    cn: *const c_char,
+    asn1_public_cert: *const u8,
+    asn1_length: c_ulong,
+    _session: *mut coap_session_t,
+    depth: c_uint,
+    _validated: c_int,
+    arg: *mut c_void,
+) -> c_int {
+    // Only the server's own certificate has to match, not the CAs above it
+    if depth > 0 {
+        return 1;
+    }
+
+    let pki = &*(arg as *const CoapDtlsPki);
+    let accepted = match &pki.check {
+        ServerCheck::None => true,
+        // libcoap passes no name for certificates without SAN and CN
+        ServerCheck::Hostname => {
+            !cn.is_null()
+                && CStr::from_ptr(cn)
+                    .to_bytes()
+                    .eq_ignore_ascii_case(pki.host.to_bytes())
+        }
+        ServerCheck::PublicKey(key) => {
+            !asn1_public_cert.is_null()
+                && std::slice::from_raw_parts(asn1_public_cert, asn1_length as usize) == &key[..]
+        }
+    };
+
+    accepted as c_int
+}
@@ -1,16 +1,119 @@
+use super::{response::CoapResponseCode, CoapNackReason};
+use std::fmt;
+
 #[derive(Debug)]
 pub enum CoapError {
     AlreadyInitialized,
     FailedToCreateContext,
     FailedToCreateSession,
-    IdentityNotAscii,
-    KeyNotAscii,
+    FailedToCreateEndpoint,
+    FailedToCreateResource,
     InvalidUri,
+    ProtocolNotSupported,
+    MissingCredentials,
+    InvalidCredentials,
+    MissingCaFile,
+    CredentialStoreIo,
+    InsecureCredentialStore,
+    WrongPassphrase,
+    RecordingIo,
     FailedToCreatePdu,
     FailedToSend,
     IoError,
     UriTooLong,
+    InvalidOptionValue,
+    FailedToAddOption,
     SerializeError,
+    DeserializeError,
+    UnsupportedContentFormat,
     AlreadyHasPayload,
     PayloadEncodingError,
+    Timeout,
+    SessionClosed,
+    ReconnectFailed,
+    QueueFull,
+    InvalidQueueConfig,
+    Nack(CoapNackReason),
+    BadRequest,
+    Unauthorized,
+    BadOption,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    PreconditionFailed,
+    RequestEntityTooLarge,
+    ClientError { code: CoapResponseCode },
+    ServerError { code: CoapResponseCode },
+    UnexpectedResponseCode { code: CoapResponseCode },
 }
+
+impl fmt::Display for CoapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoapError::AlreadyInitialized => write!(f, "CoAP is already initialized"),
+            CoapError::FailedToCreateContext => write!(f, "failed to create the CoAP context"),
+            CoapError::FailedToCreateSession => write!(f, "failed to create the CoAP session"),
+            CoapError::FailedToCreateEndpoint => write!(f, "failed to create the CoAP endpoint"),
+            CoapError::FailedToCreateResource => write!(f, "failed to create the CoAP resource"),
+            CoapError::InvalidUri => write!(f, "invalid URI"),
+            CoapError::ProtocolNotSupported => write!(f, "protocol not supported"),
+            CoapError::MissingCredentials => write!(f, "no credentials for the gateway"),
+            CoapError::InvalidCredentials => write!(f, "invalid credentials"),
+            CoapError::MissingCaFile => write!(f, "verifying the server requires a CA file"),
+            CoapError::CredentialStoreIo => {
+                write!(f, "reading or writing the credential store failed")
+            }
+            CoapError::InsecureCredentialStore => {
+                write!(f, "the credential store is accessible by other users")
+            }
+            CoapError::WrongPassphrase => {
+                write!(f, "wrong or missing passphrase for the credential store")
+            }
+            CoapError::RecordingIo => write!(f, "reading or writing the recording failed"),
+            CoapError::FailedToCreatePdu => write!(f, "failed to create the PDU"),
+            CoapError::FailedToSend => write!(f, "failed to send the request"),
+            CoapError::IoError => write!(f, "processing CoAP IO failed"),
+            CoapError::UriTooLong => write!(f, "URI too long"),
+            CoapError::InvalidOptionValue => write!(f, "invalid option value"),
+            CoapError::FailedToAddOption => write!(f, "failed to add an option"),
+            CoapError::SerializeError => write!(f, "failed to serialize the payload"),
+            CoapError::DeserializeError => write!(f, "failed to deserialize the payload"),
+            CoapError::UnsupportedContentFormat => write!(f, "unsupported Content-Format"),
+            CoapError::AlreadyHasPayload => write!(f, "the PDU already has a payload"),
+            CoapError::PayloadEncodingError => write!(f, "failed to encode the payload"),
+            CoapError::Timeout => write!(f, "timed out waiting for the response"),
+            CoapError::SessionClosed => write!(f, "the session was closed"),
+            CoapError::ReconnectFailed => write!(f, "reconnecting to the gateway failed"),
+            CoapError::QueueFull => write!(f, "the request queue is full"),
+            CoapError::InvalidQueueConfig => write!(f, "invalid request queue configuration"),
+            CoapError::Nack(reason) => {
+                let reason = match reason {
+                    CoapNackReason::TooManyRetries => "too many retransmissions",
+                    CoapNackReason::NotDeliverable => "not deliverable",
+                    CoapNackReason::Reset => "reset by the peer",
+                    CoapNackReason::TlsFailed => "DTLS failed",
+                    CoapNackReason::IcmpIssue => "ICMP error",
+                    CoapNackReason::Unknown => "unknown reason",
+                };
+                write!(f, "the request was not acknowledged: {}", reason)
+            }
+            CoapError::BadRequest => write!(f, "bad request (4.00)"),
+            CoapError::Unauthorized => write!(f, "unauthorized (4.01)"),
+            CoapError::BadOption => write!(f, "bad option (4.02)"),
+            CoapError::Forbidden => write!(f, "forbidden (4.03)"),
+            CoapError::NotFound => write!(f, "not found (4.04)"),
+            CoapError::MethodNotAllowed => write!(f, "method not allowed (4.05)"),
+            CoapError::NotAcceptable => write!(f, "not acceptable (4.06)"),
+            CoapError::PreconditionFailed => write!(f, "precondition failed (4.12)"),
+            CoapError::RequestEntityTooLarge => write!(f, "request entity too large (4.13)"),
+            CoapError::ClientError { code } => write!(f, "client error ({})", code),
+            CoapError::ServerError { code } => write!(f, "server error ({})", code),
+            CoapError::UnexpectedResponseCode { code } => {
+                write!(f, "unexpected response code {}", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CoapError {}
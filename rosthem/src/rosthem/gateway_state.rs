@@ -0,0 +1,419 @@
+use super::{
+    error::CoapError,
+    response::{CoapResponse, CoapResponseCode},
+    session_ext::{CoapSessionExt, IKEA_GATEWAY_PATH_SEGMENT, IKEA_GROUPS_PATH_SEGMENT},
+    supervised::SupervisedSession,
+    CoapMethod, CoapOptList, CoapPduBuilder, CoapToken,
+};
+use rosthem_dto::{field_name, DeviceInfo, GroupInfo};
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StateKind {
+    Device,
+    Group,
+}
+
+/// A change to a [`GatewayState`]
+#[derive(Clone, PartialEq, Debug)]
+pub enum StateEvent {
+    Added(StateKind, usize),
+    Removed(StateKind, usize),
+    Changed {
+        kind: StateKind,
+        id: usize,
+        changes: Vec<FieldChange>,
+    },
+}
+
+/// A single value that differs between the old and the new state of a device or group
+#[derive(Clone, PartialEq, Debug)]
+pub struct FieldChange {
+    /// Field names separated by dots, with array indices in brackets, e.g.
+    /// `light_options[0].brightness`. Keys without a known name are kept as they are.
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+pub type StateSubscriber = Box<dyn FnMut(&StateEvent)>;
+
+/// A cache of every device and group of a gateway. It is filled by [`GatewayState::load`], kept
+/// fresh by observing everything it knows about and tells subscribers what changed.
+///
+/// Feed it the notifications of its session with [`GatewayState::run`] or, when the session
+/// serves other requests as well, [`GatewayState::handle_response`].
+#[derive(Default)]
+pub struct GatewayState {
+    devices: BTreeMap<usize, DeviceInfo>,
+    groups: BTreeMap<usize, GroupInfo>,
+    observations: HashMap<CoapToken, (StateKind, usize)>,
+    subscribers: Vec<StateSubscriber>,
+}
+
+impl GatewayState {
+    /// Fetches and observes every device and group of the gateway
+    pub fn load(session: &mut SupervisedSession) -> Result<GatewayState, CoapError> {
+        let mut state = GatewayState::default();
+        state.refresh(session)?;
+
+        Ok(state)
+    }
+
+    /// Lists the devices and groups again, to pick up ones that were added or removed since.
+    /// The gateway doesn't notify about those.
+    pub fn refresh(&mut self, session: &mut SupervisedSession) -> Result<(), CoapError> {
+        let devices = session.list_devices()?;
+        let groups = session.list_groups()?;
+
+        for id in self.devices.keys().copied().collect::<Vec<_>>() {
+            if !devices.contains(&id) {
+                self.remove(session, StateKind::Device, id)?;
+            }
+        }
+        for id in self.groups.keys().copied().collect::<Vec<_>>() {
+            if !groups.contains(&id) {
+                self.remove(session, StateKind::Group, id)?;
+            }
+        }
+
+        let new_devices: Vec<usize> = devices
+            .into_iter()
+            .filter(|id| !self.devices.contains_key(id))
+            .collect();
+        for id in new_devices {
+            let device = session.request_status(&id.to_string())?;
+            self.devices.insert(id, device);
+            self.observe(session, StateKind::Device, id)?;
+            self.emit(StateEvent::Added(StateKind::Device, id));
+        }
+
+        let new_groups: Vec<usize> = groups
+            .into_iter()
+            .filter(|id| !self.groups.contains_key(id))
+            .collect();
+        for id in new_groups {
+            let group = session.request_group(&id.to_string())?;
+            self.groups.insert(id, group);
+            self.observe(session, StateKind::Group, id)?;
+            self.emit(StateEvent::Added(StateKind::Group, id));
+        }
+
+        Ok(())
+    }
+
+    /// Calls `subscriber` with every change from now on
+    pub fn subscribe(&mut self, subscriber: StateSubscriber) {
+        self.subscribers.push(subscriber);
+    }
+
+    /// Runs `session` for `timeout` and applies the notifications that came in
+    pub fn run(
+        &mut self,
+        session: &mut SupervisedSession,
+        timeout: Duration,
+    ) -> Result<(), CoapError> {
+        let notifications = Rc::new(RefCell::new(Vec::new()));
+        let queue = notifications.clone();
+        session.run(
+            timeout,
+            Some(Box::new(move |response: CoapResponse| {
+                queue.borrow_mut().push(response)
+            })),
+        )?;
+
+        for response in notifications.borrow_mut().drain(..) {
+            self.handle_response(session, &response)?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies a notification of one of the observations. Returns whether the response
+    /// belonged to one.
+    ///
+    /// A 4.04 means the device or group is gone, its observation is cancelled on `session`.
+    /// Other errors, e.g. a 5.03 while the gateway is busy, keep the cached state.
+    pub fn handle_response(
+        &mut self,
+        session: &mut SupervisedSession,
+        response: &CoapResponse,
+    ) -> Result<bool, CoapError> {
+        let (kind, id) = match self.observations.get(&response.token()) {
+            Some(observation) => *observation,
+            None => return Ok(false),
+        };
+
+        if response.code() == CoapResponseCode::NOT_FOUND {
+            self.remove(session, kind, id)?;
+            return Ok(true);
+        }
+        if !response.code().is_success() {
+            return Ok(true);
+        }
+
+        let changes = match kind {
+            StateKind::Device => match response.decode::<DeviceInfo>() {
+                Ok(device) => self
+                    .devices
+                    .insert(id, device.clone())
+                    .map(|old| diff(&old, &device)),
+                Err(_) => return Ok(true),
+            },
+            StateKind::Group => match response.decode::<GroupInfo>() {
+                Ok(group) => self
+                    .groups
+                    .insert(id, group.clone())
+                    .map(|old| diff(&old, &group)),
+                Err(_) => return Ok(true),
+            },
+        };
+
+        match changes {
+            Some(changes) if changes.is_empty() => {}
+            Some(changes) => self.emit(StateEvent::Changed { kind, id, changes }),
+            None => self.emit(StateEvent::Added(kind, id)),
+        }
+
+        Ok(true)
+    }
+
+    pub fn device(&self, id: usize) -> Option<&DeviceInfo> {
+        self.devices.get(&id)
+    }
+
+    pub fn device_by_label(&self, label: &str) -> Option<(usize, &DeviceInfo)> {
+        self.devices
+            .iter()
+            .find(|(_, device)| device.label.as_deref() == Some(label))
+            .map(|(id, device)| (*id, device))
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (usize, &DeviceInfo)> {
+        self.devices.iter().map(|(id, device)| (*id, device))
+    }
+
+    pub fn group(&self, id: usize) -> Option<&GroupInfo> {
+        self.groups.get(&id)
+    }
+
+    pub fn group_by_name(&self, name: &str) -> Option<(usize, &GroupInfo)> {
+        self.groups
+            .iter()
+            .find(|(_, group)| group.name.as_deref() == Some(name))
+            .map(|(id, group)| (*id, group))
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (usize, &GroupInfo)> {
+        self.groups.iter().map(|(id, group)| (*id, group))
+    }
+
+    fn observe(
+        &mut self,
+        session: &mut SupervisedSession,
+        kind: StateKind,
+        id: usize,
+    ) -> Result<(), CoapError> {
+        let optlist = CoapOptList::new();
+        optlist.add_path_segment(match kind {
+            StateKind::Device => IKEA_GATEWAY_PATH_SEGMENT,
+            StateKind::Group => IKEA_GROUPS_PATH_SEGMENT,
+        })?;
+        optlist.add_path_segment(&id.to_string())?;
+
+        let token = session.observe(CoapPduBuilder::new(CoapMethod::Get).with_optlist(&optlist))?;
+        self.observations.insert(token, (kind, id));
+
+        Ok(())
+    }
+
+    fn remove(
+        &mut self,
+        session: &mut SupervisedSession,
+        kind: StateKind,
+        id: usize,
+    ) -> Result<(), CoapError> {
+        let token = self
+            .observations
+            .iter()
+            .find(|(_, observation)| **observation == (kind, id))
+            .map(|(token, _)| *token);
+        if let Some(token) = token {
+            self.observations.remove(&token);
+            session.cancel_observe(token)?;
+        }
+
+        match kind {
+            StateKind::Device => {
+                self.devices.remove(&id);
+            }
+            StateKind::Group => {
+                self.groups.remove(&id);
+            }
+        }
+        self.emit(StateEvent::Removed(kind, id));
+
+        Ok(())
+    }
+
+    fn emit(&mut self, event: StateEvent) {
+        for subscriber in &mut self.subscribers {
+            subscriber(&event);
+        }
+    }
+}
+
+/// The fields that differ between two payloads, compared in their serialized form
+fn diff<T: Serialize>(old: &T, new: &T) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    if let (Ok(old), Ok(new)) = (serde_json::to_value(old), serde_json::to_value(new)) {
+        diff_values(String::new(), Some(&old), Some(&new), &mut changes);
+    }
+
+    changes
+}
+
+fn diff_values(
+    path: String,
+    old: Option<&Value>,
+    new: Option<&Value>,
+    changes: &mut Vec<FieldChange>,
+) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let name = field_name(key).unwrap_or(key);
+                let path = if path.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                diff_values(path, old.get(key), new.get(key), changes);
+            }
+        }
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for i in 0..old.len().max(new.len()) {
+                diff_values(format!("{}[{}]", path, i), old.get(i), new.get(i), changes);
+            }
+        }
+        (old, new) if old != new => changes.push(FieldChange {
+            path,
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn changes(old: Value, new: Value) -> Vec<FieldChange> {
+        let mut changes = Vec::new();
+        diff_values(String::new(), Some(&old), Some(&new), &mut changes);
+        changes
+    }
+
+    fn change(path: &str, old: Option<Value>, new: Option<Value>) -> FieldChange {
+        FieldChange {
+            path: path.to_owned(),
+            old,
+            new,
+        }
+    }
+
+    #[test]
+    fn equal_values_have_no_changes() {
+        let value = json!({"9001": "Lamp", "3311": [{"5850": 1}]});
+        assert!(changes(value.clone(), value).is_empty());
+    }
+
+    #[test]
+    fn keys_are_named_after_the_dto_fields() {
+        assert_eq!(
+            changes(
+                json!({"3311": [{"5850": 0, "5851": 254}]}),
+                json!({"3311": [{"5850": 1, "5851": 254}]})
+            ),
+            vec![change(
+                "light_options[0].on_off",
+                Some(json!(0)),
+                Some(json!(1))
+            )]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_kept() {
+        assert_eq!(
+            changes(json!({"65535": 1}), json!({"65535": 2})),
+            vec![change("65535", Some(json!(1)), Some(json!(2)))]
+        );
+    }
+
+    #[test]
+    fn added_and_removed_keys_have_one_side_only() {
+        assert_eq!(
+            changes(
+                json!({"9001": "Lamp", "9019": 1}),
+                json!({"9001": "Lamp", "9020": 1600000000})
+            ),
+            vec![
+                change("reachable", Some(json!(1)), None),
+                change("last_seen", None, Some(json!(1600000000))),
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays_of_different_length_are_compared_by_index() {
+        assert_eq!(
+            changes(json!({"9018": [1, 2]}), json!({"9018": [1, 3, 4]})),
+            vec![
+                change("members[1]", Some(json!(2)), Some(json!(3))),
+                change("members[2]", None, Some(json!(4))),
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_types_are_reported_whole() {
+        assert_eq!(
+            changes(json!({"3": {"0": "IKEA"}}), json!({"3": null})),
+            vec![change(
+                "product_info",
+                Some(json!({"0": "IKEA"})),
+                Some(Value::Null)
+            )]
+        );
+    }
+
+    #[test]
+    fn diff_compares_serialized_dtos() {
+        let old = DeviceInfo {
+            label: Some("Lamp".into()),
+            reachable: Some(1),
+            ..Default::default()
+        };
+        let new = DeviceInfo {
+            reachable: Some(0),
+            ..old.clone()
+        };
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![change("reachable", Some(json!(1)), Some(json!(0)))]
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use super::{
+    ffi::*, pdu_options, pdu_payload, response::CoapResponseCode, CoapContentFormat,
+    CoapMessageType, CoapMethod, CoapToken,
+};
+#[cfg(feature = "json")]
+use rosthem_dto::field_name;
+#[cfg(feature = "json")]
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Payload previews are cut off after this many characters
+const PREVIEW_LEN: usize = 256;
+
+/// A decoded copy of a sent or received PDU, for logs and traces.
+///
+/// `{}` prints it on one line, `{:#}` with every option and the payload on a line of its own.
+/// JSON payloads are previewed with the Tradfri keys annotated, e.g. `"5850(on_off)": 1`.
+#[derive(Clone, Debug)]
+pub struct CoapPduInfo {
+    pub message_type: CoapMessageType,
+    pub code: CoapResponseCode,
+    pub mid: i32,
+    pub token: CoapToken,
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl CoapPduInfo {
+    pub(crate) unsafe fn from_native(pdu: *const coap_pdu_t) -> CoapPduInfo {
+        let message_type = match coap_pdu_get_type(pdu) {
+            0 => CoapMessageType::Confirmable,
+            1 => CoapMessageType::NonConfirmable,
+            2 => CoapMessageType::Acknowledgement,
+            _ => CoapMessageType::Reset,
+        };
+
+        CoapPduInfo {
+            message_type,
+            code: CoapResponseCode::from(coap_pdu_get_code(pdu)),
+            mid: coap_pdu_get_mid(pdu),
+            token: CoapToken::from(coap_pdu_get_token(pdu)),
+            options: pdu_options(pdu),
+            payload: pdu_payload(pdu),
+        }
+    }
+
+    /// The method if this is a request
+    pub fn method(&self) -> Option<CoapMethod> {
+        match self.code.class {
+            0 => CoapMethod::from_code(u32::from(self.code)),
+            _ => None,
+        }
+    }
+
+    pub fn content_format(&self) -> Option<CoapContentFormat> {
+        self.options
+            .iter()
+            .find(|(number, _)| *number as u32 == COAP_OPTION_CONTENT_FORMAT)
+            .map(|(_, value)| CoapContentFormat::from(decode_uint(value) as u16))
+    }
+
+    /// Encodes the PDU as a CoAP-over-UDP message (RFC 7252), as it looks inside the DTLS records
+    pub fn to_udp_bytes(&self) -> Vec<u8> {
+        let token = self.token.as_bytes();
+        let mut bytes = vec![
+            0x40 | (self.message_type as u8) << 4 | token.len() as u8,
+            u32::from(self.code) as u8,
+        ];
+        bytes.extend_from_slice(&(self.mid as u16).to_be_bytes());
+        bytes.extend_from_slice(token);
+
+        let mut options = self.options.iter().collect::<Vec<_>>();
+        options.sort_by_key(|(number, _)| *number);
+
+        let mut last_number = 0;
+        for (number, value) in options {
+            let (delta, delta_ext) = option_nibble(number - last_number);
+            let (length, length_ext) = option_nibble(value.len() as u16);
+            bytes.push(delta << 4 | length);
+            bytes.extend(delta_ext);
+            bytes.extend(length_ext);
+            bytes.extend_from_slice(value);
+            last_number = *number;
+        }
+
+        if !self.payload.is_empty() {
+            bytes.push(0xFF);
+            bytes.extend_from_slice(&self.payload);
+        }
+
+        bytes
+    }
+
+    /// The payload as text, with Tradfri keys annotated if it is JSON and shortened if it is long
+    pub fn payload_preview(&self) -> Option<String> {
+        if self.payload.is_empty() {
+            return None;
+        }
+
+        let preview = match json_preview(&self.payload) {
+            Some(json) => json,
+            None => match std::str::from_utf8(&self.payload) {
+                Ok(text) => text.to_owned(),
+                Err(_) => format!(
+                    "<{} bytes> {}",
+                    self.payload.len(),
+                    hex::encode(&self.payload)
+                ),
+            },
+        };
+
+        Some(match preview.char_indices().nth(PREVIEW_LEN) {
+            Some((end, _)) => format!("{}...", &preview[..end]),
+            None => preview,
+        })
+    }
+}
+
+impl fmt::Display for CoapPduInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message_type = match self.message_type {
+            CoapMessageType::Confirmable => "CON",
+            CoapMessageType::NonConfirmable => "NON",
+            CoapMessageType::Acknowledgement => "ACK",
+            CoapMessageType::Reset => "RST",
+        };
+        write!(f, "{} {}", message_type, self.code)?;
+        if let Some(method) = self.method() {
+            write!(f, " {}", format!("{:?}", method).to_uppercase())?;
+        }
+        write!(
+            f,
+            " mid={} token={}",
+            self.mid,
+            hex::encode(self.token.as_bytes())
+        )?;
+
+        let separator = if f.alternate() { "\n  " } else { " " };
+        for (number, value) in &self.options {
+            write!(f, "{}{}", separator, DisplayOption(*number, value))?;
+        }
+        if let Some(preview) = self.payload_preview() {
+            write!(f, "{}{}", separator, preview)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct DisplayOption<'a>(u16, &'a [u8]);
+
+impl fmt::Display for DisplayOption<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let DisplayOption(number, value) = *self;
+        let name = match number as u32 {
+            COAP_OPTION_IF_MATCH => "If-Match",
+            COAP_OPTION_URI_HOST => "Uri-Host",
+            COAP_OPTION_ETAG => "ETag",
+            COAP_OPTION_IF_NONE_MATCH => "If-None-Match",
+            COAP_OPTION_OBSERVE => "Observe",
+            COAP_OPTION_URI_PORT => "Uri-Port",
+            COAP_OPTION_LOCATION_PATH => "Location-Path",
+            COAP_OPTION_URI_PATH => "Uri-Path",
+            COAP_OPTION_CONTENT_FORMAT => "Content-Format",
+            COAP_OPTION_MAXAGE => "Max-Age",
+            COAP_OPTION_URI_QUERY => "Uri-Query",
+            COAP_OPTION_ACCEPT => "Accept",
+            COAP_OPTION_LOCATION_QUERY => "Location-Query",
+            COAP_OPTION_BLOCK2 => "Block2",
+            COAP_OPTION_BLOCK1 => "Block1",
+            COAP_OPTION_SIZE2 => "Size2",
+            COAP_OPTION_PROXY_URI => "Proxy-Uri",
+            COAP_OPTION_PROXY_SCHEME => "Proxy-Scheme",
+            COAP_OPTION_SIZE1 => "Size1",
+            _ => return write!(f, "Option{}={}", number, hex::encode(value)),
+        };
+
+        match number as u32 {
+            COAP_OPTION_URI_HOST
+            | COAP_OPTION_LOCATION_PATH
+            | COAP_OPTION_URI_PATH
+            | COAP_OPTION_URI_QUERY
+            | COAP_OPTION_LOCATION_QUERY
+            | COAP_OPTION_PROXY_URI
+            | COAP_OPTION_PROXY_SCHEME => {
+                write!(f, "{}={}", name, String::from_utf8_lossy(value))
+            }
+            COAP_OPTION_CONTENT_FORMAT | COAP_OPTION_ACCEPT => write!(
+                f,
+                "{}={:?}",
+                name,
+                CoapContentFormat::from(decode_uint(value) as u16)
+            ),
+            COAP_OPTION_BLOCK1 | COAP_OPTION_BLOCK2 => {
+                // NUM/M/SZX, shown as block number, more flag and block size
+                let block = decode_uint(value);
+                write!(
+                    f,
+                    "{}={}/{}/{}",
+                    name,
+                    block >> 4,
+                    (block >> 3) & 1,
+                    16 << (block & 7)
+                )
+            }
+            COAP_OPTION_OBSERVE | COAP_OPTION_URI_PORT | COAP_OPTION_MAXAGE | COAP_OPTION_SIZE1
+            | COAP_OPTION_SIZE2 => write!(f, "{}={}", name, decode_uint(value)),
+            _ => write!(f, "{}={}", name, hex::encode(value)),
+        }
+    }
+}
+
+/// The 4 bit value of an option delta or length and its extended bytes
+fn option_nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+fn decode_uint(value: &[u8]) -> u32 {
+    value.iter().fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+#[cfg(feature = "json")]
+fn json_preview(payload: &[u8]) -> Option<String> {
+    let value = serde_json::from_slice::<Value>(payload).ok()?;
+    Some(annotate(value).to_string())
+}
+
+#[cfg(not(feature = "json"))]
+fn json_preview(_payload: &[u8]) -> Option<String> {
+    None
+}
+
+/// Appends the field name to every key a rosthem-dto type knows
+#[cfg(feature = "json")]
+fn annotate(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match field_name(&key) {
+                        Some(name) => format!("{}({})", key, name),
+                        None => key,
+                    };
+                    (key, annotate(value))
+                })
+                .collect::<Map<String, Value>>(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(annotate).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pdu(
+        message_type: CoapMessageType,
+        code: CoapResponseCode,
+        token: &[u8],
+        options: &[(u16, &[u8])],
+        payload: &[u8],
+    ) -> CoapPduInfo {
+        let mut token_buf = [0; 8];
+        token_buf[..token.len()].copy_from_slice(token);
+
+        CoapPduInfo {
+            message_type,
+            code,
+            mid: 0x1234,
+            token: CoapToken {
+                len: token.len() as _,
+                token: token_buf,
+            },
+            options: options
+                .iter()
+                .map(|(number, value)| (*number, value.to_vec()))
+                .collect(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn option_nibble_uses_extended_bytes() {
+        let cases: [(u16, u8, &[u8]); 7] = [
+            (0, 0, &[]),
+            (12, 12, &[]),
+            (13, 13, &[0x00]),
+            (268, 13, &[0xFF]),
+            (269, 14, &[0x00, 0x00]),
+            (1000, 14, &[0x02, 0xDB]),
+            (u16::MAX, 14, &[0xFE, 0xF2]),
+        ];
+
+        for (value, nibble, extended) in cases {
+            assert_eq!(
+                option_nibble(value),
+                (nibble, extended.to_vec()),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn to_udp_bytes_encodes_rfc7252_messages() {
+        let proxy_uri = [b'x'; 20];
+        let cases: [(CoapPduInfo, Vec<u8>); 4] = [
+            (
+                pdu(
+                    CoapMessageType::Confirmable,
+                    CoapResponseCode::new(0, 1),
+                    &[],
+                    &[],
+                    b"",
+                ),
+                vec![0x40, 0x01, 0x12, 0x34],
+            ),
+            (
+                pdu(
+                    CoapMessageType::Acknowledgement,
+                    CoapResponseCode::CONTENT,
+                    &[0xAB, 0xCD],
+                    &[],
+                    b"{}",
+                ),
+                vec![0x62, 0x45, 0x12, 0x34, 0xAB, 0xCD, 0xFF, b'{', b'}'],
+            ),
+            (
+                // Options are sorted by number, each delta is relative to the previous one
+                pdu(
+                    CoapMessageType::NonConfirmable,
+                    CoapResponseCode::new(0, 3),
+                    &[0x01],
+                    &[(12, &[50]), (11, b"15001")],
+                    b"",
+                ),
+                [
+                    &[0x51, 0x03, 0x12, 0x34, 0x01, 0xB5][..],
+                    b"15001",
+                    &[0x11, 50],
+                ]
+                .concat(),
+            ),
+            (
+                pdu(
+                    CoapMessageType::Reset,
+                    CoapResponseCode::new(0, 0),
+                    &[],
+                    &[(60, &[]), (35, &proxy_uri)],
+                    b"",
+                ),
+                [
+                    &[0x70, 0x00, 0x12, 0x34, 0xDD, 35 - 13, 20 - 13][..],
+                    &proxy_uri,
+                    &[0xD0, 25 - 13],
+                ]
+                .concat(),
+            ),
+        ];
+
+        for (pdu, expected) in cases {
+            assert_eq!(pdu.to_udp_bytes(), expected, "{}", pdu);
+        }
+    }
+}
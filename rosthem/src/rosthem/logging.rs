@@ -0,0 +1,30 @@
+use super::ffi::*;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// Routes libcoap's output to `tracing` (and through its `log` feature to `log`) under the
+/// `libcoap` target instead of stderr. libcoap 4.3.0 has no separate DTLS log handler; (D)TLS
+/// messages come through here as well, filtered by `coap_dtls_set_log_level`.
+pub(crate) fn install() {
+    unsafe {
+        coap_set_log_handler(Some(handle_log));
+    }
+}
+
+unsafe extern "C" fn handle_log(level: coap_log_t, message: *const c_char) {
+    if message.is_null() {
+        return;
+    }
+
+    let message = CStr::from_ptr(message).to_string_lossy();
+    let message = message.trim_end();
+
+    // Syslog levels, plus COAP_LOG_CIPHERS above debug
+    match level {
+        0..=3 => tracing::error!(target: "libcoap", "{}", message),
+        4 => tracing::warn!(target: "libcoap", "{}", message),
+        5 | 6 => tracing::info!(target: "libcoap", "{}", message),
+        7 => tracing::debug!(target: "libcoap", "{}", message),
+        _ => tracing::trace!(target: "libcoap", "{}", message),
+    }
+}
@@ -0,0 +1,130 @@
+use super::CoapNackReason;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Upper bounds of the response latency buckets
+pub const LATENCY_BUCKETS: &[Duration] = &[
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Counters of a single session, see [`super::CoapSession::metrics`]. They survive reconnects.
+#[derive(Clone, Debug, Default)]
+pub struct SessionMetrics {
+    /// Requests handed to libcoap, including observations re-registered after a reconnect
+    pub requests_sent: u64,
+    pub responses_received: u64,
+    pub nacks: HashMap<CoapNackReason, u64>,
+    /// Retransmissions of confirmable requests, as far as they are known: libcoap doesn't report
+    /// them, but a [`CoapNackReason::TooManyRetries`] means it retransmitted the request
+    /// MAX_RETRANSMIT times before giving up. Retransmissions of requests that got through in
+    /// the end aren't counted, so this is a lower bound.
+    pub retransmissions: u64,
+    /// Successful [`super::CoapSession::reconnect`]s
+    pub reconnects: u64,
+    /// Time from sending a request to its first response. Later notifications of an
+    /// observation aren't counted.
+    pub response_latency: LatencyHistogram,
+}
+
+/// Response latencies in the buckets of [`LATENCY_BUCKETS`]
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    sum: Duration,
+    count: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            counts: vec![0; LATENCY_BUCKETS.len()],
+            sum: Duration::ZERO,
+            count: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub(crate) fn observe(&mut self, latency: Duration) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| latency <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += latency;
+        self.count += 1;
+    }
+
+    /// Upper bound and cumulative count of every bucket, without the implicit `+Inf` bucket
+    /// (which is [`LatencyHistogram::count`])
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        LATENCY_BUCKETS
+            .iter()
+            .zip(&self.counts)
+            .scan(0, |cumulative, (bound, count)| {
+                *cumulative += count;
+                Some((*bound, *cumulative))
+            })
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_histogram_has_zero_buckets() {
+        let histogram = LatencyHistogram::default();
+
+        assert_eq!(histogram.buckets().count(), LATENCY_BUCKETS.len());
+        assert!(histogram.buckets().all(|(_, count)| count == 0));
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.sum(), Duration::ZERO);
+    }
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(10));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(2));
+
+        let buckets: Vec<(Duration, u64)> = histogram.buckets().collect();
+        assert_eq!(buckets[0], (Duration::from_millis(10), 2));
+        assert_eq!(buckets[1], (Duration::from_millis(25), 2));
+        assert_eq!(buckets[2], (Duration::from_millis(50), 3));
+        assert_eq!(buckets[6], (Duration::from_secs(1), 3));
+        assert_eq!(buckets[7], (Duration::from_millis(2500), 4));
+        assert_eq!(buckets[9], (Duration::from_secs(10), 4));
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(2045));
+    }
+
+    #[test]
+    fn latencies_above_the_last_bucket_only_count_towards_the_total() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_secs(30));
+
+        assert_eq!(
+            histogram.buckets().last(),
+            Some((Duration::from_secs(10), 0))
+        );
+        assert_eq!(histogram.count(), 1);
+    }
+}
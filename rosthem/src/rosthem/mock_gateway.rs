@@ -0,0 +1,306 @@
+use super::{
+    error::CoapError,
+    response::CoapResponseCode,
+    server::{CoapReply, CoapResource},
+    CoapContext, CoapMethod, CoapUriScheme,
+};
+use rosthem_dto::{DeviceInfo, LightInfo};
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+
+const DEVICES: &str = "15001";
+const GROUPS: &str = "15004";
+const MOODS: &str = "15005";
+const GATEWAY: &str = "15011";
+/// The keys of a group PUT that the gateway passes on to the lights of the group
+const LIGHT_KEYS: &[&str] = &["5850", "5851", "5706", "5709", "5710", "5711", "5712"];
+
+/// An in-memory stand-in for an IKEA Tradfri gateway, serving devices (15001), groups (15004),
+/// moods (15005) and gateway details and pairing (15011) over DTLS-PSK.
+///
+/// It is served from the same context as the client sessions talking to it, so requests are
+/// answered while a session waits for them. The only accepted PSK is the security code, which
+/// is also what pairing hands out.
+pub struct MockGateway {
+    state: Rc<RefCell<MockState>>,
+    context: Rc<CoapContext>,
+    addr: SocketAddr,
+}
+
+#[derive(Default)]
+struct MockState {
+    devices: BTreeMap<u32, MockResource>,
+    groups: BTreeMap<u32, MockResource>,
+    moods: BTreeMap<u32, BTreeMap<u32, MockResource>>,
+    security_code: String,
+}
+
+struct MockResource {
+    value: Value,
+    resource: CoapResource,
+}
+
+impl MockGateway {
+    pub fn new(
+        context: &Rc<CoapContext>,
+        addr: SocketAddr,
+        security_code: &str,
+    ) -> Result<MockGateway, CoapError> {
+        context.set_server_psk("mock-gateway", security_code)?;
+        let addr = context.add_endpoint(addr, CoapUriScheme::Coaps)?;
+
+        let gateway = MockGateway {
+            state: Rc::new(RefCell::new(MockState {
+                security_code: security_code.to_owned(),
+                ..MockState::default()
+            })),
+            context: context.clone(),
+            addr,
+        };
+
+        gateway.add_list(DEVICES, |state| state.devices.keys().copied().collect())?;
+        gateway.add_list(GROUPS, |state| state.groups.keys().copied().collect())?;
+        gateway.add_list(MOODS, |state| state.moods.keys().copied().collect())?;
+
+        let details = gateway
+            .context
+            .add_resource(&format!("{}/15012", GATEWAY))?;
+        details.on(CoapMethod::Get, |_| {
+            CoapReply::content().with_payload(&json!({ "9029": "1.0.0", "9023": "localhost" }))
+        });
+
+        let state = Rc::downgrade(&gateway.state);
+        let pairing = gateway.context.add_resource(&format!("{}/9063", GATEWAY))?;
+        pairing.on(CoapMethod::Post, move |request| {
+            with_state(&state, |state| match request.decode::<Value>() {
+                Ok(body) if body.get("9090").is_some() => CoapReply::new(CoapResponseCode::CREATED)
+                    .with_payload(&json!({ "9091": state.security_code, "9029": "1.0.0" })),
+                _ => CoapError::BadRequest.into(),
+            })
+        });
+
+        Ok(gateway)
+    }
+
+    /// The address the gateway listens on, with the port picked if `new` was given port 0
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn add_device(&self, id: u32, device: &DeviceInfo) -> Result<(), CoapError> {
+        let mut value = serde_json::to_value(device).map_err(|_| CoapError::SerializeError)?;
+        value["9003"] = json!(id);
+
+        let resource = self.context.add_resource(&format!("{}/{}", DEVICES, id))?;
+        resource.set_observable(true);
+
+        let state = Rc::downgrade(&self.state);
+        resource.on(CoapMethod::Get, move |_| {
+            with_state(&state, |state| match state.devices.get(&id) {
+                Some(device) => CoapReply::content().with_payload(&device.value),
+                None => CoapError::NotFound.into(),
+            })
+        });
+
+        let state = Rc::downgrade(&self.state);
+        resource.on(CoapMethod::Put, move |request| {
+            with_state(&state, |state| match request.decode::<Value>() {
+                Ok(update) => match state.devices.get_mut(&id) {
+                    Some(device) => {
+                        merge(&mut device.value, &update);
+                        device.resource.notify_observers();
+                        CoapReply::changed()
+                    }
+                    None => CoapError::NotFound.into(),
+                },
+                Err(e) => e.into(),
+            })
+        });
+
+        self.state
+            .borrow_mut()
+            .devices
+            .insert(id, MockResource { value, resource });
+
+        Ok(())
+    }
+
+    /// Adds a group whose PUTs of light state (on/off, brightness, color, ...) are also applied to
+    /// the lights of `members`
+    pub fn add_group(&self, id: u32, name: &str, members: &[u32]) -> Result<(), CoapError> {
+        let value = json!({
+            "9001": name,
+            "9003": id,
+            "9018": { "15002": { "9003": members } },
+        });
+
+        let resource = self.context.add_resource(&format!("{}/{}", GROUPS, id))?;
+        resource.set_observable(true);
+
+        let state = Rc::downgrade(&self.state);
+        resource.on(CoapMethod::Get, move |_| {
+            with_state(&state, |state| match state.groups.get(&id) {
+                Some(group) => CoapReply::content().with_payload(&group.value),
+                None => CoapError::NotFound.into(),
+            })
+        });
+
+        let state = Rc::downgrade(&self.state);
+        let members = members.to_vec();
+        resource.on(CoapMethod::Put, move |request| {
+            with_state(&state, |state| match request.decode::<Value>() {
+                Ok(update) => {
+                    let light_options: Map<String, Value> = update
+                        .as_object()
+                        .into_iter()
+                        .flatten()
+                        .filter(|(key, _)| LIGHT_KEYS.contains(&key.as_str()))
+                        .map(|(key, value)| (key.clone(), value.clone()))
+                        .collect();
+                    if !light_options.is_empty() {
+                        let light_update = json!({ "3311": [light_options] });
+                        for member in &members {
+                            if let Some(device) = state.devices.get_mut(member) {
+                                merge(&mut device.value, &light_update);
+                                device.resource.notify_observers();
+                            }
+                        }
+                    }
+
+                    match state.groups.get_mut(&id) {
+                        Some(group) => {
+                            merge(&mut group.value, &update);
+                            group.resource.notify_observers();
+                            CoapReply::changed()
+                        }
+                        None => CoapError::NotFound.into(),
+                    }
+                }
+                Err(e) => e.into(),
+            })
+        });
+
+        self.state
+            .borrow_mut()
+            .groups
+            .insert(id, MockResource { value, resource });
+
+        Ok(())
+    }
+
+    pub fn add_mood(&self, group: u32, id: u32, name: &str) -> Result<(), CoapError> {
+        if !self.state.borrow().moods.contains_key(&group) {
+            self.add_list(&format!("{}/{}", MOODS, group), move |state| {
+                state
+                    .moods
+                    .get(&group)
+                    .map(|moods| moods.keys().copied().collect())
+                    .unwrap_or_default()
+            })?;
+        }
+
+        let value = json!({ "9001": name, "9003": id });
+        let resource = self
+            .context
+            .add_resource(&format!("{}/{}/{}", MOODS, group, id))?;
+
+        let state = Rc::downgrade(&self.state);
+        resource.on(CoapMethod::Get, move |_| {
+            with_state(&state, |state| {
+                match state.moods.get(&group).and_then(|moods| moods.get(&id)) {
+                    Some(mood) => CoapReply::content().with_payload(&mood.value),
+                    None => CoapError::NotFound.into(),
+                }
+            })
+        });
+
+        self.state
+            .borrow_mut()
+            .moods
+            .entry(group)
+            .or_default()
+            .insert(id, MockResource { value, resource });
+
+        Ok(())
+    }
+
+    /// The current state of a device, as a client would read it
+    pub fn device(&self, id: u32) -> Option<DeviceInfo> {
+        let state = self.state.borrow();
+        let device = state.devices.get(&id)?;
+
+        serde_json::from_value(device.value.clone()).ok()
+    }
+
+    /// Changes a light as if someone used the remote, notifying observers
+    pub fn set_light(&self, id: u32, light: &LightInfo) -> Result<(), CoapError> {
+        let update = serde_json::to_value(light).map_err(|_| CoapError::SerializeError)?;
+
+        let mut state = self.state.borrow_mut();
+        let device = state.devices.get_mut(&id).ok_or(CoapError::NotFound)?;
+        merge(&mut device.value, &update);
+        device.resource.notify_observers();
+
+        Ok(())
+    }
+
+    fn add_list(
+        &self,
+        path: &str,
+        ids: impl Fn(&MockState) -> Vec<u32> + 'static,
+    ) -> Result<(), CoapError> {
+        let state = Rc::downgrade(&self.state);
+        self.context
+            .add_resource(path)?
+            .on(CoapMethod::Get, move |_| {
+                with_state(&state, |state| {
+                    CoapReply::content().with_payload(&ids(state))
+                })
+            });
+
+        Ok(())
+    }
+}
+
+/// Handlers outlive the gateway (they belong to the context), so they only hold on to its state
+/// weakly
+fn with_state(
+    state: &Weak<RefCell<MockState>>,
+    handle: impl FnOnce(&mut MockState) -> CoapReply,
+) -> CoapReply {
+    match state.upgrade() {
+        Some(state) => handle(&mut state.borrow_mut()),
+        None => CoapError::NotFound.into(),
+    }
+}
+
+/// Applies a partial update the way the gateway does: objects are merged key by key, and so are
+/// the objects in arrays like 3311
+fn merge(target: &mut Value, update: &Value) {
+    match (target, update) {
+        (Value::Object(target), Value::Object(update)) => merge_objects(target, update),
+        (Value::Array(target), Value::Array(update)) => {
+            for (i, update) in update.iter().enumerate() {
+                match target.get_mut(i) {
+                    Some(target) => merge(target, update),
+                    None => target.push(update.clone()),
+                }
+            }
+        }
+        (target, update) => *target = update.clone(),
+    }
+}
+
+fn merge_objects(target: &mut Map<String, Value>, update: &Map<String, Value>) {
+    for (key, update) in update {
+        match target.get_mut(key) {
+            Some(target) => merge(target, update),
+            None => {
+                target.insert(key.clone(), update.clone());
+            }
+        }
+    }
+}
@@ -1,21 +1,56 @@
 // TODO: Use size_t/usize properly where I failed to do it
 
+pub mod codec;
+pub mod credential_store;
+pub mod credentials;
 pub mod error;
 mod ffi;
+#[cfg(feature = "json")]
+pub mod gateway_state;
+pub mod inspect;
+mod logging;
+pub mod metrics;
+#[cfg(feature = "mock-gateway")]
+pub mod mock_gateway;
+mod pcap;
+pub mod queue;
+pub mod recording;
+pub mod response;
+pub mod server;
+#[cfg(feature = "json")]
 pub mod session_ext;
+pub mod supervised;
 
+#[cfg(feature = "json")]
+use self::codec::JsonCodec;
+use self::codec::PayloadCodec;
+use self::credential_store::CredentialStore;
+use self::credentials::{CoapCredentials, CoapDtlsServerPsk, DtlsSetup};
 use self::error::CoapError;
+use self::inspect::CoapPduInfo;
+use self::metrics::SessionMetrics;
+use self::pcap::PcapWriter;
+use self::queue::{OutboundQueue, QueueConfig};
+use self::recording::Direction;
+#[cfg(feature = "json")]
+use self::recording::Recorder;
+use self::response::{CoapResponse, CoapResponseCode};
+use self::server::CoapResource;
 use ffi::*;
 pub use rosthem_dto;
 use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::os::raw::c_ulong;
 use std::rc::Rc;
 use std::{
-    net::Ipv4Addr,
+    ffi::CStr,
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
     pin::Pin,
     ptr::{self, NonNull},
     sync::atomic::{AtomicBool, Ordering},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 static COAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
@@ -61,6 +96,7 @@ impl Coap {
                 coap_dtls_set_log_level(log_level as i32);
                 coap_set_log_level(log_level as u32);
             }
+            logging::install();
             Ok(Rc::new(Coap {
                 _private: ptr::null(),
             }))
@@ -72,7 +108,6 @@ impl Coap {
     pub fn new_context<'a>(self: &Rc<Self>) -> Result<Rc<CoapContext>, CoapError> {
         unsafe {
             let ctx = coap_new_context(ptr::null());
-            // coap_context_set_keepalive(ctx, ping_seconds);
             // coap_context_set_block_mode(ctx, block_mode);
             if let Some(ctx) = NonNull::new(ctx) {
                 coap_context_set_block_mode(
@@ -83,8 +118,12 @@ impl Coap {
                 coap_register_response_handler(ctx.as_ptr(), Some(handle_response));
                 coap_register_event_handler(ctx.as_ptr(), Some(handle_event));
                 coap_register_nack_handler(ctx.as_ptr(), Some(handle_nack));
+                coap_register_ping_handler(ctx.as_ptr(), Some(handle_ping));
+                coap_register_pong_handler(ctx.as_ptr(), Some(handle_pong));
                 Ok(Rc::new(CoapContext {
                     inner: ctx,
+                    credential_store: RefCell::new(None),
+                    server_psk: RefCell::new(None),
                     _coap: self.clone(),
                 }))
             } else {
@@ -96,6 +135,8 @@ impl Coap {
 
 pub struct CoapContext {
     inner: NonNull<coap_context_t>,
+    credential_store: RefCell<Option<Box<dyn CredentialStore>>>,
+    server_psk: RefCell<Option<Pin<Box<CoapDtlsServerPsk>>>>,
     _coap: Rc<Coap>,
 }
 
@@ -109,51 +150,231 @@ impl Drop for CoapContext {
 
 impl CoapContext {
     // TODO: Parse ip from uri
+    /// Creates a DTLS session to a gateway. Without `credentials`, they are looked up in the
+    /// credential store by the host of `uri`.
     pub fn new_session(
         self: &Rc<Self>,
         ip: Ipv4Addr,
         uri: CoapUri,
-        identity: &str,
-        key: &str,
+        credentials: Option<CoapCredentials>,
         warmup: bool,
     ) -> Result<CoapSession, CoapError> {
+        let _span = tracing::debug_span!("new_session", %ip).entered();
+        let server = CoapAddress::new(ip);
+        let credentials = self.credentials_for(&uri, credentials)?;
+        let mut dtls = Some(DtlsSetup::new(uri, credentials)?);
+        let inner = self.connect(&server, coap_proto_t_COAP_PROTO_DTLS, &mut dtls)?;
+        let coap_session = CoapSession::new(
+            inner,
+            self.clone(),
+            server,
+            coap_proto_t_COAP_PROTO_DTLS,
+            dtls,
+        );
+
+        if warmup {
+            self.run(Some(Duration::from_millis(1500)), None)?; // TODO: Is this number sensible?
+        }
+
+        Ok(coap_session)
+    }
+
+    /// Creates an unsecured CoAP session over UDP, e.g. for lab devices or a local test server.
+    /// A port of 0 selects the default CoAP port.
+    pub fn new_session_plain(self: &Rc<Self>, addr: SocketAddr) -> Result<CoapSession, CoapError> {
+        let _span = tracing::debug_span!("new_session", %addr).entered();
+        let server = CoapAddress::from(addr);
+        let inner = self.connect(&server, coap_proto_t_COAP_PROTO_UDP, &mut None)?;
+
+        Ok(CoapSession::new(
+            inner,
+            self.clone(),
+            server,
+            coap_proto_t_COAP_PROTO_UDP,
+            None,
+        ))
+    }
+
+    /// Creates a session for a `coap://`, `coaps://`, `coap+tcp://` or `coaps+tcp://` URI. The
+    /// secure schemes need credentials, which are looked up in the credential store by host if
+    /// none are passed. Over TCP and TLS, libcoap exchanges the
+    /// CSM signalling message before the session counts as connected and answers pings by
+    /// itself; both show up as [`CoapEvent`]s.
+    pub fn new_session_from_uri(
+        self: &Rc<Self>,
+        uri: CoapUri,
+        credentials: Option<CoapCredentials>,
+    ) -> Result<CoapSession, CoapError> {
+        let _span = tracing::debug_span!("new_session", uri = %uri.uri).entered();
+        let scheme = uri.scheme()?;
+        if !scheme.is_supported() {
+            return Err(CoapError::ProtocolNotSupported);
+        }
+
+        let server = CoapAddress::from(uri.socket_addr()?);
+        let mut dtls = if scheme.is_secure() {
+            let credentials = self.credentials_for(&uri, credentials)?;
+            Some(DtlsSetup::new(uri, credentials)?)
+        } else {
+            None
+        };
+
+        let inner = self.connect(&server, scheme.proto(), &mut dtls)?;
+
+        Ok(CoapSession::new(
+            inner,
+            self.clone(),
+            server,
+            scheme.proto(),
+            dtls,
+        ))
+    }
+
+    pub fn set_credential_store(&self, store: Option<Box<dyn CredentialStore>>) {
+        *self.credential_store.borrow_mut() = store;
+    }
+
+    /// `credentials`, or else the ones stored for the host of `uri`
+    fn credentials_for(
+        &self,
+        uri: &CoapUri,
+        credentials: Option<CoapCredentials>,
+    ) -> Result<CoapCredentials, CoapError> {
+        if let Some(credentials) = credentials {
+            return Ok(credentials);
+        }
+
+        match &*self.credential_store.borrow() {
+            Some(store) => store.load(uri.host()?)?,
+            None => None,
+        }
+        .ok_or(CoapError::MissingCredentials)
+    }
+
+    /// Accepts requests on `addr` and returns the address the endpoint is bound to, e.g. to learn
+    /// the port picked for port 0. Secure schemes need a PSK set with
+    /// [`CoapContext::set_server_psk`] first.
+    pub fn add_endpoint(
+        &self,
+        addr: SocketAddr,
+        scheme: CoapUriScheme,
+    ) -> Result<SocketAddr, CoapError> {
+        if !scheme.is_supported() {
+            return Err(CoapError::ProtocolNotSupported);
+        }
+
+        let listen_addr = CoapAddress::from(addr);
+        unsafe {
+            let endpoint =
+                coap_new_endpoint(self.inner.as_ptr(), &listen_addr.native, scheme.proto());
+            if endpoint.is_null() {
+                return Err(CoapError::FailedToCreateEndpoint);
+            }
+
+            // libcoap only describes endpoints as text, e.g. "127.0.0.1:5683 UDP"
+            CStr::from_ptr(coap_endpoint_str(endpoint))
+                .to_str()
+                .ok()
+                .and_then(|description| description.split_whitespace().next())
+                .and_then(|addr| addr.parse().ok())
+                .ok_or(CoapError::FailedToCreateEndpoint)
+        }
+    }
+
+    /// The PSK that clients of secure endpoints have to use, whatever their identity
+    pub fn set_server_psk(
+        &self,
+        hint: impl Into<Vec<u8>>,
+        key: impl Into<Vec<u8>>,
+    ) -> Result<(), CoapError> {
+        let mut psk = CoapDtlsServerPsk::new(hint.into(), key.into());
         unsafe {
-            let server = CoapAddress::new(ip);
-            let mut dtls_psk = CoapDtlsPsk::new(uri, identity, key)?;
-            let session = coap_new_client_session_psk2(
+            if coap_context_set_psk2(
                 self.inner.as_ptr(),
-                ptr::null(),
-                &server.native,
-                coap_proto_t_COAP_PROTO_DTLS,
-                &mut Pin::get_unchecked_mut(dtls_psk.as_mut()).native,
-            );
+                &mut Pin::get_unchecked_mut(psk.as_mut()).native,
+            ) == 0
+            {
+                return Err(CoapError::InvalidCredentials);
+            }
+        }
+        *self.server_psk.borrow_mut() = Some(psk);
 
-            let mut coap_session = NonNull::new(session)
-                .ok_or(CoapError::FailedToCreateSession)
-                .map(|inner| CoapSession {
-                    inner,
-                    last_token: CoapToken::new(),
-                    _context: self.clone(),
-                })?;
+        Ok(())
+    }
 
-            coap_session_init_token(
-                coap_session.inner.as_ptr(),
-                coap_session.last_token.token.len() as c_ulong,
-                coap_session.last_token.token.as_mut_ptr(),
+    /// Serves `path` (without leading slash, e.g. `15001/65536`) from this context. Handlers
+    /// are added to the returned resource and run from [`CoapContext::run`].
+    pub fn add_resource(self: &Rc<Self>, path: &str) -> Result<CoapResource, CoapError> {
+        CoapResource::new(self, path)
+    }
+
+    fn connect(
+        &self,
+        server: &CoapAddress,
+        proto: coap_proto_t,
+        dtls: &mut Option<DtlsSetup>,
+    ) -> Result<NonNull<coap_session_t>, CoapError> {
+        unsafe {
+            let session = match dtls {
+                Some(DtlsSetup::Psk(dtls_psk)) => coap_new_client_session_psk2(
+                    self.inner.as_ptr(),
+                    ptr::null(),
+                    &server.native,
+                    proto,
+                    &mut Pin::get_unchecked_mut(dtls_psk.as_mut()).native,
+                ),
+                Some(DtlsSetup::Pki(dtls_pki)) => coap_new_client_session_pki(
+                    self.inner.as_ptr(),
+                    ptr::null(),
+                    &server.native,
+                    proto,
+                    &mut Pin::get_unchecked_mut(dtls_pki.as_mut()).native,
+                ),
+                None => {
+                    coap_new_client_session(self.inner.as_ptr(), ptr::null(), &server.native, proto)
+                }
+            };
+
+            NonNull::new(session).ok_or(CoapError::FailedToCreateSession)
+        }
+    }
+
+    /// Sends a CoAP ping on every session that has been idle for `interval`, which keeps NAT
+    /// mappings and the gateway's DTLS state alive between (Observe) notifications. Pongs and
+    /// unanswered pings are reported through the event handler; a session whose ping goes
+    /// unanswered is marked as failed so a [`supervised::SupervisedSession`] reconnects it.
+    pub fn set_keepalive(&self, interval: Option<Duration>) {
+        unsafe {
+            coap_context_set_keepalive(
+                self.inner.as_ptr(),
+                interval.map(|i| i.as_secs().max(1) as u32).unwrap_or(0),
             );
+        }
+    }
 
-            if warmup {
-                self.run(Some(Duration::from_millis(1500)), None)?; // TODO: Is this number sensible?
-            }
+    /// Installs a handler for session events. Unlike the response handler it stays installed
+    /// until replaced, so events are also seen outside of `run`.
+    pub fn set_event_handler(&self, handle_event: Option<Box<dyn Fn(CoapEvent)>>) {
+        unsafe {
+            USER_EVENT_HANDLER = handle_event;
+        }
+    }
 
-            Ok(coap_session)
+    /// Runs a single round of IO processing, waiting at most `timeout` for something to happen
+    pub(crate) fn process(&self, timeout: Duration) -> Result<(), CoapError> {
+        unsafe {
+            if coap_io_process(self.inner.as_ptr(), timeout.as_millis().max(1) as u32) == -1 {
+                Err(CoapError::IoError)
+            } else {
+                Ok(())
+            }
         }
     }
 
     pub fn run(
         &self,
         timeout_ms: Option<Duration>,
-        handle_response: Option<Box<dyn Fn(CoapToken, String)>>,
+        handle_response: Option<Box<dyn Fn(CoapResponse)>>,
     ) -> Result<(), CoapError> {
         unsafe {
             USER_RESPONSE_HANDLER = handle_response;
@@ -177,73 +398,573 @@ impl CoapContext {
     }
 }
 
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct CoapSession {
     inner: NonNull<coap_session_t>,
     last_token: CoapToken,
-    _context: Rc<CoapContext>,
+    request_timeout: Duration,
+    // Boxed separately so the pointer handed to libcoap as app data stays valid when the session moves
+    state: NonNull<SessionState>,
+    server: CoapAddress,
+    proto: coap_proto_t,
+    // libcoap keeps pointers into this (e.g. the SNI), so it has to live as long as the native session
+    dtls: Option<DtlsSetup>,
+    queue: OutboundQueue,
+    context: Rc<CoapContext>,
+}
+
+#[derive(Default)]
+pub(crate) struct SessionState {
+    awaiting: HashSet<CoapToken>,
+    responses: HashMap<CoapToken, Result<CoapResponse, CoapError>>,
+    failed: bool,
+    #[cfg(feature = "json")]
+    recorder: Option<Recorder>,
+    capture: Option<PcapWriter>,
+    inspector: Option<PduInspector>,
+    metrics: SessionMetrics,
+    // Send time of requests whose first response hasn't arrived yet, for the latency histogram
+    sent_at: HashMap<CoapToken, Instant>,
+}
+
+impl SessionState {
+    /// Whether a recorder, capture or inspector wants to see the PDUs of the session
+    fn inspects_pdus(&self) -> bool {
+        #[cfg(feature = "json")]
+        if self.recorder.is_some() {
+            return true;
+        }
+        self.capture.is_some() || self.inspector.is_some()
+    }
 }
 
 impl Drop for CoapSession {
     fn drop(&mut self) {
-        unsafe { coap_session_release(self.inner.as_ptr()) }
+        unsafe {
+            coap_session_set_app_data(self.inner.as_ptr(), ptr::null_mut());
+            coap_session_release(self.inner.as_ptr());
+            drop(Box::from_raw(self.state.as_ptr()));
+        }
     }
 }
 
 impl CoapSession {
-    pub fn send_pdu<P: Serialize>(
-        &mut self,
-        pdu: CoapPduBuilder<'_, P>,
-    ) -> Result<CoapToken, CoapError> {
-        let pdu = pdu.with_token(&self.last_token).build(self)?;
-        let token = self.last_token.clone();
+    fn new(
+        inner: NonNull<coap_session_t>,
+        context: Rc<CoapContext>,
+        server: CoapAddress,
+        proto: coap_proto_t,
+        dtls: Option<DtlsSetup>,
+    ) -> CoapSession {
+        unsafe {
+            let state = NonNull::new_unchecked(Box::into_raw(Box::new(SessionState::default())));
+            coap_session_set_app_data(inner.as_ptr(), state.as_ptr() as *mut _);
+
+            let mut session = CoapSession {
+                inner,
+                last_token: CoapToken::new(),
+                request_timeout: DEFAULT_REQUEST_TIMEOUT,
+                state,
+                server,
+                proto,
+                dtls,
+                queue: OutboundQueue::new(),
+                context,
+            };
+
+            coap_session_init_token(
+                session.inner.as_ptr(),
+                session.last_token.token.len() as c_ulong,
+                session.last_token.token.as_mut_ptr(),
+            );
+
+            session
+        }
+    }
+
+    /// Whether the (D)TLS handshake has completed and the session can carry requests
+    pub fn is_connected(&self) -> bool {
+        unsafe {
+            coap_session_get_state(self.inner.as_ptr())
+                == coap_session_state_t_COAP_SESSION_STATE_ESTABLISHED
+        }
+    }
+
+    /// Whether libcoap reported the session as closed or failed. A failed session has to be
+    /// reconnected before it can be used again.
+    pub fn has_failed(&self) -> bool {
+        unsafe { self.state.as_ref().failed }
+    }
+
+    /// Replaces the native session with a fresh one to the same server, using the same
+    /// credentials and transmission parameters. The token sequence continues where the old
+    /// session left off, so tokens of earlier requests are never handed out again.
+    /// Requests that were in flight on the old session are lost.
+    pub fn reconnect(&mut self) -> Result<(), CoapError> {
+        let _span = tracing::debug_span!("reconnect").entered();
+        let ack_timeout = self.ack_timeout();
+        let ack_random_factor = self.ack_random_factor();
+        let max_retransmit = self.max_retransmit();
+
+        let inner = self
+            .context
+            .connect(&self.server, self.proto, &mut self.dtls)?;
+
+        unsafe {
+            coap_session_set_app_data(self.inner.as_ptr(), ptr::null_mut());
+            coap_session_release(self.inner.as_ptr());
+
+            self.inner = inner;
+            coap_session_set_app_data(self.inner.as_ptr(), self.state.as_ptr() as *mut _);
+            let state = self.state.as_mut();
+            state.failed = false;
+            state.sent_at.clear();
+            state.metrics.reconnects += 1;
+            for token in self.queue.forget_in_flight() {
+                state.awaiting.remove(&token);
+                state.responses.remove(&token);
+            }
+
+            coap_session_init_token(
+                self.inner.as_ptr(),
+                self.last_token.len,
+                self.last_token.token.as_ptr(),
+            );
+        }
+
+        self.set_ack_timeout(ack_timeout);
+        self.set_ack_random_factor(ack_random_factor);
+        self.set_max_retransmit(max_retransmit);
+
+        Ok(())
+    }
+
+    /// Processes IO until the session is established. Fails if libcoap gives up on the
+    /// connection or the timeout passes first.
+    pub fn wait_until_connected(&mut self, timeout: Duration) -> Result<(), CoapError> {
+        let deadline = Instant::now() + timeout;
+
+        while !self.is_connected() {
+            if self.has_failed() {
+                return Err(CoapError::SessionClosed);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.as_millis() == 0 {
+                return Err(CoapError::Timeout);
+            }
+
+            self.context.process(remaining)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets how long [`CoapSession::request`] waits for a response before giving up
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// Sets ACK_TIMEOUT, the initial time to wait for an acknowledgement of a confirmable message
+    pub fn set_ack_timeout(&mut self, timeout: Duration) {
+        unsafe {
+            coap_session_set_ack_timeout(
+                self.inner.as_ptr(),
+                coap_fixed_point_t {
+                    integer_part: timeout.as_secs() as u16,
+                    fractional_part: timeout.subsec_millis() as u16,
+                },
+            );
+        }
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        unsafe {
+            let timeout = coap_session_get_ack_timeout(self.inner.as_ptr());
+            Duration::from_secs(timeout.integer_part as u64)
+                + Duration::from_millis(timeout.fractional_part as u64)
+        }
+    }
+
+    /// Sets ACK_RANDOM_FACTOR. Must be at least 1.0, values are truncated to 3 decimal places.
+    pub fn set_ack_random_factor(&mut self, factor: f32) {
+        let factor = factor.max(1.0);
+
+        unsafe {
+            coap_session_set_ack_random_factor(
+                self.inner.as_ptr(),
+                coap_fixed_point_t {
+                    integer_part: factor.trunc() as u16,
+                    fractional_part: (factor.fract() * 1000.0) as u16,
+                },
+            );
+        }
+    }
+
+    pub fn ack_random_factor(&self) -> f32 {
+        unsafe {
+            let factor = coap_session_get_ack_random_factor(self.inner.as_ptr());
+            factor.integer_part as f32 + factor.fractional_part as f32 / 1000.0
+        }
+    }
+
+    /// Sets MAX_RETRANSMIT, the number of retransmissions of a confirmable message before giving up
+    pub fn set_max_retransmit(&mut self, max_retransmit: u32) {
+        unsafe {
+            coap_session_set_max_retransmit(self.inner.as_ptr(), max_retransmit);
+        }
+    }
+
+    pub fn max_retransmit(&self) -> u32 {
+        unsafe { coap_session_get_max_retransmit(self.inner.as_ptr()) }
+    }
+
+    pub fn send_pdu(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapToken, CoapError> {
+        let token = self.last_token;
+        let pdu = pdu.with_token(&token).build(self)?;
+
+        unsafe {
+            self.next_token();
+            self.count_sent(token);
+            inspect_pdu(
+                Some(self.state.as_mut()),
+                Direction::Sent,
+                pdu.inner.as_ptr(),
+            );
+            coap_send(self.inner.as_ptr(), pdu.inner.as_ptr());
+            Ok(token)
+        }
+    }
 
+    /// Hands out the current token and moves on to the next one
+    fn next_token(&mut self) -> CoapToken {
+        let token = self.last_token;
         unsafe {
             coap_session_new_token(
                 self.inner.as_ptr(),
                 &mut self.last_token.len,
                 self.last_token.token.as_mut_ptr(),
             );
+        }
+
+        token
+    }
+
+    /// Sets the limits of the outbound queue. Without this, queued requests are sent right away.
+    /// Fails with [`CoapError::InvalidQueueConfig`] if a limit is 0 or the rate isn't positive.
+    pub fn set_queue_config(&mut self, config: QueueConfig) -> Result<(), CoapError> {
+        self.queue.set_config(config)
+    }
+
+    pub fn queue_config(&self) -> QueueConfig {
+        self.queue.config()
+    }
+
+    /// Number of requests waiting in the outbound queue
+    pub fn queued(&self) -> usize {
+        self.queue.waiting_len()
+    }
+
+    /// Number of queued requests that were sent but not answered yet
+    pub fn in_flight(&self) -> usize {
+        self.queue.in_flight_len()
+    }
+
+    /// Queues a request, to be sent within the limits of [`CoapSession::set_queue_config`]
+    /// whenever this session processes IO. Its response is dropped, failures are only logged.
+    /// Requests that aren't answered within the request timeout give up their slot.
+    ///
+    /// Only the session itself sends queued requests: [`CoapSession::flush`], the request
+    /// methods and [`supervised::SupervisedSession::run`] do, [`CoapContext::run`] doesn't. The
+    /// first request goes out right away if the limits allow it.
+    ///
+    /// If a request with the same `key` (e.g. the path of a device) is still waiting, it is
+    /// superseded: JSON payloads are merged into it (`json` feature) and its token is returned.
+    /// Once the queue is full this fails with [`CoapError::QueueFull`], so callers can back off.
+    pub fn enqueue(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapToken, CoapError> {
+        let token = self.push_queued(pdu, key, false)?;
+        self.pump_queue()?;
+
+        Ok(token)
+    }
+
+    /// Like [`CoapSession::request`], but the request goes through the outbound queue. The
+    /// request timeout includes the time spent waiting in the queue.
+    pub fn request_queued(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapResponse, CoapError> {
+        let token = self.push_queued(pdu, key, true)?;
+        self.await_response(token)
+    }
+
+    /// Processes IO until every queued request has been answered or given up on
+    pub fn flush(&mut self, timeout: Duration) -> Result<(), CoapError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let wait = self.pump_queue()?;
+            if self.queue.is_idle() {
+                return Ok(());
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.as_millis() == 0 {
+                return Err(CoapError::Timeout);
+            }
+
+            self.context
+                .process(wait.map_or(remaining, |w| w.min(remaining)))?;
+        }
+    }
+
+    fn push_queued(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+        wait: bool,
+    ) -> Result<CoapToken, CoapError> {
+        let request = pdu.into_stored()?;
+        let token = self.last_token;
+        let queued = self.queue.push(token, key, request, wait)?;
+        if queued == token {
+            self.next_token();
+        }
+
+        Ok(queued)
+    }
+
+    /// Frees the slots of answered and expired requests and sends whatever the limits allow.
+    /// Returns how long until the rate limit lets the next request go.
+    pub(crate) fn pump_queue(&mut self) -> Result<Option<Duration>, CoapError> {
+        let answered: Vec<CoapToken> = unsafe {
+            let state = self.state.as_ref();
+            self.queue
+                .in_flight()
+                .filter(|token| state.responses.contains_key(token))
+                .copied()
+                .collect()
+        };
+        for token in answered {
+            self.complete_queued(token);
+        }
+
+        let now = Instant::now();
+        for token in self.queue.expire(now) {
+            tracing::warn!(
+                token = %hex::encode(token.as_bytes()),
+                "queued request got no response in time"
+            );
+            self.untrack(token);
+        }
+
+        while let Some(queued) = self.queue.pop_ready(now, self.request_timeout) {
+            self.track(queued.token);
+            if let Err(e) = self.resend_pdu(queued.request.builder(), queued.token) {
+                // Handled like an error response, which frees the slot again
+                unsafe {
+                    self.state.as_mut().responses.insert(queued.token, Err(e));
+                }
+                self.complete_queued(queued.token);
+            }
+        }
+
+        Ok(self.queue.time_until_ready(now))
+    }
+
+    /// Frees the slot of a queued request whose response (or failure) is in. Unless a caller
+    /// waits for it, the response is dropped here.
+    fn complete_queued(&mut self, token: CoapToken) {
+        if self.queue.complete(token) {
+            return;
+        }
+
+        let response = self.take_response(token);
+        if let Some(Err(e)) = response.map(|r| r.and_then(CoapResponse::into_result)) {
+            tracing::warn!(
+                token = %hex::encode(token.as_bytes()),
+                error = ?e,
+                "queued request failed"
+            );
+        }
+        self.untrack(token);
+    }
+
+    /// Sends a PDU with a token that was handed out earlier, e.g. to re-register an
+    /// observation after a reconnect
+    pub(crate) fn resend_pdu(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        token: CoapToken,
+    ) -> Result<(), CoapError> {
+        let pdu = pdu.with_token(&token).build(self)?;
+
+        unsafe {
+            self.count_sent(token);
+            inspect_pdu(
+                Some(self.state.as_mut()),
+                Direction::Sent,
+                pdu.inner.as_ptr(),
+            );
             coap_send(self.inner.as_ptr(), pdu.inner.as_ptr());
-            Ok(token)
+            Ok(())
         }
     }
-}
 
-struct CoapDtlsPsk {
-    uri: CoapUri,
-    native: coap_dtls_cpsk_t,
-}
+    /// Appends every PDU sent and every response received from now on to a JSON Lines file,
+    /// which [`recording::Replay`] can serve back later
+    #[cfg(feature = "json")]
+    pub fn start_recording(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), CoapError> {
+        let recorder = Recorder::create(path.as_ref())?;
+        unsafe {
+            self.state.as_mut().recorder = Some(recorder);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    pub fn stop_recording(&mut self) {
+        unsafe {
+            self.state.as_mut().recorder = None;
+        }
+    }
 
-impl CoapDtlsPsk {
-    fn new(uri: CoapUri, identity: &str, key: &str) -> Result<Pin<Box<CoapDtlsPsk>>, CoapError> {
-        if !identity.is_ascii() {
-            return Err(CoapError::IdentityNotAscii);
+    /// Writes every PDU sent and every response received from now on to a pcapng file, as
+    /// plaintext CoAP in synthetic UDP packets. Unlike a capture of the real traffic, this opens
+    /// in Wireshark's CoAP dissector even for DTLS sessions.
+    pub fn start_capture(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), CoapError> {
+        let capture = PcapWriter::create(path.as_ref())?;
+        unsafe {
+            self.state.as_mut().capture = Some(capture);
         }
 
-        if !key.is_ascii() {
-            return Err(CoapError::KeyNotAscii);
+        Ok(())
+    }
+
+    pub fn stop_capture(&mut self) {
+        unsafe {
+            self.state.as_mut().capture = None;
         }
+    }
 
+    /// Calls `inspector` with every PDU sent and every response received, e.g. for a trace
+    /// mode. PDUs are also traced under the `rosthem::pdu` target without an inspector.
+    pub fn set_pdu_inspector(&mut self, inspector: Option<PduInspector>) {
         unsafe {
-            let mut dtls_psk: coap_dtls_cpsk_t = std::mem::zeroed();
+            self.state.as_mut().inspector = inspector;
+        }
+    }
 
-            dtls_psk.version = COAP_DTLS_CPSK_SETUP_VERSION as u8;
+    /// A snapshot of the counters of this session
+    pub fn metrics(&self) -> SessionMetrics {
+        unsafe { self.state.as_ref().metrics.clone() }
+    }
 
-            dtls_psk.validate_ih_call_back = None;
+    unsafe fn count_sent(&mut self, token: CoapToken) {
+        let request_timeout = self.request_timeout;
+        let state = self.state.as_mut();
+        state.metrics.requests_sent += 1;
+        // Requests nobody waits for, e.g. non-confirmable ones, may never be answered
+        state
+            .sent_at
+            .retain(|_, sent_at| sent_at.elapsed() < request_timeout);
+        state.sent_at.insert(token, Instant::now());
+    }
 
-            dtls_psk.psk_info.identity.s = identity.as_ptr();
-            dtls_psk.psk_info.identity.length = identity.len() as c_ulong;
-            dtls_psk.psk_info.key.s = key.as_ptr();
-            dtls_psk.psk_info.key.length = key.len() as c_ulong;
+    /// Sends a request and processes IO until the matching response arrives. Error response
+    /// codes are turned into the corresponding [`CoapError`].
+    pub fn request(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapResponse, CoapError> {
+        let token = self.send_pdu(pdu)?;
+        self.await_response(token)
+    }
 
-            let mut psk = Pin::new(Box::new(CoapDtlsPsk {
-                uri,
-                native: dtls_psk,
-            }));
-            psk.native.ih_call_back_arg = &mut psk.native.psk_info as *mut _ as *mut _;
-            psk.native.client_sni = psk.uri.native.host.s as *mut _;
+    pub(crate) fn rerequest(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        token: CoapToken,
+    ) -> Result<CoapResponse, CoapError> {
+        self.resend_pdu(pdu, token)?;
+        self.await_response(token)
+    }
+
+    pub(crate) fn await_response(&mut self, token: CoapToken) -> Result<CoapResponse, CoapError> {
+        let _span =
+            tracing::debug_span!("exchange", token = %hex::encode(token.as_bytes())).entered();
+
+        self.track(token);
+        let response = self.wait_for_response(token);
+        // A queued request that was sent keeps being tracked, so its late response still frees
+        // the slot it held
+        if !self.queue.abandon(token) {
+            self.untrack(token);
+        }
+
+        match &response {
+            Ok(response) => tracing::debug!(code = %response.code(), "response received"),
+            Err(e) => tracing::debug!(error = ?e, "no response"),
+        }
+
+        response?.into_result()
+    }
+
+    /// Starts collecting the response to `token` so it can be picked up with `take_response`
+    pub(crate) fn track(&mut self, token: CoapToken) {
+        unsafe {
+            self.state.as_mut().awaiting.insert(token);
+        }
+    }
 
-            return Ok(psk);
+    pub(crate) fn untrack(&mut self, token: CoapToken) {
+        unsafe {
+            let state = self.state.as_mut();
+            state.awaiting.remove(&token);
+            state.responses.remove(&token);
+            state.sent_at.remove(&token);
+        }
+    }
+
+    pub(crate) fn take_response(
+        &mut self,
+        token: CoapToken,
+    ) -> Option<Result<CoapResponse, CoapError>> {
+        unsafe { self.state.as_mut().responses.remove(&token) }
+    }
+
+    pub(crate) fn context(&self) -> &Rc<CoapContext> {
+        &self.context
+    }
+
+    fn wait_for_response(&mut self, token: CoapToken) -> Result<CoapResponse, CoapError> {
+        let deadline = Instant::now() + self.request_timeout;
+
+        loop {
+            let wait = self.pump_queue()?;
+            if let Some(response) = self.take_response(token) {
+                return response;
+            }
+
+            if self.has_failed() {
+                return Err(CoapError::SessionClosed);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.as_millis() == 0 {
+                return Err(CoapError::Timeout);
+            }
+
+            self.context
+                .process(wait.map_or(remaining, |w| w.min(remaining)))?;
         }
     }
 }
@@ -265,23 +986,114 @@ impl Clone for CoapUri {
             uri
         }
     }
-}
+}
+
+impl CoapUri {
+    pub fn new(uri: String) -> Result<CoapUri, CoapError> {
+        unsafe {
+            let mut uri = CoapUri {
+                uri,
+                native: std::mem::zeroed(),
+            };
+
+            if coap_split_uri(uri.uri.as_ptr(), uri.uri.len() as c_ulong, &mut uri.native) == 0 {
+                Ok(uri)
+            } else {
+                Err(CoapError::InvalidUri)
+            }
+        }
+    }
+
+    pub fn scheme(&self) -> Result<CoapUriScheme, CoapError> {
+        CoapUriScheme::from_native(self.native.scheme).ok_or(CoapError::InvalidUri)
+    }
+
+    pub fn host(&self) -> Result<&str, CoapError> {
+        unsafe { str_from_native(&self.native.host) }
+    }
+
+    /// The explicit port of the URI, or the default port of its scheme
+    pub fn port(&self) -> u16 {
+        self.native.port
+    }
+
+    /// Resolves the host (an IP literal or a name) to the address the session connects to
+    pub fn socket_addr(&self) -> Result<SocketAddr, CoapError> {
+        let host = self.host()?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+
+        (host, self.port())
+            .to_socket_addrs()
+            .map_err(|_| CoapError::InvalidUri)?
+            .next()
+            .ok_or(CoapError::InvalidUri)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CoapUriScheme {
+    /// `coap://`, plain UDP
+    Coap,
+    /// `coaps://`, DTLS
+    Coaps,
+    /// `coap+tcp://`
+    CoapTcp,
+    /// `coaps+tcp://`, TLS
+    CoapsTcp,
+}
+
+impl CoapUriScheme {
+    #[allow(non_upper_case_globals)]
+    fn from_native(scheme: coap_uri_scheme_t) -> Option<Self> {
+        match scheme {
+            coap_uri_scheme_t_COAP_URI_SCHEME_COAP => Some(CoapUriScheme::Coap),
+            coap_uri_scheme_t_COAP_URI_SCHEME_COAPS => Some(CoapUriScheme::Coaps),
+            coap_uri_scheme_t_COAP_URI_SCHEME_COAP_TCP => Some(CoapUriScheme::CoapTcp),
+            coap_uri_scheme_t_COAP_URI_SCHEME_COAPS_TCP => Some(CoapUriScheme::CoapsTcp),
+            _ => None,
+        }
+    }
 
-impl CoapUri {
-    pub fn new(uri: String) -> Result<CoapUri, CoapError> {
-        unsafe {
-            let mut uri = CoapUri {
-                uri,
-                native: std::mem::zeroed(),
-            };
+    pub fn is_secure(&self) -> bool {
+        matches!(self, CoapUriScheme::Coaps | CoapUriScheme::CoapsTcp)
+    }
 
-            if coap_split_uri(uri.uri.as_ptr(), uri.uri.len() as c_ulong, &mut uri.native) == 0 {
-                Ok(uri)
-            } else {
-                Err(CoapError::InvalidUri)
+    pub fn is_reliable(&self) -> bool {
+        matches!(self, CoapUriScheme::CoapTcp | CoapUriScheme::CoapsTcp)
+    }
+
+    /// Whether the linked libcoap was built with support for this transport
+    pub fn is_supported(&self) -> bool {
+        unsafe {
+            match self {
+                CoapUriScheme::Coap => true,
+                CoapUriScheme::Coaps => coap_dtls_is_supported() != 0,
+                CoapUriScheme::CoapTcp => coap_tcp_is_supported() != 0,
+                CoapUriScheme::CoapsTcp => coap_tls_is_supported() != 0,
             }
         }
     }
+
+    fn proto(&self) -> coap_proto_t {
+        match self {
+            CoapUriScheme::Coap => coap_proto_t_COAP_PROTO_UDP,
+            CoapUriScheme::Coaps => coap_proto_t_COAP_PROTO_DTLS,
+            CoapUriScheme::CoapTcp => coap_proto_t_COAP_PROTO_TCP,
+            CoapUriScheme::CoapsTcp => coap_proto_t_COAP_PROTO_TLS,
+        }
+    }
+}
+
+unsafe fn str_from_native(native: &coap_str_const_t) -> Result<&str, CoapError> {
+    if native.s.is_null() {
+        return Ok("");
+    }
+
+    std::str::from_utf8(std::slice::from_raw_parts(native.s, native.length as usize))
+        .map_err(|_| CoapError::InvalidUri)
 }
 
 pub struct CoapAddress {
@@ -289,19 +1101,35 @@ pub struct CoapAddress {
 }
 
 impl CoapAddress {
-    // TODO: Support IPv6
+    /// The address of a Tradfri gateway on the default CoAPS port
     pub fn new(ip: Ipv4Addr) -> CoapAddress {
-        let b = ip.octets();
+        CoapAddress::from(SocketAddr::new(ip.into(), 5684))
+    }
+}
 
-        CoapAddress {
-            native: unsafe {
-                std::mem::transmute([
+impl From<SocketAddr> for CoapAddress {
+    fn from(addr: SocketAddr) -> Self {
+        let p = addr.port().to_be_bytes();
+        let mut bytes = [0u8; 32];
+
+        match addr {
+            SocketAddr::V4(addr) => {
+                let b = addr.ip().octets();
+                bytes[..12].copy_from_slice(&[
                     //                                   PORT HL ---  IP -------------------
-                    0x10, 0x00, 0x00, 0x00, 0x02, 0x00, 0x16, 0x34, b[0], b[1], b[2], b[3], 0x00,
-                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00, 0x00, 0x00, 0xE0, 0x00,
-                    0x00, 0x00, 0x06, 0x00, 0x00, 0x00u8,
-                ])
-            },
+                    0x10, 0x00, 0x00, 0x00, 0x02, 0x00, p[0], p[1], b[0], b[1], b[2], b[3],
+                ]);
+            }
+            SocketAddr::V6(addr) => {
+                bytes[..8].copy_from_slice(&[0x1C, 0x00, 0x00, 0x00, 0x0A, 0x00, p[0], p[1]]);
+                bytes[8..12].copy_from_slice(&addr.flowinfo().to_be_bytes());
+                bytes[12..28].copy_from_slice(&addr.ip().octets());
+                bytes[28..32].copy_from_slice(&addr.scope_id().to_ne_bytes());
+            }
+        }
+
+        CoapAddress {
+            native: unsafe { std::mem::transmute::<[u8; 32], coap_address_t>(bytes) },
         }
     }
 }
@@ -328,61 +1156,184 @@ impl CoapOptList {
     }
 
     pub fn add_uri_path_segments(&self, uri: &CoapUri) -> Result<(), CoapError> {
+        self.add_split_segments(
+            COAP_OPTION_URI_PATH as u16,
+            &uri.native.path,
+            coap_split_path,
+        )
+    }
+
+    pub fn add_uri_query_segments(&self, uri: &CoapUri) -> Result<(), CoapError> {
+        self.add_split_segments(
+            COAP_OPTION_URI_QUERY as u16,
+            &uri.native.query,
+            coap_split_query,
+        )
+    }
+
+    pub fn add_path_segment(&self, segment: &str) -> Result<(), CoapError> {
+        self.add_option(COAP_OPTION_URI_PATH as u16, segment.as_bytes())
+    }
+
+    /// Adds a single query argument, e.g. `"key=value"`
+    pub fn add_uri_query(&self, argument: &str) -> Result<(), CoapError> {
+        self.add_option(COAP_OPTION_URI_QUERY as u16, argument.as_bytes())
+    }
+
+    pub fn add_uri_host(&self, host: &str) -> Result<(), CoapError> {
+        self.add_option(COAP_OPTION_URI_HOST as u16, host.as_bytes())
+    }
+
+    pub fn add_uri_port(&self, port: u16) -> Result<(), CoapError> {
+        self.add_uint_option(COAP_OPTION_URI_PORT as u16, port as u32)
+    }
+
+    pub fn add_content_format(&self, content_format: CoapContentFormat) -> Result<(), CoapError> {
+        self.add_uint_option(
+            COAP_OPTION_CONTENT_FORMAT as u16,
+            u16::from(content_format) as u32,
+        )
+    }
+
+    pub fn add_accept(&self, content_format: CoapContentFormat) -> Result<(), CoapError> {
+        self.add_uint_option(COAP_OPTION_ACCEPT as u16, u16::from(content_format) as u32)
+    }
+
+    pub fn add_if_match(&self, etag: &[u8]) -> Result<(), CoapError> {
+        if etag.len() > 8 {
+            return Err(CoapError::InvalidOptionValue);
+        }
+
+        self.add_option(COAP_OPTION_IF_MATCH as u16, etag)
+    }
+
+    pub fn add_if_none_match(&self) -> Result<(), CoapError> {
+        self.add_option(COAP_OPTION_IF_NONE_MATCH as u16, &[])
+    }
+
+    pub fn add_etag(&self, etag: &[u8]) -> Result<(), CoapError> {
+        if etag.is_empty() || etag.len() > 8 {
+            return Err(CoapError::InvalidOptionValue);
+        }
+
+        self.add_option(COAP_OPTION_ETAG as u16, etag)
+    }
+
+    pub fn add_observe(&self, value: u32) -> Result<(), CoapError> {
+        self.add_uint_option(COAP_OPTION_OBSERVE as u16, value)
+    }
+
+    pub fn add_max_age(&self, seconds: u32) -> Result<(), CoapError> {
+        self.add_uint_option(COAP_OPTION_MAXAGE as u16, seconds)
+    }
+
+    pub fn add_size1(&self, size: u32) -> Result<(), CoapError> {
+        self.add_uint_option(COAP_OPTION_SIZE1 as u16, size)
+    }
+
+    pub fn add_size2(&self, size: u32) -> Result<(), CoapError> {
+        self.add_uint_option(COAP_OPTION_SIZE2 as u16, size)
+    }
+
+    pub fn add_proxy_uri(&self, uri: &str) -> Result<(), CoapError> {
+        self.add_option(COAP_OPTION_PROXY_URI as u16, uri.as_bytes())
+    }
+
+    /// Adds an option with an already encoded value. Use this for options without a typed adder.
+    pub fn add_option(&self, number: u16, value: &[u8]) -> Result<(), CoapError> {
+        unsafe {
+            let option = coap_new_optlist(number, value.len() as c_ulong, value.as_ptr());
+            if option.is_null() {
+                return Err(CoapError::InvalidOptionValue);
+            }
+
+            if coap_insert_optlist(&self.inner as *const _ as *mut _, option) == 1 {
+                Ok(())
+            } else {
+                Err(CoapError::FailedToAddOption)
+            }
+        }
+    }
+
+    /// Adds an option with uint encoding (big-endian without leading zero bytes)
+    pub fn add_uint_option(&self, number: u16, value: u32) -> Result<(), CoapError> {
+        let mut buf = [0u8; 4];
+        self.add_option(number, encode_uint(value, &mut buf))
+    }
+
+    pub fn contains(&self, number: u16) -> bool {
+        self.options().any(|option| option.number == number)
+    }
+
+    fn options(&self) -> impl Iterator<Item = &coap_optlist_t> {
+        let mut next = self.inner;
+        std::iter::from_fn(move || unsafe {
+            let option = next.as_ref()?;
+            next = option.next;
+            Some(option)
+        })
+    }
+
+    fn extend_from(&self, other: &CoapOptList) -> Result<(), CoapError> {
+        self.extend_from_except(other, &[])
+    }
+
+    fn extend_from_except(&self, other: &CoapOptList, except: &[u16]) -> Result<(), CoapError> {
+        for option in other.options().filter(|o| !except.contains(&o.number)) {
+            self.add_option(option.number, unsafe {
+                std::slice::from_raw_parts(option.data, option.length as usize)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn add_split_segments(
+        &self,
+        number: u16,
+        s: &coap_str_const_t,
+        split: unsafe extern "C" fn(*const u8, c_ulong, *mut u8, *mut c_ulong) -> i32,
+    ) -> Result<(), CoapError> {
         unsafe {
-            if uri.native.path.length > 0 {
-                let mut uri_path_buf_len = [0u8; 256]; // PERF: Uninitialized
+            if s.length > 0 {
+                let mut segment_buf = [0u8; 256]; // PERF: Uninitialized
 
-                if uri.native.path.length > uri_path_buf_len.len() as c_ulong {
+                if s.length > segment_buf.len() as c_ulong {
                     return Err(CoapError::UriTooLong);
                 }
 
-                let mut _used_buf_len = uri_path_buf_len.len() as c_ulong;
-                let path_segment_count = coap_split_path(
-                    uri.native.path.s,
-                    uri.native.path.length,
-                    uri_path_buf_len.as_mut_ptr(),
-                    &mut _used_buf_len,
-                );
+                let mut _used_buf_len = segment_buf.len() as c_ulong;
+                let segment_count =
+                    split(s.s, s.length, segment_buf.as_mut_ptr(), &mut _used_buf_len);
 
                 let mut buf_write_offset = 0usize;
-                let mut writable_buf = &mut uri_path_buf_len[..];
-                for _ in 0..path_segment_count {
-                    // println!("Path segment: {:?}", std::str::from_utf8_unchecked(std::slice::from_raw_parts(coap_opt_value(writable_buf.as_mut_ptr()), coap_opt_length(writable_buf.as_mut_ptr()) as usize)));
-
-                    coap_insert_optlist(
-                        &self.inner as *const _ as *mut _,
-                        coap_new_optlist(
-                            COAP_OPTION_URI_PATH as u16,
-                            coap_opt_length(writable_buf.as_mut_ptr()) as c_ulong,
+                let mut writable_buf = &mut segment_buf[..];
+                for _ in 0..segment_count {
+                    self.add_option(
+                        number,
+                        std::slice::from_raw_parts(
                             coap_opt_value(writable_buf.as_mut_ptr()),
+                            coap_opt_length(writable_buf.as_mut_ptr()) as usize,
                         ),
-                    );
+                    )?;
 
                     buf_write_offset += coap_opt_size(writable_buf.as_mut_ptr()) as usize;
-                    writable_buf = &mut uri_path_buf_len[buf_write_offset..];
+                    writable_buf = &mut segment_buf[buf_write_offset..];
                 }
             }
 
             Ok(())
         }
     }
+}
 
-    pub fn add_path_segment(&self, segment: &str) -> Result<(), CoapError> {
-        unsafe {
-            coap_insert_optlist(
-                &self.inner as *const _ as *mut _,
-                coap_new_optlist(
-                    COAP_OPTION_URI_PATH as u16,
-                    segment.len() as c_ulong,
-                    segment.as_ptr(),
-                ),
-            );
-            Ok(())
-        }
-    }
+/// Encodes a uint option value: big-endian without leading zero bytes, so 0 is empty
+pub(crate) fn encode_uint(value: u32, buf: &mut [u8; 4]) -> &[u8] {
+    *buf = value.to_be_bytes();
+    &buf[value.leading_zeros() as usize / 8..]
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub struct CoapToken {
     len: c_ulong,
     token: [u8; 8],
@@ -411,6 +1362,10 @@ impl CoapToken {
             token: [0; 8],
         }
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.token[..self.len as usize]
+    }
 }
 
 #[repr(u32)]
@@ -425,6 +1380,154 @@ pub enum CoapMethod {
     Ipatch = 7,
 }
 
+impl CoapMethod {
+    fn from_code(code: u32) -> Option<CoapMethod> {
+        match code {
+            1 => Some(CoapMethod::Get),
+            2 => Some(CoapMethod::Post),
+            3 => Some(CoapMethod::Put),
+            4 => Some(CoapMethod::Delete),
+            5 => Some(CoapMethod::Fetch),
+            6 => Some(CoapMethod::Patch),
+            7 => Some(CoapMethod::Ipatch),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CoapMessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum CoapNackReason {
+    TooManyRetries,
+    NotDeliverable,
+    Reset,
+    TlsFailed,
+    IcmpIssue,
+    Unknown,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CoapEvent {
+    DtlsConnected,
+    DtlsClosed,
+    DtlsRenegotiate,
+    DtlsError,
+    TcpConnected,
+    TcpClosed,
+    TcpFailed,
+    SessionConnected,
+    SessionClosed,
+    SessionFailed,
+    PartialBlock,
+    /// The peer pinged us (answered by libcoap)
+    Ping,
+    /// A keepalive ping was answered
+    Pong,
+    /// A keepalive ping went unanswered
+    KeepaliveFailed(CoapNackReason),
+    Unknown(u32),
+}
+
+impl From<coap_event_t> for CoapEvent {
+    #[allow(non_upper_case_globals)]
+    fn from(event: coap_event_t) -> Self {
+        match event {
+            coap_event_t_COAP_EVENT_DTLS_CONNECTED => CoapEvent::DtlsConnected,
+            coap_event_t_COAP_EVENT_DTLS_CLOSED => CoapEvent::DtlsClosed,
+            coap_event_t_COAP_EVENT_DTLS_RENEGOTIATE => CoapEvent::DtlsRenegotiate,
+            coap_event_t_COAP_EVENT_DTLS_ERROR => CoapEvent::DtlsError,
+            coap_event_t_COAP_EVENT_TCP_CONNECTED => CoapEvent::TcpConnected,
+            coap_event_t_COAP_EVENT_TCP_CLOSED => CoapEvent::TcpClosed,
+            coap_event_t_COAP_EVENT_TCP_FAILED => CoapEvent::TcpFailed,
+            coap_event_t_COAP_EVENT_SESSION_CONNECTED => CoapEvent::SessionConnected,
+            coap_event_t_COAP_EVENT_SESSION_CLOSED => CoapEvent::SessionClosed,
+            coap_event_t_COAP_EVENT_SESSION_FAILED => CoapEvent::SessionFailed,
+            coap_event_t_COAP_EVENT_PARTIAL_BLOCK => CoapEvent::PartialBlock,
+            _ => CoapEvent::Unknown(event),
+        }
+    }
+}
+
+impl CoapEvent {
+    /// Whether the session can no longer be used after this event
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            CoapEvent::DtlsClosed
+                | CoapEvent::DtlsError
+                | CoapEvent::TcpClosed
+                | CoapEvent::TcpFailed
+                | CoapEvent::SessionClosed
+                | CoapEvent::SessionFailed
+                | CoapEvent::KeepaliveFailed(_)
+        )
+    }
+}
+
+impl From<coap_nack_reason_t> for CoapNackReason {
+    #[allow(non_upper_case_globals)]
+    fn from(reason: coap_nack_reason_t) -> Self {
+        match reason {
+            coap_nack_reason_t_COAP_NACK_TOO_MANY_RETRIES => CoapNackReason::TooManyRetries,
+            coap_nack_reason_t_COAP_NACK_NOT_DELIVERABLE => CoapNackReason::NotDeliverable,
+            coap_nack_reason_t_COAP_NACK_RST => CoapNackReason::Reset,
+            coap_nack_reason_t_COAP_NACK_TLS_FAILED => CoapNackReason::TlsFailed,
+            coap_nack_reason_t_COAP_NACK_ICMP_ISSUE => CoapNackReason::IcmpIssue,
+            _ => CoapNackReason::Unknown,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CoapContentFormat {
+    TextPlain,
+    LinkFormat,
+    Xml,
+    OctetStream,
+    Exi,
+    Json,
+    Cbor,
+    Other(u16),
+}
+
+impl From<CoapContentFormat> for u16 {
+    fn from(content_format: CoapContentFormat) -> Self {
+        match content_format {
+            CoapContentFormat::TextPlain => COAP_MEDIATYPE_TEXT_PLAIN as u16,
+            CoapContentFormat::LinkFormat => COAP_MEDIATYPE_APPLICATION_LINK_FORMAT as u16,
+            CoapContentFormat::Xml => COAP_MEDIATYPE_APPLICATION_XML as u16,
+            CoapContentFormat::OctetStream => COAP_MEDIATYPE_APPLICATION_OCTET_STREAM as u16,
+            CoapContentFormat::Exi => COAP_MEDIATYPE_APPLICATION_EXI as u16,
+            CoapContentFormat::Json => COAP_MEDIATYPE_APPLICATION_JSON as u16,
+            CoapContentFormat::Cbor => COAP_MEDIATYPE_APPLICATION_CBOR as u16,
+            CoapContentFormat::Other(value) => value,
+        }
+    }
+}
+
+impl From<u16> for CoapContentFormat {
+    fn from(value: u16) -> Self {
+        match value as u32 {
+            COAP_MEDIATYPE_TEXT_PLAIN => CoapContentFormat::TextPlain,
+            COAP_MEDIATYPE_APPLICATION_LINK_FORMAT => CoapContentFormat::LinkFormat,
+            COAP_MEDIATYPE_APPLICATION_XML => CoapContentFormat::Xml,
+            COAP_MEDIATYPE_APPLICATION_OCTET_STREAM => CoapContentFormat::OctetStream,
+            COAP_MEDIATYPE_APPLICATION_EXI => CoapContentFormat::Exi,
+            COAP_MEDIATYPE_APPLICATION_JSON => CoapContentFormat::Json,
+            COAP_MEDIATYPE_APPLICATION_CBOR => CoapContentFormat::Cbor,
+            _ => CoapContentFormat::Other(value),
+        }
+    }
+}
+
 struct CoapPdu {
     inner: NonNull<coap_pdu_t>,
 }
@@ -439,10 +1542,14 @@ struct CoapPdu {
 // }
 
 impl CoapPdu {
-    fn new(session: &CoapSession, method: CoapMethod) -> Result<CoapPdu, CoapError> {
+    fn new(
+        session: &CoapSession,
+        message_type: CoapMessageType,
+        method: CoapMethod,
+    ) -> Result<CoapPdu, CoapError> {
         unsafe {
             NonNull::new(coap_new_pdu(
-                coap_pdu_type_t_COAP_MESSAGE_CON,
+                message_type as u32,
                 method as u32,
                 session.inner.as_ptr(),
             ))
@@ -465,16 +1572,9 @@ impl CoapPdu {
         }
     }
 
-    fn add_payload<P: Serialize>(
-        &mut self,
-        payload: P,
-        session: &CoapSession,
-    ) -> Result<(), CoapError> {
+    fn add_payload(&mut self, payload: Vec<u8>, session: &CoapSession) -> Result<(), CoapError> {
         unsafe {
-            let payload = serde_json::to_string(&payload)
-                .map(|json| Box::new(json.into_bytes()))
-                .map_err(|_| CoapError::SerializeError)?;
-
+            let payload = Box::new(payload);
             let payload_ptr = payload.as_ptr();
             let payload_len = payload.len();
             let payload_vec = Box::into_raw(payload);
@@ -492,100 +1592,234 @@ impl CoapPdu {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct CoapPduBuilder<'a, P> {
+pub struct CoapPduBuilder<'a> {
+    message_type: CoapMessageType,
     method: CoapMethod,
     token: Option<&'a CoapToken>,
     optlist: Option<&'a CoapOptList>,
-    payload: Option<P>,
+    content_format: Option<CoapContentFormat>,
+    payload: Option<Result<Cow<'a, [u8]>, CoapError>>,
 }
 
-impl<'a> CoapPduBuilder<'a, ()> {
-    pub fn new(method: CoapMethod) -> CoapPduBuilder<'a, ()> {
+impl<'a> CoapPduBuilder<'a> {
+    pub fn new(method: CoapMethod) -> CoapPduBuilder<'a> {
         CoapPduBuilder {
+            message_type: CoapMessageType::Confirmable,
             method,
             token: None,
             optlist: None,
+            content_format: None,
             payload: None,
         }
     }
-}
 
-impl<'a, P> CoapPduBuilder<'a, P> {
-    fn with_token(mut self, token: &'a CoapToken) -> CoapPduBuilder<'a, P> {
+    fn with_token(mut self, token: &'a CoapToken) -> CoapPduBuilder<'a> {
         self.token = Some(token);
         self
     }
 
-    pub fn with_optlist(mut self, optlist: &'a CoapOptList) -> CoapPduBuilder<'a, P> {
+    pub fn with_message_type(mut self, message_type: CoapMessageType) -> CoapPduBuilder<'a> {
+        self.message_type = message_type;
+        self
+    }
+
+    pub fn with_optlist(mut self, optlist: &'a CoapOptList) -> CoapPduBuilder<'a> {
         self.optlist = Some(optlist);
         self
     }
 
-    pub fn with_payload<Q: Serialize>(self, payload: Q) -> CoapPduBuilder<'a, Q> {
-        CoapPduBuilder {
-            method: self.method,
-            token: self.token,
-            optlist: self.optlist,
-            payload: Some(payload),
+    /// Encodes the payload as JSON
+    #[cfg(feature = "json")]
+    pub fn with_payload<P: Serialize>(self, payload: P) -> CoapPduBuilder<'a> {
+        self.with_encoded_payload::<JsonCodec, P>(payload)
+    }
+
+    pub fn with_encoded_payload<C: PayloadCodec, P: Serialize>(
+        mut self,
+        payload: P,
+    ) -> CoapPduBuilder<'a> {
+        self.content_format = Some(C::CONTENT_FORMAT);
+        self.payload = Some(C::encode(&payload).map(Cow::Owned));
+        self
+    }
+
+    pub fn with_raw_payload(
+        mut self,
+        payload: &'a [u8],
+        content_format: CoapContentFormat,
+    ) -> CoapPduBuilder<'a> {
+        self.content_format = Some(content_format);
+        self.payload = Some(Ok(Cow::Borrowed(payload)));
+        self
+    }
+
+    pub(crate) fn into_stored(self) -> Result<StoredRequest, CoapError> {
+        let optlist = CoapOptList::new();
+        if let Some(other) = self.optlist {
+            optlist.extend_from(other)?;
         }
+
+        Ok(StoredRequest {
+            message_type: self.message_type,
+            method: self.method,
+            optlist,
+            content_format: self.content_format,
+            payload: self
+                .payload
+                .transpose()?
+                .map(|payload| payload.into_owned()),
+        })
     }
 
-    fn build(self, session: &CoapSession) -> Result<CoapPdu, CoapError>
-    where
-        P: Serialize,
-    {
-        let mut pdu = CoapPdu::new(session, self.method)?;
+    fn build(self, session: &CoapSession) -> Result<CoapPdu, CoapError> {
+        let mut pdu = CoapPdu::new(session, self.message_type, self.method)?;
         if let Some(token) = self.token {
             pdu.add_token(token)?;
         }
-        if let Some(optlist) = self.optlist {
-            pdu.add_optlist(optlist)?;
+        match (self.optlist, self.content_format) {
+            (Some(optlist), Some(content_format))
+                if !optlist.contains(COAP_OPTION_CONTENT_FORMAT as u16) =>
+            {
+                let merged = CoapOptList::new();
+                merged.extend_from(optlist)?;
+                merged.add_content_format(content_format)?;
+                pdu.add_optlist(&merged)?;
+            }
+            (None, Some(content_format)) => {
+                let optlist = CoapOptList::new();
+                optlist.add_content_format(content_format)?;
+                pdu.add_optlist(&optlist)?;
+            }
+            (Some(optlist), _) => pdu.add_optlist(optlist)?,
+            (None, None) => {}
         }
         if let Some(payload) = self.payload {
-            pdu.add_payload(payload, session)?;
+            pdu.add_payload(payload?.into_owned(), session)?;
         }
         Ok(pdu)
     }
 }
 
-static mut USER_RESPONSE_HANDLER: Option<Box<dyn Fn(CoapToken, String)>> = None;
+/// An owned copy of a request, so it can be sent again later (e.g. after a reconnect)
+pub(crate) struct StoredRequest {
+    message_type: CoapMessageType,
+    method: CoapMethod,
+    optlist: CoapOptList,
+    content_format: Option<CoapContentFormat>,
+    payload: Option<Vec<u8>>,
+}
+
+impl StoredRequest {
+    pub(crate) fn builder(&self) -> CoapPduBuilder<'_> {
+        CoapPduBuilder {
+            message_type: self.message_type,
+            method: self.method,
+            token: None,
+            optlist: Some(&self.optlist),
+            content_format: self.content_format,
+            payload: self
+                .payload
+                .as_ref()
+                .map(|payload| Ok(Cow::Borrowed(&payload[..]))),
+        }
+    }
+
+    /// Copies the request, replacing any Observe option with `observe`
+    pub(crate) fn with_observe(&self, observe: u32) -> Result<StoredRequest, CoapError> {
+        let optlist = CoapOptList::new();
+        optlist.extend_from_except(&self.optlist, &[COAP_OPTION_OBSERVE as u16])?;
+        optlist.add_observe(observe)?;
+
+        Ok(StoredRequest {
+            message_type: self.message_type,
+            method: self.method,
+            optlist,
+            content_format: self.content_format,
+            payload: self.payload.clone(),
+        })
+    }
+}
+
+pub type PduInspector = Box<dyn FnMut(Direction, &CoapPduInfo)>;
+
+/// Hands a PDU to the recorder, capture and inspector of its session and traces it
+pub(crate) unsafe fn inspect_pdu(
+    state: Option<&mut SessionState>,
+    direction: Direction,
+    pdu: *const coap_pdu_t,
+) {
+    let traced = tracing::enabled!(target: "rosthem::pdu", tracing::Level::TRACE);
+    let state = state.filter(|state| state.inspects_pdus());
+    if !traced && state.is_none() {
+        return;
+    }
+
+    let info = CoapPduInfo::from_native(pdu);
+    if traced {
+        tracing::trace!(target: "rosthem::pdu", ?direction, "{}", info);
+    }
+    if let Some(state) = state {
+        #[cfg(feature = "json")]
+        if let Some(recorder) = &mut state.recorder {
+            recorder.record(direction, &info);
+        }
+        if let Some(capture) = &mut state.capture {
+            capture.write(direction, &info);
+        }
+        if let Some(inspector) = &mut state.inspector {
+            inspector(direction, &info);
+        }
+    }
+}
+
+static mut USER_RESPONSE_HANDLER: Option<Box<dyn Fn(CoapResponse)>> = None;
+
+fn set_response_handler(handle_response: Option<Box<dyn Fn(CoapResponse)>>) {
+    unsafe {
+        USER_RESPONSE_HANDLER = handle_response;
+    }
+}
 
 unsafe extern "C" fn handle_response(
-    _session: *mut coap_session_t,
+    session: *mut coap_session_t,
     _sent: *const coap_pdu_t,
     received: *const coap_pdu_t,
     _mid: coap_mid_t,
 ) -> coap_response_t {
-    // let _rcv_code = coap_pdu_get_code(received);
     // let _rcv_type = coap_pdu_get_type(received);
 
-    if let Some(user_response_handler) = &USER_RESPONSE_HANDLER {
-        let token = CoapToken::from(coap_pdu_get_token(received));
-
-        let mut data_len: c_ulong = 0;
-        let mut data_ptr = ptr::null();
-        let mut data_offset: c_ulong = 0;
-        let mut data_total: c_ulong = 0;
-
-        if coap_get_data_large(
-            received,
-            &mut data_len,
-            &mut data_ptr,
-            &mut data_offset,
-            &mut data_total,
-        ) == 1
-        {
-            user_response_handler(
-                token,
-                String::from_utf8_lossy(std::slice::from_raw_parts(data_ptr, data_len as usize))
-                    .into_owned(),
-            );
+    let user_response_handler = &USER_RESPONSE_HANDLER;
+    let mut state = (coap_session_get_app_data(session) as *mut SessionState).as_mut();
+    inspect_pdu(state.as_deref_mut(), Direction::Received, received);
+    let token = CoapToken::from(coap_pdu_get_token(received));
+    if let Some(state) = state.as_deref_mut() {
+        state.metrics.responses_received += 1;
+        if let Some(sent_at) = state.sent_at.remove(&token) {
+            state.metrics.response_latency.observe(sent_at.elapsed());
+        }
+    }
+    let awaited = state
+        .as_ref()
+        .map(|state| state.awaiting.contains(&token))
+        .unwrap_or(false);
+
+    if awaited || user_response_handler.is_some() {
+        let response = CoapResponse {
+            token,
+            code: CoapResponseCode::from(coap_pdu_get_code(received)),
+            content_format: pdu_content_format(received),
+            payload: pdu_payload(received),
+        };
+
+        if let Some(state) = state.filter(|_| awaited) {
+            state.responses.insert(token, Ok(response.clone()));
+        }
+
+        if let Some(user_response_handler) = user_response_handler {
+            user_response_handler(response);
         }
     }
 
-    // println!("A got something");
-    // coap_show_pdu(coap_log_t_LOG_DEBUG, received);
     return coap_response_t_COAP_RESPONSE_OK;
 
     // coap_log(LOG_DEBUG, "** process incoming %d.%02d response:\n",
@@ -673,9 +1907,11 @@ unsafe extern "C" fn handle_response(
 }
 
 unsafe extern "C" fn handle_event(
-    _session: *mut coap_session_t,
-    _event: coap_event_t,
+    session: *mut coap_session_t,
+    event: coap_event_t,
 ) -> ::std::os::raw::c_int {
+    dispatch_event(session, CoapEvent::from(event));
+
     // match event {
     //     coap_event_t_COAP_EVENT_SESSION_CLOSED => {
     //         println!("coap_event_t_COAP_EVENT_SESSION_CLOSED")
@@ -704,12 +1940,53 @@ unsafe extern "C" fn handle_event(
     return 0;
 }
 
+#[allow(non_upper_case_globals)]
 unsafe extern "C" fn handle_nack(
-    _session: *mut coap_session_t,
-    _sent: *const coap_pdu_t,
-    _reason: coap_nack_reason_t,
+    session: *mut coap_session_t,
+    sent: *const coap_pdu_t,
+    reason: coap_nack_reason_t,
     _mid: coap_mid_t,
 ) {
+    // Keepalive pings are empty confirmable messages over UDP and 7.02 signalling over TCP
+    if !sent.is_null()
+        && matches!(
+            coap_pdu_get_code(sent),
+            0 | coap_pdu_code_t_COAP_SIGNALING_CODE_PING
+        )
+    {
+        dispatch_event(
+            session,
+            CoapEvent::KeepaliveFailed(CoapNackReason::from(reason)),
+        );
+        return;
+    }
+
+    if let Some(state) = (coap_session_get_app_data(session) as *mut SessionState).as_mut() {
+        *state
+            .metrics
+            .nacks
+            .entry(CoapNackReason::from(reason))
+            .or_default() += 1;
+
+        if reason == coap_nack_reason_t_COAP_NACK_TOO_MANY_RETRIES {
+            state.metrics.retransmissions += coap_session_get_max_retransmit(session) as u64;
+        }
+
+        if reason == coap_nack_reason_t_COAP_NACK_TLS_FAILED {
+            state.failed = true;
+        }
+
+        if !sent.is_null() {
+            let token = CoapToken::from(coap_pdu_get_token(sent));
+            state.sent_at.remove(&token);
+            if state.awaiting.contains(&token) {
+                state
+                    .responses
+                    .insert(token, Err(CoapError::Nack(CoapNackReason::from(reason))));
+            }
+        }
+    }
+
     // match reason {
     //     coap_nack_reason_t_COAP_NACK_TLS_FAILED => {
     //         println!("coap_nack_reason_t_COAP_NACK_TLS_FAILED")
@@ -728,9 +2005,135 @@ unsafe extern "C" fn handle_nack(
     // }
 }
 
-unsafe extern "C" fn drop_boxed_slice(
+unsafe extern "C" fn handle_ping(
+    session: *mut coap_session_t,
+    _received: *const coap_pdu_t,
+    _mid: coap_mid_t,
+) {
+    dispatch_event(session, CoapEvent::Ping);
+}
+
+unsafe extern "C" fn handle_pong(
+    session: *mut coap_session_t,
+    _received: *const coap_pdu_t,
+    _mid: coap_mid_t,
+) {
+    dispatch_event(session, CoapEvent::Pong);
+}
+
+static mut USER_EVENT_HANDLER: Option<Box<dyn Fn(CoapEvent)>> = None;
+
+unsafe fn dispatch_event(session: *mut coap_session_t, event: CoapEvent) {
+    if event.is_failure() {
+        if let Some(state) = (coap_session_get_app_data(session) as *mut SessionState).as_mut() {
+            state.failed = true;
+        }
+    }
+
+    if let Some(user_event_handler) = &*ptr::addr_of!(USER_EVENT_HANDLER) {
+        user_event_handler(event);
+    }
+}
+
+pub(crate) unsafe fn pdu_content_format(pdu: *const coap_pdu_t) -> Option<CoapContentFormat> {
+    let mut opt_iter: coap_opt_iterator_t = std::mem::zeroed();
+    let content_format_opt =
+        coap_check_option(pdu, COAP_OPTION_CONTENT_FORMAT as u16, &mut opt_iter);
+
+    if content_format_opt.is_null() {
+        None
+    } else {
+        Some(CoapContentFormat::from(coap_decode_var_bytes(
+            coap_opt_value(content_format_opt),
+            coap_opt_length(content_format_opt) as c_ulong,
+        ) as u16))
+    }
+}
+
+pub(crate) unsafe fn pdu_options(pdu: *const coap_pdu_t) -> Vec<(u16, Vec<u8>)> {
+    let mut options = Vec::new();
+    let mut opt_iter: coap_opt_iterator_t = std::mem::zeroed();
+    coap_option_iterator_init(pdu, &mut opt_iter, ptr::null());
+
+    loop {
+        let option = coap_option_next(&mut opt_iter);
+        if option.is_null() {
+            return options;
+        }
+
+        options.push((
+            opt_iter.number,
+            std::slice::from_raw_parts(coap_opt_value(option), coap_opt_length(option) as usize)
+                .to_vec(),
+        ));
+    }
+}
+
+pub(crate) unsafe fn pdu_payload(pdu: *const coap_pdu_t) -> Vec<u8> {
+    let mut data_len: c_ulong = 0;
+    let mut data_ptr = ptr::null();
+    let mut data_offset: c_ulong = 0;
+    let mut data_total: c_ulong = 0;
+
+    if coap_get_data_large(
+        pdu,
+        &mut data_len,
+        &mut data_ptr,
+        &mut data_offset,
+        &mut data_total,
+    ) == 1
+    {
+        std::slice::from_raw_parts(data_ptr, data_len as usize).to_vec()
+    } else {
+        Vec::new()
+    }
+}
+
+pub(crate) unsafe extern "C" fn drop_boxed_slice(
     _session: *mut coap_session_t,
     app_ptr: *mut ::std::os::raw::c_void,
 ) {
     drop(Box::<Vec<u8>>::from_raw(app_ptr as _));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_uint_drops_leading_zero_bytes() {
+        let cases: [(u32, &[u8]); 7] = [
+            (0, &[]),
+            (1, &[0x01]),
+            (0xFF, &[0xFF]),
+            (0x100, &[0x01, 0x00]),
+            (60, &[0x3C]),
+            (0x01_0000, &[0x01, 0x00, 0x00]),
+            (u32::MAX, &[0xFF, 0xFF, 0xFF, 0xFF]),
+        ];
+
+        for (value, expected) in cases {
+            let mut buf = [0u8; 4];
+            assert_eq!(encode_uint(value, &mut buf), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn content_format_maps_to_media_type() {
+        let cases = [
+            (CoapContentFormat::TextPlain, 0),
+            (CoapContentFormat::LinkFormat, 40),
+            (CoapContentFormat::Xml, 41),
+            (CoapContentFormat::OctetStream, 42),
+            (CoapContentFormat::Exi, 47),
+            (CoapContentFormat::Json, 50),
+            (CoapContentFormat::Cbor, 60),
+            (CoapContentFormat::Other(11542), 11542),
+        ];
+
+        for (content_format, media_type) in cases {
+            assert_eq!(u16::from(content_format), media_type);
+            assert_eq!(CoapContentFormat::from(media_type), content_format);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use super::{error::CoapError, inspect::CoapPduInfo, recording::Direction};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LINKTYPE_IPV4: u16 = 228;
+
+// The addresses are made up; the gateway side uses 5683 so Wireshark's CoAP dissector picks
+// the packets up without "Decode As"
+const CLIENT: (Ipv4Addr, u16) = (Ipv4Addr::new(127, 0, 0, 2), 49152);
+const GATEWAY: (Ipv4Addr, u16) = (Ipv4Addr::new(127, 0, 0, 1), 5683);
+
+/// Writes plaintext CoAP messages to a pcapng file, wrapped in synthetic IPv4 and UDP headers
+pub(crate) struct PcapWriter {
+    out: BufWriter<File>,
+}
+
+impl PcapWriter {
+    pub(crate) fn create(path: &Path) -> Result<PcapWriter, CoapError> {
+        let file = File::create(path).map_err(|_| CoapError::RecordingIo)?;
+        let mut writer = PcapWriter {
+            out: BufWriter::new(file),
+        };
+
+        // Section header block: byte order magic, version 1.0, unknown section length
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        writer.write_block(0x0A0D_0D0A, &shb)?;
+
+        // Interface description block with microsecond timestamps (the default)
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_IPV4.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_block(1, &idb)?;
+        writer.out.flush().map_err(|_| CoapError::RecordingIo)?;
+
+        Ok(writer)
+    }
+
+    /// Capturing is best effort like recording
+    pub(crate) fn write(&mut self, direction: Direction, pdu: &CoapPduInfo) {
+        let (src, dst) = match direction {
+            Direction::Sent => (CLIENT, GATEWAY),
+            Direction::Received => (GATEWAY, CLIENT),
+        };
+        let packet = ipv4_udp_packet(src, dst, &pdu.to_udp_bytes());
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_micros() as u64)
+            .unwrap_or(0);
+
+        // Enhanced packet block
+        let mut epb = Vec::new();
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&packet);
+        epb.resize((epb.len() + 3) & !3, 0);
+
+        if self.write_block(6, &epb).is_ok() {
+            let _ = self.out.flush();
+        }
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<(), CoapError> {
+        let total_len = (body.len() as u32 + 12).to_le_bytes();
+        let mut block = Vec::with_capacity(body.len() + 12);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_len);
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_len);
+
+        self.out
+            .write_all(&block)
+            .map_err(|_| CoapError::RecordingIo)
+    }
+}
+
+fn ipv4_udp_packet(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len() as u16;
+    let total_len = 20 + udp_len;
+
+    let mut packet = vec![0x45, 0x00];
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    // Identification, don't fragment, TTL 64, UDP, checksum placeholder
+    packet.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 64, 17, 0x00, 0x00]);
+    packet.extend_from_slice(&src.0.octets());
+    packet.extend_from_slice(&dst.0.octets());
+
+    let checksum = !packet
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .fold(0u32, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xFFFF) + (sum >> 16)
+        }) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    // A zero UDP checksum means none was computed, which IPv4 allows
+    packet.extend_from_slice(&src.1.to_be_bytes());
+    packet.extend_from_slice(&dst.1.to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0x00, 0x00]);
+    packet.extend_from_slice(payload);
+
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rosthem::{response::CoapResponseCode, CoapMessageType, CoapToken};
+    use std::convert::TryInto;
+
+    fn pdu(payload: &[u8]) -> CoapPduInfo {
+        CoapPduInfo {
+            message_type: CoapMessageType::Confirmable,
+            code: CoapResponseCode::new(0, 1),
+            mid: 1,
+            token: CoapToken {
+                len: 1,
+                token: [7, 0, 0, 0, 0, 0, 0, 0],
+            },
+            options: Vec::new(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// Splits a pcapng file into (block type, body) after checking both length fields
+    fn blocks(mut data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let u32_at =
+            |data: &[u8], at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+
+        let mut blocks = Vec::new();
+        while !data.is_empty() {
+            let block_type = u32_at(data, 0);
+            let len = u32_at(data, 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(data, len - 4) as usize, len);
+            blocks.push((block_type, data[8..len - 4].to_vec()));
+            data = &data[len..];
+        }
+
+        blocks
+    }
+
+    #[test]
+    fn ipv4_udp_packet_wraps_payload() {
+        let cases = [
+            (CLIENT, GATEWAY, &b""[..]),
+            (GATEWAY, CLIENT, &b"\x40\x01\x00\x01"[..]),
+            (CLIENT, GATEWAY, &[0xAB; 301][..]),
+        ];
+
+        for (src, dst, payload) in cases {
+            let packet = ipv4_udp_packet(src, dst, payload);
+            let header = &packet[..20];
+
+            assert_eq!(packet.len(), 28 + payload.len());
+            assert_eq!(&header[2..4], &(packet.len() as u16).to_be_bytes());
+            assert_eq!(&header[12..16], &src.0.octets());
+            assert_eq!(&header[16..20], &dst.0.octets());
+            // Summing a header including its checksum gives 0xFFFF
+            let sum = header
+                .chunks(2)
+                .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+                .sum::<u32>();
+            assert_eq!((sum & 0xFFFF) + (sum >> 16), 0xFFFF);
+
+            assert_eq!(&packet[20..22], &src.1.to_be_bytes());
+            assert_eq!(&packet[22..24], &dst.1.to_be_bytes());
+            assert_eq!(&packet[24..26], &(8 + payload.len() as u16).to_be_bytes());
+            assert_eq!(&packet[28..], payload);
+        }
+    }
+
+    #[test]
+    fn writer_appends_enhanced_packet_blocks() {
+        let path = std::env::temp_dir().join(format!("rosthem-pcap-{}.pcapng", std::process::id()));
+        let cases = [
+            (Direction::Sent, pdu(b""), CLIENT),
+            (Direction::Received, pdu(b"{}"), GATEWAY),
+            (Direction::Sent, pdu(b"odd"), CLIENT),
+        ];
+
+        let mut writer = PcapWriter::create(&path).unwrap();
+        for (direction, pdu, _) in &cases {
+            writer.write(*direction, pdu);
+        }
+        drop(writer);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 2 + cases.len());
+        assert_eq!(blocks[0].0, 0x0A0D_0D0A);
+        assert_eq!(&blocks[0].1[..4], &0x1A2B_3C4Du32.to_le_bytes());
+        assert_eq!(blocks[1].0, 1);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_IPV4.to_le_bytes());
+
+        for ((block_type, body), (_, pdu, src)) in blocks[2..].iter().zip(&cases) {
+            let udp = pdu.to_udp_bytes();
+            let captured_len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+            let packet = &body[20..20 + captured_len];
+
+            assert_eq!(*block_type, 6);
+            assert_eq!(captured_len, 28 + udp.len());
+            assert_eq!(&body[16..20], &body[12..16]);
+            assert_eq!(&packet[12..16], &src.0.octets());
+            assert_eq!(&packet[28..], &udp[..]);
+        }
+    }
+}
@@ -0,0 +1,603 @@
+#[cfg(feature = "json")]
+use super::CoapContentFormat;
+use super::{error::CoapError, CoapToken, StoredRequest};
+#[cfg(feature = "json")]
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Light options that each select a color mode: preset, xy and temperature. A light only takes
+/// one of them per update.
+#[cfg(feature = "json")]
+const COLOR_KEYS: &[&str] = &["5706", "5709", "5710", "5711"];
+
+/// Limits of the outbound queue of a session, see [`super::CoapSession::enqueue`]
+#[derive(Copy, Clone, Debug)]
+pub struct QueueConfig {
+    /// Requests sent but not answered yet. The Tradfri gateway copes best with 1.
+    pub max_in_flight: usize,
+    /// Requests sent per second, `None` for no limit
+    pub max_per_second: Option<f32>,
+    /// Requests waiting to be sent before further ones are refused with
+    /// [`CoapError::QueueFull`]
+    pub capacity: usize,
+}
+
+impl QueueConfig {
+    /// Sends everything right away, like a session without a queue
+    pub const UNLIMITED: QueueConfig = QueueConfig {
+        max_in_flight: usize::MAX,
+        max_per_second: None,
+        capacity: usize::MAX,
+    };
+
+    /// Fails with [`CoapError::InvalidQueueConfig`] for limits that would never let a request
+    /// through, or a rate that isn't a positive number
+    pub(crate) fn validate(&self) -> Result<(), CoapError> {
+        if self.max_in_flight == 0 || self.capacity == 0 {
+            return Err(CoapError::InvalidQueueConfig);
+        }
+
+        match self.max_per_second {
+            Some(rate)
+                if rate.is_nan()
+                    || rate <= 0.0
+                    || Duration::try_from_secs_f32(1.0 / rate).is_err() =>
+            {
+                Err(CoapError::InvalidQueueConfig)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f32(1.0 / self.max_per_second?))
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_in_flight: 1,
+            max_per_second: Some(5.0),
+            capacity: 64,
+        }
+    }
+}
+
+pub(crate) struct Queued {
+    pub(crate) token: CoapToken,
+    key: Option<String>,
+    pub(crate) request: StoredRequest,
+}
+
+pub(crate) struct OutboundQueue {
+    config: QueueConfig,
+    waiting: VecDeque<Queued>,
+    // Sent requests by the time their response is given up on. Non-confirmable requests and
+    // ones that failed to send are never answered and would hold their slot forever otherwise.
+    in_flight: HashMap<CoapToken, Instant>,
+    // Tokens whose response is picked up by a caller instead of being dropped
+    waiters: HashSet<CoapToken>,
+    // Sent requests nobody waits for anymore. They don't take up a slot, but their response
+    // (or the NACK once libcoap gives up) still has to be picked up and dropped.
+    abandoned: HashMap<CoapToken, Instant>,
+    last_sent: Option<Instant>,
+}
+
+impl OutboundQueue {
+    pub(crate) fn new() -> OutboundQueue {
+        OutboundQueue {
+            config: QueueConfig::UNLIMITED,
+            waiting: VecDeque::new(),
+            in_flight: HashMap::new(),
+            waiters: HashSet::new(),
+            abandoned: HashMap::new(),
+            last_sent: None,
+        }
+    }
+
+    pub(crate) fn config(&self) -> QueueConfig {
+        self.config
+    }
+
+    pub(crate) fn set_config(&mut self, config: QueueConfig) -> Result<(), CoapError> {
+        config.validate()?;
+        self.config = config;
+
+        Ok(())
+    }
+
+    /// Queues `request` under `token`, unless a request with the same key is still waiting. That
+    /// one is updated instead and its token returned.
+    pub(crate) fn push(
+        &mut self,
+        token: CoapToken,
+        key: Option<&str>,
+        request: StoredRequest,
+        wait: bool,
+    ) -> Result<CoapToken, CoapError> {
+        let pending = key.and_then(|key| {
+            self.waiting
+                .iter_mut()
+                .find(|queued| queued.key.as_deref() == Some(key))
+        });
+
+        let token = match pending {
+            Some(queued) => {
+                coalesce(&mut queued.request, request);
+                queued.token
+            }
+            None => {
+                if self.waiting.len() >= self.config.capacity {
+                    return Err(CoapError::QueueFull);
+                }
+
+                self.waiting.push_back(Queued {
+                    token,
+                    key: key.map(str::to_owned),
+                    request,
+                });
+                token
+            }
+        };
+
+        if wait {
+            self.waiters.insert(token);
+        }
+
+        Ok(token)
+    }
+
+    /// The next request to send, if the limits allow one now. Its slot is freed once it is
+    /// answered, or `timeout` from now at the latest.
+    pub(crate) fn pop_ready(&mut self, now: Instant, timeout: Duration) -> Option<Queued> {
+        if self.in_flight.len() >= self.config.max_in_flight || self.time_until_ready(now).is_some()
+        {
+            return None;
+        }
+
+        let queued = self.waiting.pop_front()?;
+        self.in_flight.insert(queued.token, now + timeout);
+        self.last_sent = Some(now);
+
+        Some(queued)
+    }
+
+    /// How long the rate limit holds back the next waiting request
+    pub(crate) fn time_until_ready(&self, now: Instant) -> Option<Duration> {
+        if self.waiting.is_empty() {
+            return None;
+        }
+
+        let interval = self.config.interval()?;
+        let ready = self.last_sent? + interval;
+        (ready > now).then(|| ready - now)
+    }
+
+    /// Sent requests whose response is still expected, including abandoned ones
+    pub(crate) fn in_flight(&self) -> impl Iterator<Item = &CoapToken> {
+        self.in_flight.keys().chain(self.abandoned.keys())
+    }
+
+    /// Gives up on sent requests whose deadline passed and returns their tokens. Requests a
+    /// caller waits for are left to the caller's own timeout, which abandons them.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<CoapToken> {
+        let waiters = &self.waiters;
+        let mut expired = Vec::new();
+
+        self.in_flight.retain(|token, deadline| {
+            let keep = *deadline > now || waiters.contains(token);
+            if !keep {
+                expired.push(*token);
+            }
+            keep
+        });
+        self.abandoned.retain(|token, deadline| {
+            if *deadline > now {
+                return true;
+            }
+            expired.push(*token);
+            false
+        });
+
+        expired
+    }
+
+    /// Frees the slot of an answered request. Returns whether a caller waits for the response.
+    pub(crate) fn complete(&mut self, token: CoapToken) -> bool {
+        self.in_flight.remove(&token);
+        self.abandoned.remove(&token);
+        self.waiters.remove(&token)
+    }
+
+    /// Stops waiting for the response to `token`, e.g. after a timeout. If the request hasn't
+    /// been sent yet, it never will be. If it has, its slot is freed right away; returns `true`
+    /// then, since its response still has to be tracked until it arrives or libcoap gives up.
+    pub(crate) fn abandon(&mut self, token: CoapToken) -> bool {
+        if !self.waiters.remove(&token) {
+            return false;
+        }
+
+        self.waiting.retain(|queued| queued.token != token);
+        if let Some(deadline) = self.in_flight.remove(&token) {
+            self.abandoned.insert(token, deadline);
+            return true;
+        }
+
+        false
+    }
+
+    /// Requests in flight on a session that was replaced will never be answered. Returns their
+    /// tokens.
+    pub(crate) fn forget_in_flight(&mut self) -> Vec<CoapToken> {
+        let forgotten: Vec<CoapToken> = self
+            .in_flight
+            .drain()
+            .chain(self.abandoned.drain())
+            .map(|(token, _)| token)
+            .collect();
+        for token in &forgotten {
+            self.waiters.remove(token);
+        }
+
+        forgotten
+    }
+
+    pub(crate) fn waiting_len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub(crate) fn in_flight_len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub(crate) fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.in_flight.is_empty()
+    }
+}
+
+/// Folds a newer request into an older one for the same resource. With the `json` feature,
+/// JSON payloads are merged, with the newer values winning, so e.g. switching a light on and
+/// then dimming it still does both. A newer color replaces the older one as a whole, whatever
+/// its mode. Anything else is replaced.
+fn coalesce(older: &mut StoredRequest, newer: StoredRequest) {
+    #[cfg(feature = "json")]
+    if let Some(payload) = merged_json(older, &newer) {
+        older.payload = Some(payload);
+        return;
+    }
+
+    *older = newer;
+}
+
+/// The merged payload of two requests that both carry JSON
+#[cfg(feature = "json")]
+fn merged_json(older: &StoredRequest, newer: &StoredRequest) -> Option<Vec<u8>> {
+    if !matches!(
+        (older.content_format, newer.content_format),
+        (Some(CoapContentFormat::Json), Some(CoapContentFormat::Json))
+    ) {
+        return None;
+    }
+
+    let mut old = serde_json::from_slice::<Value>(older.payload.as_deref()?).ok()?;
+    let new = serde_json::from_slice::<Value>(newer.payload.as_deref()?).ok()?;
+    merge_json(&mut old, new);
+    serde_json::to_vec(&old).ok()
+}
+
+#[cfg(feature = "json")]
+fn merge_json(old: &mut Value, new: Value) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            if COLOR_KEYS.iter().any(|key| new.contains_key(*key)) {
+                old.retain(|key, _| !COLOR_KEYS.contains(&key.as_str()));
+            }
+            for (key, value) in new {
+                merge_json(old.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        // The Tradfri option lists (e.g. "3311") hold a single object
+        (Value::Array(old), Value::Array(new)) if old.len() == new.len() => {
+            for (old, new) in old.iter_mut().zip(new) {
+                merge_json(old, new);
+            }
+        }
+        (old, new) => *old = new,
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::rosthem::{CoapMessageType, CoapMethod, CoapOptList};
+    use serde_json::json;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn token(n: u8) -> CoapToken {
+        CoapToken {
+            len: 1,
+            token: [n, 0, 0, 0, 0, 0, 0, 0],
+        }
+    }
+
+    fn put(payload: Value) -> StoredRequest {
+        StoredRequest {
+            message_type: CoapMessageType::Confirmable,
+            method: CoapMethod::Put,
+            optlist: CoapOptList::new(),
+            content_format: Some(CoapContentFormat::Json),
+            payload: Some(serde_json::to_vec(&payload).unwrap()),
+        }
+    }
+
+    fn payload(queued: &Queued) -> Value {
+        serde_json::from_slice(queued.request.payload.as_deref().unwrap()).unwrap()
+    }
+
+    fn queue(config: QueueConfig) -> OutboundQueue {
+        let mut queue = OutboundQueue::new();
+        queue.set_config(config).unwrap();
+        queue
+    }
+
+    #[test]
+    fn refuses_requests_beyond_capacity() {
+        let mut queue = queue(QueueConfig {
+            capacity: 2,
+            ..QueueConfig::UNLIMITED
+        });
+        queue
+            .push(token(1), Some("15001/1"), put(json!({})), false)
+            .unwrap();
+        queue.push(token(2), None, put(json!({})), false).unwrap();
+
+        assert!(matches!(
+            queue.push(token(3), None, put(json!({})), false),
+            Err(CoapError::QueueFull)
+        ));
+        // Coalescing into a waiting request doesn't need room
+        let coalesced = queue.push(token(3), Some("15001/1"), put(json!({})), false);
+        assert_eq!(coalesced.unwrap(), token(1));
+        assert_eq!(queue.waiting_len(), 2);
+    }
+
+    #[test]
+    fn coalesces_waiting_requests_with_the_same_key() {
+        let mut queue = queue(QueueConfig::UNLIMITED);
+        let first = queue
+            .push(
+                token(1),
+                Some("15001/1"),
+                put(json!({"3311": [{"5850": 1}]})),
+                false,
+            )
+            .unwrap();
+        let second = queue
+            .push(
+                token(2),
+                Some("15001/1"),
+                put(json!({"3311": [{"5851": 10}]})),
+                true,
+            )
+            .unwrap();
+        queue
+            .push(
+                token(3),
+                Some("15001/2"),
+                put(json!({"3311": [{"5850": 0}]})),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(first, token(1));
+        assert_eq!(second, token(1));
+        assert_eq!(queue.waiting_len(), 2);
+
+        let queued = queue.pop_ready(Instant::now(), TIMEOUT).unwrap();
+        assert_eq!(queued.token, token(1));
+        assert_eq!(payload(&queued), json!({"3311": [{"5850": 1, "5851": 10}]}));
+        // The second caller waits for the merged request
+        assert!(queue.complete(token(1)));
+    }
+
+    #[test]
+    fn a_newer_color_replaces_the_older_one() {
+        let mut queue = queue(QueueConfig::UNLIMITED);
+        queue
+            .push(
+                token(1),
+                Some("15001/1"),
+                put(json!({"3311": [{"5850": 1, "5706": "f1e0b5"}]})),
+                false,
+            )
+            .unwrap();
+        queue
+            .push(
+                token(2),
+                Some("15001/1"),
+                put(json!({"3311": [{"5709": 30000, "5710": 26000}]})),
+                false,
+            )
+            .unwrap();
+
+        let queued = queue.pop_ready(Instant::now(), TIMEOUT).unwrap();
+        assert_eq!(
+            payload(&queued),
+            json!({"3311": [{"5850": 1, "5709": 30000, "5710": 26000}]})
+        );
+    }
+
+    #[test]
+    fn limits_requests_in_flight() {
+        let mut queue = queue(QueueConfig {
+            max_in_flight: 1,
+            ..QueueConfig::UNLIMITED
+        });
+        queue.push(token(1), None, put(json!({})), false).unwrap();
+        queue.push(token(2), None, put(json!({})), false).unwrap();
+        let now = Instant::now();
+
+        assert_eq!(queue.pop_ready(now, TIMEOUT).unwrap().token, token(1));
+        assert!(queue.pop_ready(now, TIMEOUT).is_none());
+
+        assert!(!queue.complete(token(1)));
+        assert_eq!(queue.pop_ready(now, TIMEOUT).unwrap().token, token(2));
+    }
+
+    #[test]
+    fn limits_the_rate() {
+        let mut queue = queue(QueueConfig {
+            max_per_second: Some(4.0),
+            ..QueueConfig::UNLIMITED
+        });
+        for n in 1..=3 {
+            queue.push(token(n), None, put(json!({})), false).unwrap();
+        }
+        let start = Instant::now();
+
+        assert_eq!(queue.pop_ready(start, TIMEOUT).unwrap().token, token(1));
+        assert!(queue.pop_ready(start, TIMEOUT).is_none());
+        assert_eq!(
+            queue.time_until_ready(start + Duration::from_millis(100)),
+            Some(Duration::from_millis(150))
+        );
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(queue.time_until_ready(later), None);
+        assert_eq!(queue.pop_ready(later, TIMEOUT).unwrap().token, token(2));
+        assert!(queue.pop_ready(later, TIMEOUT).is_none());
+    }
+
+    #[test]
+    fn abandoning_a_sent_request_frees_its_slot() {
+        let mut queue = queue(QueueConfig {
+            max_in_flight: 1,
+            ..QueueConfig::UNLIMITED
+        });
+        queue.push(token(1), None, put(json!({})), true).unwrap();
+        queue.push(token(2), None, put(json!({})), false).unwrap();
+        let now = Instant::now();
+        queue.pop_ready(now, TIMEOUT).unwrap();
+
+        // Timed out after it was sent
+        assert!(queue.abandon(token(1)));
+        assert_eq!(queue.in_flight_len(), 0);
+        // Its late response is still picked up, without a waiter
+        assert!(queue.in_flight().any(|t| *t == token(1)));
+
+        assert_eq!(queue.pop_ready(now, TIMEOUT).unwrap().token, token(2));
+        assert!(!queue.complete(token(1)));
+        assert!(!queue.in_flight().any(|t| *t == token(1)));
+    }
+
+    #[test]
+    fn unanswered_requests_free_their_slot_after_the_deadline() {
+        let mut queue = queue(QueueConfig {
+            max_in_flight: 1,
+            ..QueueConfig::UNLIMITED
+        });
+        queue.push(token(1), None, put(json!({})), false).unwrap();
+        queue.push(token(2), None, put(json!({})), true).unwrap();
+        queue.push(token(3), None, put(json!({})), false).unwrap();
+        let start = Instant::now();
+        queue.pop_ready(start, TIMEOUT).unwrap();
+
+        // e.g. a non-confirmable request whose response got lost
+        assert!(queue.pop_ready(start + TIMEOUT / 2, TIMEOUT).is_none());
+        assert!(queue.expire(start + TIMEOUT / 2).is_empty());
+        let later = start + TIMEOUT;
+        assert_eq!(queue.expire(later), vec![token(1)]);
+        assert!(!queue.in_flight().any(|t| *t == token(1)));
+        assert_eq!(queue.pop_ready(later, TIMEOUT).unwrap().token, token(2));
+
+        // A caller waiting for its response times out on its own and abandons it, then it
+        // expires like the others
+        let much_later = later + TIMEOUT;
+        assert!(queue.expire(much_later).is_empty());
+        assert!(queue.abandon(token(2)));
+        assert_eq!(queue.expire(much_later), vec![token(2)]);
+        assert!(queue.in_flight().next().is_none());
+        assert_eq!(
+            queue.pop_ready(much_later, TIMEOUT).unwrap().token,
+            token(3)
+        );
+    }
+
+    #[test]
+    fn abandoning_a_waiting_request_drops_it() {
+        let mut queue = queue(QueueConfig::UNLIMITED);
+        queue.push(token(1), None, put(json!({})), true).unwrap();
+
+        assert!(!queue.abandon(token(1)));
+        assert!(queue.is_idle());
+        // Requests nobody waits for aren't abandoned
+        queue.push(token(2), None, put(json!({})), false).unwrap();
+        assert!(!queue.abandon(token(2)));
+        assert_eq!(queue.waiting_len(), 1);
+    }
+
+    #[test]
+    fn forgets_requests_in_flight() {
+        let mut queue = queue(QueueConfig::UNLIMITED);
+        queue.push(token(1), None, put(json!({})), true).unwrap();
+        queue.push(token(2), None, put(json!({})), true).unwrap();
+        queue.push(token(3), None, put(json!({})), false).unwrap();
+        let now = Instant::now();
+        queue.pop_ready(now, TIMEOUT).unwrap();
+        queue.pop_ready(now, TIMEOUT).unwrap();
+        queue.abandon(token(2));
+
+        let mut forgotten = queue.forget_in_flight();
+        forgotten.sort_by_key(|t| t.token);
+        assert_eq!(forgotten, vec![token(1), token(2)]);
+        assert_eq!(queue.in_flight_len(), 0);
+        assert!(!queue.complete(token(1)));
+        // Requests that weren't sent yet go out on the new session
+        assert_eq!(queue.waiting_len(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        for config in [
+            QueueConfig {
+                max_in_flight: 0,
+                ..QueueConfig::default()
+            },
+            QueueConfig {
+                capacity: 0,
+                ..QueueConfig::default()
+            },
+            QueueConfig {
+                max_per_second: Some(0.0),
+                ..QueueConfig::default()
+            },
+            QueueConfig {
+                max_per_second: Some(-1.0),
+                ..QueueConfig::default()
+            },
+            QueueConfig {
+                max_per_second: Some(f32::NAN),
+                ..QueueConfig::default()
+            },
+            QueueConfig {
+                max_per_second: Some(f32::MIN_POSITIVE),
+                ..QueueConfig::default()
+            },
+        ] {
+            assert!(matches!(
+                OutboundQueue::new().set_config(config),
+                Err(CoapError::InvalidQueueConfig)
+            ));
+        }
+
+        OutboundQueue::new()
+            .set_config(QueueConfig::default())
+            .unwrap();
+        OutboundQueue::new()
+            .set_config(QueueConfig::UNLIMITED)
+            .unwrap();
+    }
+}
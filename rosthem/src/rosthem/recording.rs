@@ -0,0 +1,405 @@
+use super::{
+    error::CoapError,
+    ffi::*,
+    response::CoapResponseCode,
+    server::{CoapReply, CoapRequest},
+    CoapContentFormat, CoapContext, CoapMethod, CoapUriScheme,
+};
+#[cfg(feature = "json")]
+use super::{inspect::CoapPduInfo, CoapMessageType};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "json")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "json")]
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::net::SocketAddr;
+#[cfg(feature = "json")]
+use std::path::Path;
+use std::rc::Rc;
+#[cfg(feature = "json")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One line of a recording
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedPdu {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub direction: Direction,
+    /// CON, NON, ACK or RST
+    pub message_type: String,
+    pub mid: i32,
+    /// Hex encoded
+    pub token: String,
+    /// Method or response code in its `class.detail` form, e.g. 0.01 for GET
+    pub code: String,
+    pub options: Vec<RecordedOption>,
+    /// The payload if it is UTF-8, which is the case for everything the gateway sends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_hex: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedOption {
+    pub number: u16,
+    /// Hex encoded
+    pub value: String,
+}
+
+impl RecordedPdu {
+    #[cfg(feature = "json")]
+    pub(crate) fn new(direction: Direction, pdu: &CoapPduInfo) -> RecordedPdu {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_millis() as u64)
+            .unwrap_or(0);
+        let message_type = match pdu.message_type {
+            CoapMessageType::Confirmable => "CON",
+            CoapMessageType::NonConfirmable => "NON",
+            CoapMessageType::Acknowledgement => "ACK",
+            CoapMessageType::Reset => "RST",
+        };
+
+        let options = pdu
+            .options
+            .iter()
+            .map(|(number, value)| RecordedOption {
+                number: *number,
+                value: hex::encode(value),
+            })
+            .collect();
+
+        let (payload, payload_hex) = match std::str::from_utf8(&pdu.payload) {
+            Ok("") => (None, None),
+            Ok(payload) => (Some(payload.to_owned()), None),
+            Err(_) => (None, Some(hex::encode(&pdu.payload))),
+        };
+
+        RecordedPdu {
+            timestamp,
+            direction,
+            message_type: message_type.to_owned(),
+            mid: pdu.mid,
+            token: hex::encode(pdu.token.as_bytes()),
+            code: pdu.code.to_string(),
+            options,
+            payload,
+            payload_hex,
+        }
+    }
+
+    pub fn code(&self) -> Result<CoapResponseCode, CoapError> {
+        let mut parts = self.code.splitn(2, '.');
+        match (
+            parts.next().and_then(|c| c.parse().ok()),
+            parts.next().and_then(|d| d.parse().ok()),
+        ) {
+            (Some(class), Some(detail)) => Ok(CoapResponseCode::new(class, detail)),
+            _ => Err(CoapError::DeserializeError),
+        }
+    }
+
+    pub fn payload(&self) -> Result<Vec<u8>, CoapError> {
+        match (&self.payload, &self.payload_hex) {
+            (Some(payload), _) => Ok(payload.as_bytes().to_vec()),
+            (None, Some(payload)) => hex::decode(payload).map_err(|_| CoapError::DeserializeError),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+
+    /// Values of all options with `number`, in order
+    pub fn option_values(&self, number: u16) -> Result<Vec<Vec<u8>>, CoapError> {
+        self.options
+            .iter()
+            .filter(|option| option.number == number)
+            .map(|option| hex::decode(&option.value).map_err(|_| CoapError::DeserializeError))
+            .collect()
+    }
+
+    fn option_strings(&self, number: u16) -> Result<Vec<String>, CoapError> {
+        Ok(self
+            .option_values(number)?
+            .into_iter()
+            .map(|value| String::from_utf8_lossy(&value).into_owned())
+            .collect())
+    }
+}
+
+/// Appends the PDUs of a session to a JSON Lines file
+#[cfg(feature = "json")]
+pub(crate) struct Recorder {
+    out: LineWriter<File>,
+}
+
+#[cfg(feature = "json")]
+impl Recorder {
+    pub(crate) fn create(path: &Path) -> Result<Recorder, CoapError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| CoapError::RecordingIo)?;
+
+        Ok(Recorder {
+            out: LineWriter::new(file),
+        })
+    }
+
+    /// Recording is best effort; a full disk must not break the session
+    pub(crate) fn record(&mut self, direction: Direction, pdu: &CoapPduInfo) {
+        if let Ok(line) = serde_json::to_string(&RecordedPdu::new(direction, pdu)) {
+            let _ = writeln!(self.out, "{}", line);
+        }
+    }
+}
+
+type ExchangeKey = (u32, Vec<String>);
+
+/// Serves recorded responses back over plain CoAP, so a recording can be replayed against the
+/// regular session API. Requests are matched by method, path and query; each match gets the
+/// next recorded response, and the last one again once they run out.
+pub struct Replay {
+    exchanges: Vec<(RecordedPdu, RecordedPdu)>,
+}
+
+impl Replay {
+    #[cfg(feature = "json")]
+    pub fn load(path: impl AsRef<Path>) -> Result<Replay, CoapError> {
+        let file = File::open(path).map_err(|_| CoapError::RecordingIo)?;
+        let records = BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| {
+                let line = line.map_err(|_| CoapError::RecordingIo)?;
+                serde_json::from_str(&line).map_err(|_| CoapError::DeserializeError)
+            })
+            .collect::<Result<Vec<RecordedPdu>, CoapError>>()?;
+
+        Ok(Replay::from_records(records))
+    }
+
+    /// Pairs every sent request with the first response received for its token
+    pub fn from_records(records: Vec<RecordedPdu>) -> Replay {
+        let mut exchanges = Vec::new();
+
+        for (i, request) in records.iter().enumerate() {
+            if request.direction != Direction::Sent || !request.code.starts_with("0.") {
+                continue;
+            }
+
+            let response = records[i + 1..].iter().find(|response| {
+                response.direction == Direction::Received && response.token == request.token
+            });
+            if let Some(response) = response {
+                exchanges.push((request.clone(), response.clone()));
+            }
+        }
+
+        Replay { exchanges }
+    }
+
+    pub fn len(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exchanges.is_empty()
+    }
+
+    /// Serves the recording on `addr` (`coap://`) from `context` and returns the address it is
+    /// bound to
+    pub fn serve(
+        &self,
+        context: &Rc<CoapContext>,
+        addr: SocketAddr,
+    ) -> Result<SocketAddr, CoapError> {
+        let mut paths: HashMap<String, HashMap<ExchangeKey, VecDeque<RecordedPdu>>> =
+            HashMap::new();
+
+        for (request, response) in &self.exchanges {
+            let path = request
+                .option_strings(COAP_OPTION_URI_PATH as u16)?
+                .join("/");
+            let key = (
+                u32::from(request.code()?),
+                request.option_strings(COAP_OPTION_URI_QUERY as u16)?,
+            );
+
+            paths
+                .entry(path)
+                .or_default()
+                .entry(key)
+                .or_default()
+                .push_back(response.clone());
+        }
+
+        for (path, exchanges) in paths {
+            let methods: Vec<u32> = exchanges.keys().map(|(method, _)| *method).collect();
+            let exchanges = Rc::new(RefCell::new(exchanges));
+            let resource = context.add_resource(&path)?;
+
+            for method in methods {
+                let method = match CoapMethod::from_code(method) {
+                    Some(method) => method,
+                    None => continue,
+                };
+
+                let exchanges = exchanges.clone();
+                resource.on(method, move |request| {
+                    replay(&mut exchanges.borrow_mut(), request).unwrap_or_else(CoapReply::from)
+                });
+            }
+        }
+
+        context.add_endpoint(addr, CoapUriScheme::Coap)
+    }
+}
+
+fn replay(
+    exchanges: &mut HashMap<ExchangeKey, VecDeque<RecordedPdu>>,
+    request: &CoapRequest,
+) -> Result<CoapReply, CoapError> {
+    let key = (request.method() as u32, request.query().to_vec());
+    let responses = exchanges.get_mut(&key).ok_or(CoapError::NotFound)?;
+
+    let response = if responses.len() > 1 {
+        responses.pop_front()
+    } else {
+        responses.front().cloned()
+    }
+    .ok_or(CoapError::NotFound)?;
+
+    let reply = CoapReply::new(response.code()?);
+    let payload = response.payload()?;
+    if payload.is_empty() {
+        return Ok(reply);
+    }
+
+    let content_format = response
+        .option_values(COAP_OPTION_CONTENT_FORMAT as u16)?
+        .first()
+        .map(|value| {
+            let format = value.iter().fold(0u16, |acc, b| (acc << 8) | *b as u16);
+            CoapContentFormat::from(format)
+        });
+
+    Ok(match content_format {
+        Some(content_format) => reply.with_raw_payload(payload, content_format),
+        None => reply.with_untyped_payload(payload),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Direction::{Received, Sent};
+
+    type Pdus<'a> = &'a [(Direction, &'a str, &'a str)];
+    /// Name, records and the timestamps of the expected request and response pairs
+    type Case<'a> = (&'a str, Pdus<'a>, &'a [(u64, u64)]);
+
+    /// The timestamp doubles as the index, so exchanges can be compared by it
+    fn records(pdus: Pdus<'_>) -> Vec<RecordedPdu> {
+        pdus.iter()
+            .enumerate()
+            .map(|(i, (direction, token, code))| RecordedPdu {
+                timestamp: i as u64,
+                direction: *direction,
+                message_type: "CON".to_owned(),
+                mid: i as i32,
+                token: token.to_string(),
+                code: code.to_string(),
+                options: Vec::new(),
+                payload: None,
+                payload_hex: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn code_parses_class_and_detail() {
+        let cases = [
+            ("0.01", Some(CoapResponseCode::new(0, 1))),
+            ("2.05", Some(CoapResponseCode::CONTENT)),
+            ("4.04", Some(CoapResponseCode::NOT_FOUND)),
+            ("4.13", Some(CoapResponseCode::REQUEST_ENTITY_TOO_LARGE)),
+            ("5.3", Some(CoapResponseCode::new(5, 3))),
+            ("", None),
+            ("2", None),
+            ("2.", None),
+            (".05", None),
+            ("a.05", None),
+            ("2.05.1", None),
+            ("256.01", None),
+        ];
+
+        for (code, expected) in cases {
+            let record = records(&[(Sent, "01", code)]).remove(0);
+            assert_eq!(record.code().ok(), expected, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn from_records_pairs_requests_with_first_response() {
+        let cases: [Case<'_>; 6] = [
+            (
+                "request and response",
+                &[(Sent, "01", "0.01"), (Received, "01", "2.05")],
+                &[(0, 1)],
+            ),
+            (
+                "interleaved tokens",
+                &[
+                    (Sent, "01", "0.01"),
+                    (Sent, "02", "0.03"),
+                    (Received, "02", "2.04"),
+                    (Received, "01", "2.05"),
+                ],
+                &[(0, 3), (1, 2)],
+            ),
+            (
+                "notifications after the first response",
+                &[
+                    (Sent, "01", "0.01"),
+                    (Received, "01", "2.05"),
+                    (Received, "01", "2.05"),
+                ],
+                &[(0, 1)],
+            ),
+            (
+                "request without response",
+                &[(Sent, "01", "0.01"), (Received, "02", "2.05")],
+                &[],
+            ),
+            (
+                "response before the request",
+                &[(Received, "01", "2.05"), (Sent, "01", "0.01")],
+                &[],
+            ),
+            (
+                "received requests and sent responses",
+                &[(Received, "01", "0.01"), (Sent, "01", "2.05")],
+                &[],
+            ),
+        ];
+
+        for (name, pdus, expected) in cases {
+            let exchanges = Replay::from_records(records(pdus))
+                .exchanges
+                .iter()
+                .map(|(request, response)| (request.timestamp, response.timestamp))
+                .collect::<Vec<_>>();
+            assert_eq!(exchanges, expected, "{}", name);
+        }
+    }
+}
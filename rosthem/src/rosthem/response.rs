@@ -0,0 +1,227 @@
+#[cfg(feature = "cbor")]
+use super::codec::CborCodec;
+#[cfg(feature = "json")]
+use super::codec::JsonCodec;
+use super::{codec::PayloadCodec, error::CoapError, CoapContentFormat, CoapToken};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::fmt;
+
+/// A response code in its `class.detail` form, e.g. 4.04
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CoapResponseCode {
+    pub class: u8,
+    pub detail: u8,
+}
+
+impl From<u32> for CoapResponseCode {
+    fn from(code: u32) -> Self {
+        CoapResponseCode {
+            class: ((code >> 5) & 0x07) as u8,
+            detail: (code & 0x1F) as u8,
+        }
+    }
+}
+
+impl fmt::Display for CoapResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.class, self.detail)
+    }
+}
+
+impl From<CoapResponseCode> for u32 {
+    fn from(code: CoapResponseCode) -> Self {
+        ((code.class as u32) << 5) | code.detail as u32
+    }
+}
+
+impl CoapResponseCode {
+    pub const CREATED: CoapResponseCode = CoapResponseCode::new(2, 1);
+    pub const DELETED: CoapResponseCode = CoapResponseCode::new(2, 2);
+    pub const VALID: CoapResponseCode = CoapResponseCode::new(2, 3);
+    pub const CHANGED: CoapResponseCode = CoapResponseCode::new(2, 4);
+    pub const CONTENT: CoapResponseCode = CoapResponseCode::new(2, 5);
+    pub const BAD_REQUEST: CoapResponseCode = CoapResponseCode::new(4, 0);
+    pub const UNAUTHORIZED: CoapResponseCode = CoapResponseCode::new(4, 1);
+    pub const BAD_OPTION: CoapResponseCode = CoapResponseCode::new(4, 2);
+    pub const FORBIDDEN: CoapResponseCode = CoapResponseCode::new(4, 3);
+    pub const NOT_FOUND: CoapResponseCode = CoapResponseCode::new(4, 4);
+    pub const METHOD_NOT_ALLOWED: CoapResponseCode = CoapResponseCode::new(4, 5);
+    pub const NOT_ACCEPTABLE: CoapResponseCode = CoapResponseCode::new(4, 6);
+    pub const PRECONDITION_FAILED: CoapResponseCode = CoapResponseCode::new(4, 12);
+    pub const REQUEST_ENTITY_TOO_LARGE: CoapResponseCode = CoapResponseCode::new(4, 13);
+    pub const UNSUPPORTED_CONTENT_FORMAT: CoapResponseCode = CoapResponseCode::new(4, 15);
+    pub const INTERNAL_SERVER_ERROR: CoapResponseCode = CoapResponseCode::new(5, 0);
+
+    pub const fn new(class: u8, detail: u8) -> CoapResponseCode {
+        CoapResponseCode { class, detail }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.class == 2
+    }
+
+    /// Maps error codes to their [`CoapError`], success codes to `Ok`
+    pub fn to_result(self) -> Result<(), CoapError> {
+        match (self.class, self.detail) {
+            (2, _) => Ok(()),
+            (4, 0) => Err(CoapError::BadRequest),
+            (4, 1) => Err(CoapError::Unauthorized),
+            (4, 2) => Err(CoapError::BadOption),
+            (4, 3) => Err(CoapError::Forbidden),
+            (4, 4) => Err(CoapError::NotFound),
+            (4, 5) => Err(CoapError::MethodNotAllowed),
+            (4, 6) => Err(CoapError::NotAcceptable),
+            (4, 12) => Err(CoapError::PreconditionFailed),
+            (4, 13) => Err(CoapError::RequestEntityTooLarge),
+            (4, _) => Err(CoapError::ClientError { code: self }),
+            (5, _) => Err(CoapError::ServerError { code: self }),
+            _ => Err(CoapError::UnexpectedResponseCode { code: self }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CoapResponse {
+    pub(super) token: CoapToken,
+    pub(super) code: CoapResponseCode,
+    pub(super) content_format: Option<CoapContentFormat>,
+    pub(super) payload: Vec<u8>,
+}
+
+impl CoapResponse {
+    pub fn token(&self) -> CoapToken {
+        self.token
+    }
+
+    pub fn code(&self) -> CoapResponseCode {
+        self.code
+    }
+
+    /// Returns the response if its code signals success, otherwise the matching error
+    pub fn into_result(self) -> Result<CoapResponse, CoapError> {
+        self.code.to_result().map(|_| self)
+    }
+
+    pub fn content_format(&self) -> Option<CoapContentFormat> {
+        self.content_format
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn payload_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    /// Decodes the payload with the codec matching its Content-Format. Responses without a
+    /// Content-Format are assumed to be JSON, which is what the Tradfri gateway sends.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, CoapError> {
+        match self.content_format {
+            #[cfg(feature = "json")]
+            None | Some(CoapContentFormat::Json) => self.decode_with::<JsonCodec, T>(),
+            #[cfg(feature = "cbor")]
+            Some(CoapContentFormat::Cbor) => self.decode_with::<CborCodec, T>(),
+            _ => Err(CoapError::UnsupportedContentFormat),
+        }
+    }
+
+    pub fn decode_with<C: PayloadCodec, T: DeserializeOwned>(&self) -> Result<T, CoapError> {
+        C::decode(&self.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_result_maps_codes_to_errors() {
+        let cases = [
+            (CoapResponseCode::CREATED, Ok(())),
+            (CoapResponseCode::CONTENT, Ok(())),
+            (CoapResponseCode::new(2, 31), Ok(())),
+            (CoapResponseCode::BAD_REQUEST, Err(CoapError::BadRequest)),
+            (CoapResponseCode::UNAUTHORIZED, Err(CoapError::Unauthorized)),
+            (CoapResponseCode::BAD_OPTION, Err(CoapError::BadOption)),
+            (CoapResponseCode::FORBIDDEN, Err(CoapError::Forbidden)),
+            (CoapResponseCode::NOT_FOUND, Err(CoapError::NotFound)),
+            (
+                CoapResponseCode::METHOD_NOT_ALLOWED,
+                Err(CoapError::MethodNotAllowed),
+            ),
+            (
+                CoapResponseCode::NOT_ACCEPTABLE,
+                Err(CoapError::NotAcceptable),
+            ),
+            (
+                CoapResponseCode::PRECONDITION_FAILED,
+                Err(CoapError::PreconditionFailed),
+            ),
+            (
+                CoapResponseCode::REQUEST_ENTITY_TOO_LARGE,
+                Err(CoapError::RequestEntityTooLarge),
+            ),
+            (
+                CoapResponseCode::UNSUPPORTED_CONTENT_FORMAT,
+                Err(CoapError::ClientError {
+                    code: CoapResponseCode::UNSUPPORTED_CONTENT_FORMAT,
+                }),
+            ),
+            (
+                CoapResponseCode::INTERNAL_SERVER_ERROR,
+                Err(CoapError::ServerError {
+                    code: CoapResponseCode::INTERNAL_SERVER_ERROR,
+                }),
+            ),
+            (
+                CoapResponseCode::new(5, 3),
+                Err(CoapError::ServerError {
+                    code: CoapResponseCode::new(5, 3),
+                }),
+            ),
+            (
+                CoapResponseCode::new(0, 1),
+                Err(CoapError::UnexpectedResponseCode {
+                    code: CoapResponseCode::new(0, 1),
+                }),
+            ),
+            (
+                CoapResponseCode::new(3, 0),
+                Err(CoapError::UnexpectedResponseCode {
+                    code: CoapResponseCode::new(3, 0),
+                }),
+            ),
+        ];
+
+        for (code, expected) in cases {
+            // CoapError isn't PartialEq, its Debug output names the variant and the code
+            assert_eq!(
+                format!("{:?}", code.to_result()),
+                format!("{:?}", expected),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn code_converts_to_and_from_u32() {
+        let cases = [
+            (0x01, CoapResponseCode::new(0, 1), "0.01"),
+            (0x41, CoapResponseCode::CREATED, "2.01"),
+            (0x45, CoapResponseCode::CONTENT, "2.05"),
+            (0x84, CoapResponseCode::NOT_FOUND, "4.04"),
+            (0x8D, CoapResponseCode::REQUEST_ENTITY_TOO_LARGE, "4.13"),
+            (0xA0, CoapResponseCode::INTERNAL_SERVER_ERROR, "5.00"),
+            (0xFF, CoapResponseCode::new(7, 31), "7.31"),
+        ];
+
+        for (raw, code, text) in cases {
+            assert_eq!(CoapResponseCode::from(raw), code);
+            assert_eq!(u32::from(code), raw);
+            assert_eq!(code.to_string(), text);
+        }
+    }
+}
@@ -0,0 +1,366 @@
+#[cfg(feature = "json")]
+use super::codec::JsonCodec;
+use super::{
+    codec::PayloadCodec, drop_boxed_slice, encode_uint, error::CoapError, ffi::*, inspect_pdu,
+    pdu_content_format, pdu_payload, recording::Direction, response::CoapResponseCode,
+    CoapContentFormat, CoapContext, CoapMethod,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::os::raw::{c_ulong, c_void};
+use std::ptr::{self, NonNull};
+use std::rc::Rc;
+
+type RequestHandler = Box<dyn Fn(&CoapRequest) -> CoapReply>;
+
+/// A request received by a [`CoapResource`]
+#[derive(Clone, Debug)]
+pub struct CoapRequest {
+    method: CoapMethod,
+    query: Vec<String>,
+    content_format: Option<CoapContentFormat>,
+    payload: Vec<u8>,
+}
+
+impl CoapRequest {
+    pub fn method(&self) -> CoapMethod {
+        self.method
+    }
+
+    /// The Uri-Query segments, e.g. `["a=1", "b"]`
+    pub fn query(&self) -> &[String] {
+        &self.query
+    }
+
+    pub fn content_format(&self) -> Option<CoapContentFormat> {
+        self.content_format
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn payload_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    /// Decodes a JSON payload, which is all the Tradfri gateway ever receives
+    #[cfg(feature = "json")]
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, CoapError> {
+        self.decode_with::<JsonCodec, T>()
+    }
+
+    pub fn decode_with<C: PayloadCodec, T: DeserializeOwned>(&self) -> Result<T, CoapError> {
+        C::decode(&self.payload)
+    }
+}
+
+/// What a [`CoapResource`] handler answers with
+pub struct CoapReply {
+    code: CoapResponseCode,
+    content_format: Option<CoapContentFormat>,
+    payload: Option<Result<Vec<u8>, CoapError>>,
+}
+
+impl CoapReply {
+    pub fn new(code: CoapResponseCode) -> CoapReply {
+        CoapReply {
+            code,
+            content_format: None,
+            payload: None,
+        }
+    }
+
+    /// 2.05 Content, the usual answer to a GET
+    pub fn content() -> CoapReply {
+        CoapReply::new(CoapResponseCode::CONTENT)
+    }
+
+    /// 2.04 Changed, the usual answer to a PUT
+    pub fn changed() -> CoapReply {
+        CoapReply::new(CoapResponseCode::CHANGED)
+    }
+
+    #[cfg(feature = "json")]
+    pub fn with_payload<P: Serialize>(self, payload: &P) -> CoapReply {
+        self.with_encoded_payload::<JsonCodec, P>(payload)
+    }
+
+    pub fn with_encoded_payload<C: PayloadCodec, P: Serialize>(mut self, payload: &P) -> CoapReply {
+        self.content_format = Some(C::CONTENT_FORMAT);
+        self.payload = Some(C::encode(payload));
+        self
+    }
+
+    /// A payload without Content-Format option, limited to what fits into a single message
+    pub(crate) fn with_untyped_payload(mut self, payload: Vec<u8>) -> CoapReply {
+        self.content_format = None;
+        self.payload = Some(Ok(payload));
+        self
+    }
+
+    pub fn with_raw_payload(
+        mut self,
+        payload: Vec<u8>,
+        content_format: CoapContentFormat,
+    ) -> CoapReply {
+        self.content_format = Some(content_format);
+        self.payload = Some(Ok(payload));
+        self
+    }
+}
+
+impl From<CoapError> for CoapReply {
+    /// Maps errors to the response code a client would have turned into the same error
+    fn from(error: CoapError) -> Self {
+        let code = match error {
+            CoapError::BadRequest | CoapError::DeserializeError => CoapResponseCode::BAD_REQUEST,
+            CoapError::Unauthorized => CoapResponseCode::UNAUTHORIZED,
+            CoapError::BadOption => CoapResponseCode::BAD_OPTION,
+            CoapError::Forbidden => CoapResponseCode::FORBIDDEN,
+            CoapError::NotFound => CoapResponseCode::NOT_FOUND,
+            CoapError::MethodNotAllowed => CoapResponseCode::METHOD_NOT_ALLOWED,
+            CoapError::NotAcceptable => CoapResponseCode::NOT_ACCEPTABLE,
+            CoapError::PreconditionFailed => CoapResponseCode::PRECONDITION_FAILED,
+            CoapError::RequestEntityTooLarge => CoapResponseCode::REQUEST_ENTITY_TOO_LARGE,
+            CoapError::UnsupportedContentFormat => CoapResponseCode::UNSUPPORTED_CONTENT_FORMAT,
+            CoapError::ClientError { code }
+            | CoapError::ServerError { code }
+            | CoapError::UnexpectedResponseCode { code } => code,
+            _ => CoapResponseCode::INTERNAL_SERVER_ERROR,
+        };
+
+        CoapReply::new(code)
+    }
+}
+
+struct ResourceHandlers {
+    handlers: HashMap<u32, RequestHandler>,
+    observable: Cell<bool>,
+    /// Value of the Observe option of the responses to observers, raised with every
+    /// notification
+    observe_sequence: Cell<u32>,
+}
+
+/// A path served by a [`CoapContext`]. The resource belongs to the context and lives as long as
+/// it does; this is only a handle to it.
+#[derive(Clone)]
+pub struct CoapResource {
+    inner: NonNull<coap_resource_t>,
+    context: Rc<CoapContext>,
+}
+
+impl CoapResource {
+    pub(crate) fn new(context: &Rc<CoapContext>, path: &str) -> Result<CoapResource, CoapError> {
+        unsafe {
+            // libcoap frees the copied path together with the resource
+            let uri_path = coap_new_str_const(path.as_ptr(), path.len() as c_ulong);
+            if uri_path.is_null() {
+                return Err(CoapError::FailedToCreateResource);
+            }
+
+            let resource = NonNull::new(coap_resource_init(
+                uri_path,
+                (COAP_RESOURCE_FLAGS_RELEASE_URI | COAP_RESOURCE_FLAGS_NOTIFY_CON) as i32,
+            ))
+            .ok_or(CoapError::FailedToCreateResource)?;
+
+            let handlers = Box::new(ResourceHandlers {
+                handlers: HashMap::new(),
+                observable: Cell::new(false),
+                observe_sequence: Cell::new(0),
+            });
+            coap_resource_set_userdata(resource.as_ptr(), Box::into_raw(handlers) as *mut c_void);
+            coap_resource_release_userdata_handler(
+                context.inner.as_ptr(),
+                Some(drop_resource_handlers),
+            );
+            coap_add_resource(context.inner.as_ptr(), resource.as_ptr());
+
+            Ok(CoapResource {
+                inner: resource,
+                context: context.clone(),
+            })
+        }
+    }
+
+    /// Handles requests with `method`, replacing an earlier handler for it. Methods without a
+    /// handler are answered with 4.05 by libcoap.
+    pub fn on(
+        &self,
+        method: CoapMethod,
+        handler: impl Fn(&CoapRequest) -> CoapReply + 'static,
+    ) -> &CoapResource {
+        unsafe {
+            let handlers =
+                &mut *(coap_resource_get_userdata(self.inner.as_ptr()) as *mut ResourceHandlers);
+            handlers.handlers.insert(method as u32, Box::new(handler));
+
+            coap_register_handler(self.inner.as_ptr(), method as u32, Some(handle_request));
+        }
+
+        self
+    }
+
+    /// Lets clients observe the resource. Every [`CoapResource::notify_observers`] sends them
+    /// the reply of the GET handler again.
+    pub fn set_observable(&self, observable: bool) -> &CoapResource {
+        unsafe {
+            resource_handlers(self.inner.as_ptr())
+                .observable
+                .set(observable);
+            coap_resource_set_get_observable(self.inner.as_ptr(), observable as i32);
+        }
+
+        self
+    }
+
+    /// Notifies observers on the next run of the context
+    pub fn notify_observers(&self) {
+        unsafe {
+            let sequence = &resource_handlers(self.inner.as_ptr()).observe_sequence;
+            // The option holds up to 24 bits
+            sequence.set((sequence.get() + 1) & 0xff_ffff);
+            coap_resource_notify_observers(self.inner.as_ptr(), ptr::null());
+        }
+    }
+
+    pub fn context(&self) -> &Rc<CoapContext> {
+        &self.context
+    }
+}
+
+unsafe extern "C" fn handle_request(
+    resource: *mut coap_resource_t,
+    session: *mut coap_session_t,
+    request: *const coap_pdu_t,
+    query: *const coap_string_t,
+    response: *mut coap_pdu_t,
+) {
+    inspect_pdu(None, Direction::Received, request);
+    let handlers = resource_handlers(resource);
+    let code = coap_pdu_get_code(request);
+
+    let reply = match (handlers.handlers.get(&code), CoapMethod::from_code(code)) {
+        (Some(handler), Some(method)) => handler(&CoapRequest {
+            method,
+            query: query_segments(query),
+            content_format: pdu_content_format(request),
+            payload: pdu_payload(request),
+        }),
+        _ => CoapReply::new(CoapResponseCode::METHOD_NOT_ALLOWED),
+    };
+
+    let (code, payload) = match reply.payload {
+        Some(Err(_)) => (CoapResponseCode::INTERNAL_SERVER_ERROR, None),
+        Some(Ok(payload)) => (reply.code, Some(payload)),
+        None => (reply.code, None),
+    };
+    coap_pdu_set_code(response, code.into());
+
+    match (payload, reply.content_format) {
+        (Some(payload), None) => {
+            add_observe(handlers, request, response, code);
+            coap_add_data(response, payload.len() as c_ulong, payload.as_ptr());
+        }
+        (Some(payload), Some(content_format)) => add_large_payload(
+            resource,
+            session,
+            request,
+            response,
+            query,
+            payload,
+            content_format,
+        ),
+        (None, _) => add_observe(handlers, request, response, code),
+    }
+    inspect_pdu(None, Direction::Sent, response);
+}
+
+/// Adds the Observe option to successful responses to observers, both to the registration and to
+/// notifications (which libcoap makes by handling the registering request again). Without it
+/// libcoap drops the observer again.
+unsafe fn add_observe(
+    handlers: &ResourceHandlers,
+    request: *const coap_pdu_t,
+    response: *mut coap_pdu_t,
+    code: CoapResponseCode,
+) {
+    if !handlers.observable.get() || !code.is_success() {
+        return;
+    }
+
+    let mut opt_iter: coap_opt_iterator_t = std::mem::zeroed();
+    let observe = coap_check_option(request, COAP_OPTION_OBSERVE as u16, &mut opt_iter);
+    if observe.is_null()
+        || coap_decode_var_bytes(coap_opt_value(observe), coap_opt_length(observe) as c_ulong)
+            != COAP_OBSERVE_ESTABLISH
+    {
+        return;
+    }
+
+    let mut buf = [0u8; 4];
+    let value = encode_uint(handlers.observe_sequence.get(), &mut buf);
+    coap_add_option(
+        response,
+        COAP_OPTION_OBSERVE as u16,
+        value.len() as c_ulong,
+        value.as_ptr(),
+    );
+}
+
+unsafe fn add_large_payload(
+    resource: *mut coap_resource_t,
+    session: *mut coap_session_t,
+    request: *const coap_pdu_t,
+    response: *mut coap_pdu_t,
+    query: *const coap_string_t,
+    payload: Vec<u8>,
+    content_format: CoapContentFormat,
+) {
+    let payload = Box::new(payload);
+    let payload_ptr = payload.as_ptr();
+    let payload_len = payload.len();
+
+    // Also adds the Observe option if this is a notification
+    coap_add_data_large_response(
+        resource,
+        session,
+        request,
+        response,
+        query,
+        content_format.into(),
+        -1,
+        0,
+        payload_len as c_ulong,
+        payload_ptr,
+        Some(drop_boxed_slice),
+        Box::into_raw(payload) as _,
+    );
+}
+
+unsafe fn query_segments(query: *const coap_string_t) -> Vec<String> {
+    match query.as_ref() {
+        Some(query) if !query.s.is_null() => {
+            String::from_utf8_lossy(std::slice::from_raw_parts(query.s, query.length as usize))
+                .split('&')
+                .map(str::to_owned)
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The handlers are only borrowed shared outside of [`CoapResource::on`], since handlers may
+/// notify the observers of their own resource
+unsafe fn resource_handlers<'a>(resource: *mut coap_resource_t) -> &'a ResourceHandlers {
+    &*(coap_resource_get_userdata(resource) as *const ResourceHandlers)
+}
+
+unsafe extern "C" fn drop_resource_handlers(user_data: *mut c_void) {
+    if !user_data.is_null() {
+        drop(Box::from_raw(user_data as *mut ResourceHandlers));
+    }
+}
@@ -1,37 +1,201 @@
-use crate::{CoapError, CoapMethod, CoapOptList, CoapPduBuilder, CoapSession};
-use rosthem_dto::LightInfo;
+use crate::{
+    CoapError, CoapMethod, CoapOptList, CoapPduBuilder, CoapResponse, CoapSession, CoapToken,
+    SupervisedSession,
+};
+use rosthem_dto::{BlindInfo, DeviceInfo, GroupInfo, LightInfo, MoodInfo, PlugInfo};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
 
-const IKEA_GATEWAY_PATH_SEGMENT: &'static str = "15001";
+pub(crate) const IKEA_GATEWAY_PATH_SEGMENT: &'static str = "15001";
+pub(crate) const IKEA_GROUPS_PATH_SEGMENT: &'static str = "15004";
+const IKEA_MOODS_PATH_SEGMENT: &'static str = "15005";
+const IKEA_ENDPOINT_PATH_SEGMENT: &'static str = "15011";
+const IKEA_AUTH_PATH_SEGMENT: &'static str = "9063";
 
 pub trait CoapSessionExt {
-    fn request_status(&mut self, id: &'static str) -> Result<(), CoapError>;
-    fn update_light(&mut self, id: &'static str, command: LightInfo) -> Result<(), CoapError>;
+    fn request_status(&mut self, id: &str) -> Result<DeviceInfo, CoapError>;
+    fn update_light(&mut self, id: &str, command: LightInfo) -> Result<(), CoapError>;
+    /// Queues a light update without waiting for it. Updates to the same light that are still
+    /// waiting in the queue are merged, see [`CoapSession::enqueue`].
+    fn enqueue_light_update(
+        &mut self,
+        id: &str,
+        command: LightInfo,
+    ) -> Result<CoapToken, CoapError>;
+    fn update_plug(&mut self, id: &str, command: PlugInfo) -> Result<(), CoapError>;
+    fn update_blind(&mut self, id: &str, command: BlindInfo) -> Result<(), CoapError>;
+    fn list_devices(&mut self) -> Result<Vec<usize>, CoapError>;
+    fn list_groups(&mut self) -> Result<Vec<usize>, CoapError>;
+    fn request_group(&mut self, id: &str) -> Result<GroupInfo, CoapError>;
+    fn update_group(&mut self, id: &str, command: GroupInfo) -> Result<(), CoapError>;
+    fn list_moods(&mut self, group: &str) -> Result<Vec<usize>, CoapError>;
+    fn request_mood(&mut self, group: &str, id: &str) -> Result<MoodInfo, CoapError>;
+    /// Registers `identity` with the gateway and returns the PSK it generated for it. Needs a
+    /// session with the identity `Client_identity` and the security code from the bottom of the
+    /// gateway as key.
+    fn pair(&mut self, identity: &str) -> Result<String, CoapError>;
 }
 
-impl CoapSessionExt for CoapSession {
-    fn request_status(&mut self, id: &'static str) -> Result<(), CoapError> {
-        let optlist = CoapOptList::new();
-        optlist.add_path_segment(IKEA_GATEWAY_PATH_SEGMENT)?;
-        optlist.add_path_segment(id)?;
+/// What the requests of [`CoapSessionExt`] need from a session, so they work the same on plain
+/// and supervised ones
+pub(crate) trait Requester {
+    fn request(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapResponse, CoapError>;
+    fn request_queued(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapResponse, CoapError>;
+    fn enqueue(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapToken, CoapError>;
+}
+
+impl Requester for CoapSession {
+    fn request(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapResponse, CoapError> {
+        CoapSession::request(self, pdu)
+    }
 
-        let pdu = CoapPduBuilder::new(CoapMethod::Get).with_optlist(&optlist);
+    fn request_queued(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapResponse, CoapError> {
+        CoapSession::request_queued(self, pdu, key)
+    }
+
+    fn enqueue(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapToken, CoapError> {
+        CoapSession::enqueue(self, pdu, key)
+    }
+}
+
+impl Requester for SupervisedSession {
+    fn request(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapResponse, CoapError> {
+        SupervisedSession::request(self, pdu)
+    }
+
+    fn request_queued(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapResponse, CoapError> {
+        SupervisedSession::request_queued(self, pdu, key)
+    }
 
-        self.send_pdu(pdu)?;
+    fn enqueue(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapToken, CoapError> {
+        SupervisedSession::enqueue(self, pdu, key)
+    }
+}
 
-        Ok(())
+impl<S: Requester> CoapSessionExt for S {
+    fn request_status(&mut self, id: &str) -> Result<DeviceInfo, CoapError> {
+        get(self, &[IKEA_GATEWAY_PATH_SEGMENT, id])
     }
 
-    fn update_light(&mut self, id: &'static str, command: LightInfo) -> Result<(), CoapError> {
-        let optlist = CoapOptList::new();
-        optlist.add_path_segment(IKEA_GATEWAY_PATH_SEGMENT)?;
-        optlist.add_path_segment(id)?;
+    fn update_light(&mut self, id: &str, command: LightInfo) -> Result<(), CoapError> {
+        put(self, &[IKEA_GATEWAY_PATH_SEGMENT, id], command)
+    }
 
+    fn enqueue_light_update(
+        &mut self,
+        id: &str,
+        command: LightInfo,
+    ) -> Result<CoapToken, CoapError> {
+        let segments = [IKEA_GATEWAY_PATH_SEGMENT, id];
+        let optlist = path(&segments)?;
         let pdu = CoapPduBuilder::new(CoapMethod::Put)
             .with_optlist(&optlist)
             .with_payload(command);
 
-        self.send_pdu(pdu)?;
+        Requester::enqueue(self, pdu, Some(&segments.join("/")))
+    }
+
+    fn update_plug(&mut self, id: &str, command: PlugInfo) -> Result<(), CoapError> {
+        put(self, &[IKEA_GATEWAY_PATH_SEGMENT, id], command)
+    }
+
+    fn update_blind(&mut self, id: &str, command: BlindInfo) -> Result<(), CoapError> {
+        put(self, &[IKEA_GATEWAY_PATH_SEGMENT, id], command)
+    }
+
+    fn list_devices(&mut self) -> Result<Vec<usize>, CoapError> {
+        get(self, &[IKEA_GATEWAY_PATH_SEGMENT])
+    }
+
+    fn list_groups(&mut self) -> Result<Vec<usize>, CoapError> {
+        get(self, &[IKEA_GROUPS_PATH_SEGMENT])
+    }
+
+    fn request_group(&mut self, id: &str) -> Result<GroupInfo, CoapError> {
+        get(self, &[IKEA_GROUPS_PATH_SEGMENT, id])
+    }
+
+    fn update_group(&mut self, id: &str, command: GroupInfo) -> Result<(), CoapError> {
+        put(self, &[IKEA_GROUPS_PATH_SEGMENT, id], command)
+    }
+
+    fn list_moods(&mut self, group: &str) -> Result<Vec<usize>, CoapError> {
+        get(self, &[IKEA_MOODS_PATH_SEGMENT, group])
+    }
+
+    fn request_mood(&mut self, group: &str, id: &str) -> Result<MoodInfo, CoapError> {
+        get(self, &[IKEA_MOODS_PATH_SEGMENT, group, id])
+    }
+
+    fn pair(&mut self, identity: &str) -> Result<String, CoapError> {
+        let optlist = path(&[IKEA_ENDPOINT_PATH_SEGMENT, IKEA_AUTH_PATH_SEGMENT])?;
+        let pdu = CoapPduBuilder::new(CoapMethod::Post)
+            .with_optlist(&optlist)
+            .with_payload(json!({ "9090": identity }));
 
-        Ok(())
+        let response: Value = Requester::request(self, pdu)?.decode()?;
+        response["9091"]
+            .as_str()
+            .map(str::to_owned)
+            .ok_or(CoapError::DeserializeError)
     }
 }
+
+fn path(segments: &[&str]) -> Result<CoapOptList, CoapError> {
+    let optlist = CoapOptList::new();
+    for segment in segments {
+        optlist.add_path_segment(segment)?;
+    }
+
+    Ok(optlist)
+}
+
+fn get<T: DeserializeOwned>(
+    session: &mut impl Requester,
+    segments: &[&str],
+) -> Result<T, CoapError> {
+    let optlist = path(segments)?;
+    let pdu = CoapPduBuilder::new(CoapMethod::Get).with_optlist(&optlist);
+
+    session.request(pdu)?.decode()
+}
+
+fn put<P: Serialize>(
+    session: &mut impl Requester,
+    segments: &[&str],
+    command: P,
+) -> Result<(), CoapError> {
+    let optlist = path(segments)?;
+    let pdu = CoapPduBuilder::new(CoapMethod::Put)
+        .with_optlist(&optlist)
+        .with_payload(command);
+
+    // Updates go through the outbound queue, so they respect its limits
+    session.request_queued(pdu, Some(&segments.join("/")))?;
+
+    Ok(())
+}
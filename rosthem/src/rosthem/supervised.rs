@@ -0,0 +1,277 @@
+use super::{
+    error::CoapError, response::CoapResponse, set_response_handler, CoapNackReason, CoapPduBuilder,
+    CoapSession, CoapToken, StoredRequest,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Delay before the second attempt, doubled for every further attempt
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32,
+    /// How long to wait for the handshake of a single attempt
+    pub connect_timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 10,
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Wraps a [`CoapSession`] and transparently reconnects it when the gateway closes the DTLS
+/// session (idle timeout, reboot, ...). Observations and requests that were in flight are sent
+/// again on the new session with their original tokens, so response handlers keyed by token keep
+/// working.
+pub struct SupervisedSession {
+    session: CoapSession,
+    policy: ReconnectPolicy,
+    observations: HashMap<CoapToken, StoredRequest>,
+    in_flight: HashMap<CoapToken, InFlight>,
+    reconnects: u64,
+}
+
+struct InFlight {
+    request: StoredRequest,
+    /// Replayed after reconnects until then, given up on afterwards
+    deadline: Instant,
+}
+
+impl SupervisedSession {
+    pub fn new(session: CoapSession) -> SupervisedSession {
+        SupervisedSession {
+            session,
+            policy: ReconnectPolicy::default(),
+            observations: HashMap::new(),
+            in_flight: HashMap::new(),
+            reconnects: 0,
+        }
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> SupervisedSession {
+        self.policy = policy;
+        self
+    }
+
+    pub fn session(&self) -> &CoapSession {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut CoapSession {
+        &mut self.session
+    }
+
+    /// Number of successful reconnects so far
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Like [`CoapSession::send_pdu`], but the request is sent again after a reconnect until its
+    /// response has been seen by [`SupervisedSession::run`]. After the request timeout of the
+    /// session it is given up on, e.g. for non-confirmable requests that are never answered.
+    pub fn send_pdu(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapToken, CoapError> {
+        self.ensure_connected()?;
+
+        let request = pdu.into_stored()?;
+        let token = self.session.send_pdu(request.builder())?;
+        self.session.track(token);
+        self.in_flight.insert(
+            token,
+            InFlight {
+                request,
+                deadline: Instant::now() + self.session.request_timeout(),
+            },
+        );
+
+        Ok(token)
+    }
+
+    /// Like [`CoapSession::request`], but reconnects and retries once if the session breaks
+    /// down before the response arrives
+    pub fn request(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapResponse, CoapError> {
+        self.ensure_connected()?;
+
+        let request = pdu.into_stored()?;
+        let token = self.session.send_pdu(request.builder())?;
+        let response = self.session.await_response(token);
+        if !is_connection_error(&response) {
+            return response;
+        }
+
+        // Reconnecting already makes as many attempts as the policy allows
+        self.reconnect()?;
+        self.session.rerequest(request.builder(), token)
+    }
+
+    /// Like [`CoapSession::request_queued`], but reconnects and retries once if the session
+    /// breaks down before the response arrives
+    pub fn request_queued(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapResponse, CoapError> {
+        self.ensure_connected()?;
+
+        let request = pdu.into_stored()?;
+        let response = self.session.request_queued(request.builder(), key);
+        if !is_connection_error(&response) {
+            return response;
+        }
+
+        self.reconnect()?;
+        self.session.request_queued(request.builder(), key)
+    }
+
+    /// Like [`CoapSession::enqueue`]. Queued requests are sent by [`SupervisedSession::run`].
+    pub fn enqueue(
+        &mut self,
+        pdu: CoapPduBuilder<'_>,
+        key: Option<&str>,
+    ) -> Result<CoapToken, CoapError> {
+        self.ensure_connected()?;
+        self.session.enqueue(pdu, key)
+    }
+
+    /// Registers an observation (adding the Observe option). Notifications are delivered to the
+    /// response handler passed to [`SupervisedSession::run`] with the returned token.
+    pub fn observe(&mut self, pdu: CoapPduBuilder<'_>) -> Result<CoapToken, CoapError> {
+        self.ensure_connected()?;
+
+        let request = pdu.into_stored()?.with_observe(0)?;
+        let token = self.session.send_pdu(request.builder())?;
+        self.observations.insert(token, request);
+
+        Ok(token)
+    }
+
+    pub fn cancel_observe(&mut self, token: CoapToken) -> Result<(), CoapError> {
+        if let Some(request) = self.observations.remove(&token) {
+            let deregister = request.with_observe(1)?;
+            self.session.resend_pdu(deregister.builder(), token)?;
+        }
+
+        Ok(())
+    }
+
+    /// Processes IO for `timeout`, reconnecting whenever the session fails
+    pub fn run(
+        &mut self,
+        timeout: Duration,
+        handle_response: Option<Box<dyn Fn(CoapResponse)>>,
+    ) -> Result<(), CoapError> {
+        set_response_handler(handle_response);
+        let result = self.run_until(Instant::now() + timeout);
+        set_response_handler(None);
+
+        result
+    }
+
+    fn run_until(&mut self, deadline: Instant) -> Result<(), CoapError> {
+        loop {
+            let broken = self.retire_completed();
+            if broken || self.session.has_failed() {
+                self.reconnect()?;
+            }
+
+            let wait = self.session.pump_queue()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.as_millis() == 0 {
+                return Ok(());
+            }
+
+            self.session
+                .context()
+                .process(wait.map_or(remaining, |w| w.min(remaining)))?;
+        }
+    }
+
+    /// Forgets requests that got their response or whose deadline passed. Returns whether one
+    /// of the others failed because the connection broke down, in which case it stays in flight.
+    fn retire_completed(&mut self) -> bool {
+        let mut broken = false;
+        let session = &mut self.session;
+        let now = Instant::now();
+
+        self.in_flight.retain(|token, in_flight| {
+            let expired = in_flight.deadline <= now;
+            match session.take_response(*token) {
+                Some(response) if is_connection_error(&response) && !expired => {
+                    broken = true;
+                    true
+                }
+                None if !expired => true,
+                _ => {
+                    session.untrack(*token);
+                    false
+                }
+            }
+        });
+
+        broken
+    }
+
+    fn ensure_connected(&mut self) -> Result<(), CoapError> {
+        if self.session.has_failed() {
+            self.reconnect()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), CoapError> {
+        let mut delay = self.policy.initial_delay;
+
+        for attempt in 0..self.policy.max_attempts {
+            if attempt > 0 {
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(self.policy.max_delay);
+            }
+
+            let connect_timeout = self.policy.connect_timeout;
+            let connected = self
+                .session
+                .reconnect()
+                .and_then(|_| self.session.wait_until_connected(connect_timeout));
+
+            if connected.is_ok() {
+                self.reconnects += 1;
+                return self.restore();
+            }
+        }
+
+        Err(CoapError::ReconnectFailed)
+    }
+
+    fn restore(&mut self) -> Result<(), CoapError> {
+        // Requests that expired while reconnecting aren't replayed
+        self.retire_completed();
+
+        for (token, request) in &self.observations {
+            self.session.resend_pdu(request.builder(), *token)?;
+        }
+
+        for (token, in_flight) in &self.in_flight {
+            self.session
+                .resend_pdu(in_flight.request.builder(), *token)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_connection_error(response: &Result<CoapResponse, CoapError>) -> bool {
+    matches!(
+        response,
+        Err(CoapError::SessionClosed)
+            | Err(CoapError::Nack(CoapNackReason::TlsFailed))
+            | Err(CoapError::Nack(CoapNackReason::TooManyRetries))
+            | Err(CoapError::Nack(CoapNackReason::NotDeliverable))
+    )
+}
@@ -0,0 +1,126 @@
+use rosthem::rosthem_dto::{DeviceInfo, LightInfo};
+use rosthem::{
+    Coap, CoapCredentials, CoapMethod, CoapOptList, CoapPduBuilder, CoapResponse, CoapSessionExt,
+    CoapUri, MockGateway, QueueConfig, SupervisedSession,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+const SECURITY_CODE: &str = "mock-security-code";
+const LIGHT_ID: u32 = 65536;
+const OTHER_LIGHT_ID: u32 = 65537;
+
+// libcoap can only be set up once per process, so everything runs in a single test
+#[test]
+fn session_against_mock_gateway() {
+    let coap = Coap::new(None).unwrap();
+    let context = coap.new_context().unwrap();
+
+    // Port 0 lets the OS pick a free port, so parallel runs don't collide
+    let gateway =
+        MockGateway::new(&context, "127.0.0.1:0".parse().unwrap(), SECURITY_CODE).unwrap();
+    let kitchen = DeviceInfo {
+        label: Some("Kitchen".into()),
+        ..DeviceInfo::default()
+    }
+    .with_light_info(LightInfo::default().on(false).brightness(10));
+    gateway.add_device(LIGHT_ID, &kitchen).unwrap();
+    gateway.add_device(OTHER_LIGHT_ID, &kitchen).unwrap();
+
+    let uri = CoapUri::new(format!("coaps://{}", gateway.addr())).unwrap();
+    let mut session = context
+        .new_session_from_uri(uri, Some(CoapCredentials::psk("tester", SECURITY_CODE)))
+        .unwrap();
+    session
+        .wait_until_connected(Duration::from_secs(5))
+        .unwrap();
+
+    let device = session.request_status(&LIGHT_ID.to_string()).unwrap();
+    assert_eq!(device.label.as_deref(), Some("Kitchen"));
+    assert_eq!(device.light_info.unwrap().get_on(), Some(false));
+
+    session
+        .update_light(
+            &LIGHT_ID.to_string(),
+            LightInfo::default().on(true).brightness(200),
+        )
+        .unwrap();
+    let light = gateway.device(LIGHT_ID).unwrap().light_info.unwrap();
+    assert_eq!(light.get_on(), Some(true));
+    assert_eq!(light.get_brightness(), Some(200));
+
+    // Queued requests only go out while the session processes IO, e.g. in flush
+    session.set_queue_config(QueueConfig::default()).unwrap();
+    let light_update = |id: u32, light: LightInfo| {
+        let optlist = CoapOptList::new();
+        optlist.add_path_segment("15001").unwrap();
+        optlist.add_path_segment(&id.to_string()).unwrap();
+        (
+            format!("15001/{}", id),
+            optlist,
+            DeviceInfo::default().with_light_info(light),
+        )
+    };
+    for (id, light) in [
+        (OTHER_LIGHT_ID, LightInfo::default().on(true)),
+        (LIGHT_ID, LightInfo::default().brightness(100)),
+        (OTHER_LIGHT_ID, LightInfo::default().brightness(50)),
+    ] {
+        let (key, optlist, payload) = light_update(id, light);
+        session
+            .enqueue(
+                CoapPduBuilder::new(CoapMethod::Put)
+                    .with_optlist(&optlist)
+                    .with_payload(payload),
+                Some(&key),
+            )
+            .unwrap();
+    }
+    session.flush(Duration::from_secs(5)).unwrap();
+    assert_eq!(session.queued(), 0);
+    let light = gateway.device(LIGHT_ID).unwrap().light_info.unwrap();
+    assert_eq!(light.get_brightness(), Some(100));
+    let other = gateway.device(OTHER_LIGHT_ID).unwrap().light_info.unwrap();
+    assert_eq!(other.get_on(), Some(true));
+    assert_eq!(other.get_brightness(), Some(50));
+    session
+        .update_light(&LIGHT_ID.to_string(), LightInfo::default().brightness(200))
+        .unwrap();
+
+    let mut session = SupervisedSession::new(session);
+    let optlist = CoapOptList::new();
+    optlist.add_path_segment("15001").unwrap();
+    optlist.add_path_segment(&LIGHT_ID.to_string()).unwrap();
+    let token = session
+        .observe(CoapPduBuilder::new(CoapMethod::Get).with_optlist(&optlist))
+        .unwrap();
+
+    let notifications = Rc::new(RefCell::new(Vec::new()));
+    let collect = |notifications: &Rc<RefCell<Vec<DeviceInfo>>>| {
+        let notifications = notifications.clone();
+        Some(Box::new(move |response: CoapResponse| {
+            if response.token() == token {
+                notifications.borrow_mut().push(response.decode().unwrap());
+            }
+        }) as Box<dyn Fn(CoapResponse)>)
+    };
+
+    session
+        .run(Duration::from_millis(500), collect(&notifications))
+        .unwrap();
+    assert_eq!(notifications.borrow().len(), 1);
+
+    gateway
+        .set_light(LIGHT_ID, &LightInfo::default().on(false))
+        .unwrap();
+    session
+        .run(Duration::from_millis(500), collect(&notifications))
+        .unwrap();
+
+    let notifications = notifications.borrow();
+    assert_eq!(notifications.len(), 2);
+    let light = notifications[1].light_info.as_ref().unwrap();
+    assert_eq!(light.get_on(), Some(false));
+    assert_eq!(light.get_brightness(), Some(200));
+}